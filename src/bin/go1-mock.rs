@@ -0,0 +1,71 @@
+// Pretend Go1 for CI and demos: answers HighCmd on 8082 and LowCmd on 8007 with 500 Hz state
+use std::env;
+use std::io::{self, BufRead};
+use std::net::{IpAddr, Ipv4Addr};
+use std::process;
+use std::thread;
+use rustRunner_Go1::ucl;
+use ucl::enums::Level;
+use ucl::mock::{MockConfig, UdpMock};
+
+const USAGE: &str = "usage: go1-mock [--bind IP] [--sn HEX16] [--version HEX16] [--battery PERCENT] [--level high|low]";
+
+fn parse_args() -> (IpAddr, MockConfig) {
+    let mut bind = IpAddr::V4(Ipv4Addr::LOCALHOST);
+    let mut config = MockConfig::default();
+    let mut args = env::args().skip(1);
+    while let Some(flag) = args.next() {
+        let value = args.next().unwrap_or_else(|| fail(&format!("{} needs a value", flag)));
+        match flag.as_str() {
+            "--bind" => bind = value.parse().unwrap_or_else(|_| fail("--bind expects an IP address")),
+            "--sn" => config.sn = parse_hex8(&value).unwrap_or_else(|| fail("--sn expects 8 bytes of hex")),
+            "--version" => config.version = parse_hex8(&value).unwrap_or_else(|| fail("--version expects 8 bytes of hex")),
+            "--battery" => {
+                config.battery = value.parse().ok().filter(|soc| *soc <= 100).unwrap_or_else(|| fail("--battery expects 0..100"))
+            }
+            "--level" => {
+                config.level = match value.as_str() {
+                    "high" => Level::High,
+                    "low" => Level::Low,
+                    _ => fail("--level expects high or low"),
+                }
+            }
+            _ => fail(&format!("unknown option {}", flag)),
+        }
+    }
+    (bind, config)
+}
+
+fn parse_hex8(hex: &str) -> Option<[u8; 8]> {
+    ucl::common::bytes_from_hex(hex)?.try_into().ok()
+}
+
+fn fail(msg: &str) -> ! {
+    eprintln!("{}\n{}", msg, USAGE);
+    process::exit(1);
+}
+
+fn main() {
+    let (bind, config) = parse_args();
+    let mock = UdpMock::bind(bind, ucl::unitreeConnection::SEND_PORT_HIGH, ucl::unitreeConnection::SEND_PORT_LOW, config)
+        .expect("Couldn't bind the high and low level ports");
+    println!(
+        "go1-mock SN {} listening on {} (high) and {} (low)",
+        ucl::common::byte_print(&mock.robot.lock().unwrap().config.sn), mock.high_addr(), mock.low_addr()
+    );
+
+    // Stand-in for the operator: "low" or "high" on stdin is the RC level switch, "cycle" a power cycle
+    for line in io::stdin().lock().lines().map_while(Result::ok) {
+        let mut robot = mock.robot.lock().unwrap();
+        match line.trim() {
+            "low" => robot.operator_switch(Level::Low),
+            "high" => robot.operator_switch(Level::High),
+            "cycle" => robot.power_cycle(),
+            other => eprintln!("unknown operator action '{}', expected low, high or cycle", other),
+        }
+    }
+    // stdin closed, e.g. started in the background: keep serving
+    loop {
+        thread::park();
+    }
+}
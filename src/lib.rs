@@ -0,0 +1,34 @@
+// Module files keep the camelCase names of the Python ucl they were ported from
+#![allow(non_snake_case)]
+
+pub mod ucl {
+    pub mod unitreeConnection;
+    pub mod highCmd;
+    pub mod highState;
+    pub mod common;
+    pub mod config;
+    pub mod controlLoop;
+    pub mod realtime;
+    pub mod supervisor;
+    pub mod enums;
+    pub mod complex;
+    pub mod lowCmd;
+    pub mod discovery;
+    pub mod fleet;
+    pub mod recording;
+    pub mod lowState;
+    pub mod pcap;
+    pub mod stateBus;
+    pub mod keepalive;
+    pub mod impairment;
+    pub mod transport;
+    pub mod mock;
+    pub mod levelSession;
+    pub mod safety;
+    pub mod motionLimit;
+    pub mod fallMonitor;
+    pub mod estop;
+    pub mod deadman;
+    pub mod batteryGuard;
+    pub mod thermalGuard;
+}
@@ -1,17 +1,9 @@
 // Import necessary libraries and modules
+use std::env;
+use std::process;
 use std::thread;
 use std::time::Duration;
-pub mod ucl {
-    pub mod unitreeConnection;
-    pub mod highCmd;
-    pub mod highState;
-    pub mod common;
-}
-
-// Define constants
-const HIGH_WIFI_DEFAULTS: (u16, &'static str, u16, &'static str) = (
-    8090, "192.168.12.1", 8082, "192.168.12.14"
-);
+use rustRunner_Go1::ucl;
 
 // Define custom data structures if needed
 
@@ -19,35 +11,80 @@ fn main() {
     // Print the library version
     println!("Running lib version: {}", ucl::common::lib_version());
 
-    // Create a unitreeConnection with HIGH_WIFI_DEFAULTS
-    let mut conn = ucl::unitreeConnection::new(HIGH_WIFI_DEFAULTS);
-    conn.start_recv();
+    // `rustRunner-Go1 discover` lists the dogs reachable through the built-in profiles
+    if env::args().nth(1).as_deref() == Some("discover") {
+        for robot in ucl::discovery::discover(Duration::from_millis(500)) {
+            println!(
+                "{}\t{:?} level\t{} via {}\tSN {} {} [{}]\tHW {} SW {}",
+                robot.profile, robot.level, robot.robot_addr, robot.local_ip,
+                robot.product, robot.id, ucl::common::byte_print(&robot.sn),
+                robot.hardware_version, robot.software_version
+            );
+        }
+        return;
+    }
 
-    let mut hcmd = ucl::highCmd::new();
-    let mut hstate = ucl::highState::new();
+    // `rustRunner-Go1 pcap <capture>` prints the Go1 traffic of a Wireshark capture as CSV
+    if env::args().nth(1).as_deref() == Some("pcap") {
+        let path = env::args().nth(2).unwrap_or_else(|| {
+            eprintln!("usage: rustRunner-Go1 pcap <capture.pcap|capture.pcapng>");
+            process::exit(1);
+        });
+        match ucl::pcap::read_capture(std::path::Path::new(&path)) {
+            Ok(timeline) => {
+                timeline.write_csv(&mut std::io::stdout()).expect("Couldn't write to stdout");
+                eprintln!("{:?}", timeline.stats);
+            }
+            Err(err) => {
+                eprintln!("{}: {}", path, err);
+                process::exit(1);
+            }
+        }
+        return;
+    }
 
-    // Send an empty command to initialize the connection
-    let cmd_bytes = hcmd.build_cmd(false);
-    conn.send(&cmd_bytes);
+    // Pick the connection profile by name, e.g. `rustRunner-Go1 eth-high`
+    let profile_name = env::args().nth(1).unwrap_or_else(|| ucl::config::DEFAULT_PROFILE.to_string());
+    let profile = match ucl::config::load_profile(&profile_name) {
+        Ok(profile) => profile,
+        Err(err) => {
+            eprintln!("{}", err);
+            process::exit(1);
+        }
+    };
+    println!("Using profile {}: {} -> {}", profile.name, profile.listen_addr(), profile.send_addr());
 
-    thread::sleep(Duration::from_secs(1)); // Sleep for some time to collect packets
+    let conn = ucl::unitreeConnection::UnitreeConnection::from_profile(&profile);
+    conn.start_recv();
+
+    let mut hcmd = ucl::highCmd::HighCmd::new();
+    let mut hstate = ucl::highState::HighState::new();
 
-    let mut motion_time = 0;
+    // Send an empty command to initialize the connection, and again whenever the state stream is lost
+    let mut supervisor = ucl::supervisor::LinkSupervisor::new(hcmd.build_cmd(false), Default::default());
+    supervisor.on_transition(|old, new| println!("Link {:?} -> {:?}", old, new));
+    supervisor.handshake(&conn);
 
-    loop {
-        motion_time += 1;
-        thread::sleep(Duration::from_millis(2));
+    // Stream the current command at 500 Hz, standing still if the loop below stops updating it
+    conn.start_keepalive(ucl::keepalive::KeepaliveConfig::high());
 
-        let data = conn.get_data();
+    thread::sleep(Duration::from_secs(1)); // Sleep for some time to collect packets
+
+    // 500 Hz loop, same rate as the 2 ms sleep of the python examples
+    let mut control = ucl::controlLoop::ControlLoop::new(500);
+    control.run(&conn, |tick| {
+        supervisor.poll(&conn);
 
-        for packet in data.iter() {
-            if motion_time % 100 == 0 {
+        if let Some(packet) = tick.state {
+            if tick.fresh && tick.count % 100 == 0 {
                 // Print information from hstate
                 hstate.parse_data(packet);
 
                 println!("+=+=+=+=+=+=+=+=+=+=+=+=+=+=+=+=+=+=+=+=+=+=+=+=+=+=+=+=+=+=+=+=+");
-                println!("SN [{}]:\t{}", ucl::common::byte_print(&hstate.sn), ucl::common::decode_sn(&hstate.sn));
-                println!("Ver [{}]:\t{}", ucl::common::byte_print(&hstate.version), ucl::common::decode_version(&hstate.version));
+                let (product, id) = ucl::common::decode_sn(hstate.sn[..6].try_into().unwrap());
+                let (hardware_version, software_version) = ucl::common::decode_version(hstate.version[..6].try_into().unwrap());
+                println!("SN [{}]:\t{} {}", ucl::common::byte_print(&hstate.sn), product, id);
+                println!("Ver [{}]:\tHW {} SW {}", ucl::common::byte_print(&hstate.version), hardware_version, software_version);
                 println!("SOC:\t\t\t{} %", hstate.bms.soc);
                 // Implement get_voltage, get_current, and other functions if needed
                 // println!("Overall Voltage:\t{} mv", get_voltage(&hstate.bms.cell_vol));
@@ -61,13 +98,17 @@ fn main() {
             }
         }
 
-        // Implement motion control logic here
+        // Implement motion control logic here, then hand the command to the keepalive
+        conn.set_command(&hcmd.build_cmd(false));
 
-        // Break the loop after a certain condition (e.g., motion_time > 24000)
-        if motion_time > 24000 {
-            break;
+        // Stop after a certain condition (e.g., 24000 ticks)
+        if tick.count > 24000 {
+            return ucl::controlLoop::Step::Stop;
         }
-    }
+        ucl::controlLoop::Step::Idle
+    });
+
+    println!("{}", control.stats().summary());
 }
 
 // Define functions and data structures as needed
@@ -0,0 +1,321 @@
+use std::time::{Duration, Instant};
+use super::complex::{BmsState, MotorCmdArray};
+use super::enums::{GaitType, MotorModeHigh};
+use super::highCmd::HighCmd;
+
+// Modes and gaits that draw the most current, refused from Restricted on
+const ENERGETIC_MODES: [MotorModeHigh; 4] = [MotorModeHigh::Backflip, MotorModeHigh::Jumpyaw, MotorModeHigh::Dance1, MotorModeHigh::Dance2];
+const ENERGETIC_GAITS: [GaitType; 1] = [GaitType::TrotRunning];
+
+#[derive(Debug, Clone)]
+pub struct BatteryConfig {
+    // A level is reached when the SOC, the lowest cell or the hottest sensor crosses its threshold
+    pub restrict_soc: u8,     // percent
+    pub restrict_cell: u16,   // mV
+    pub restrict_temp: u8,    // degrees centigrade
+    pub cap_soc: u8,
+    pub cap_cell: u16,
+    pub cap_temp: u8,
+    pub critical_soc: u8,
+    pub critical_cell: u16,
+    pub critical_temp: u8,
+    pub max_velocity: f32,    // m/s while capped
+    pub max_yaw_speed: f32,   // rad/s while capped
+    pub hold: Duration,       // a threshold has to stay crossed this long, cells sag under load
+    pub stand_down: Duration, // StandDown this long at critical, then Damping
+}
+
+impl Default for BatteryConfig {
+    fn default() -> Self {
+        BatteryConfig {
+            restrict_soc: 30,
+            restrict_cell: 3600,
+            restrict_temp: 50,
+            cap_soc: 15,
+            cap_cell: 3450,
+            cap_temp: 55,
+            critical_soc: 5,
+            critical_cell: 3300,
+            critical_temp: 60,
+            max_velocity: 0.3,
+            max_yaw_speed: 0.5,
+            hold: Duration::from_secs(1),
+            stand_down: Duration::from_secs(3),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum BatteryLevel {
+    Normal,
+    Restricted, // no energetic modes
+    Capped,     // restricted and slow
+    Critical,   // lie down and go limp
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum BatteryEvent {
+    Level { from: BatteryLevel, to: BatteryLevel, soc: u8, min_cell: u16, max_temp: u8 },
+    Refused(MotorModeHigh),
+    RefusedGait(GaitType),
+    SpeedCapped { velocity: [f32; 2], yaw_speed: f32 }, // what was requested
+    StandDown,
+    Damping,
+}
+
+// Restricts what the dog may do as the battery runs down. Feed every BmsState with update(),
+// pass every outgoing command through filter_high/filter_low. Events are only reported when
+// something changes, not every frame.
+#[derive(Debug, Clone)]
+pub struct BatteryGuard {
+    config: BatteryConfig,
+    level: BatteryLevel,
+    pending: Option<(BatteryLevel, Instant)>, // worse level seen, since when
+    critical_since: Option<Instant>,
+    last_step: Option<BatteryEvent>, // last refusal/cap/critical step, to report each once
+}
+
+impl BatteryGuard {
+    pub fn new(config: BatteryConfig) -> Self {
+        BatteryGuard { config, level: BatteryLevel::Normal, pending: None, critical_since: None, last_step: None }
+    }
+
+    pub fn level(&self) -> BatteryLevel {
+        self.level
+    }
+
+    // After charging or swapping the battery, levels only ever go up otherwise
+    pub fn reset(&mut self) {
+        *self = BatteryGuard::new(self.config.clone());
+    }
+
+    // Lowest cell voltage, unused cells read 0 and are skipped
+    pub fn min_cell(bms: &BmsState) -> u16 {
+        bms.cell_vol.iter().copied().filter(|mv| *mv > 0).min().unwrap_or(0)
+    }
+
+    pub fn max_temp(bms: &BmsState) -> u8 {
+        bms.bq_ntc.iter().chain(bms.mcu_ntc.iter()).copied().max().unwrap_or(0)
+    }
+
+    fn assess(&self, soc: u8, min_cell: u16, max_temp: u8) -> BatteryLevel {
+        let config = &self.config;
+        // No cell data (e.g. not decoded) must not count as an empty battery
+        let cell_below = |limit: u16| min_cell > 0 && min_cell < limit;
+        if soc <= config.critical_soc || cell_below(config.critical_cell) || max_temp >= config.critical_temp {
+            BatteryLevel::Critical
+        } else if soc <= config.cap_soc || cell_below(config.cap_cell) || max_temp >= config.cap_temp {
+            BatteryLevel::Capped
+        } else if soc <= config.restrict_soc || cell_below(config.restrict_cell) || max_temp >= config.restrict_temp {
+            BatteryLevel::Restricted
+        } else {
+            BatteryLevel::Normal
+        }
+    }
+
+    // Returns the level change once it held for `hold`
+    pub fn update(&mut self, bms: &BmsState) -> Option<BatteryEvent> {
+        let (min_cell, max_temp) = (Self::min_cell(bms), Self::max_temp(bms));
+        let assessed = self.assess(bms.soc, min_cell, max_temp);
+        if assessed <= self.level {
+            self.pending = None;
+            return None;
+        }
+
+        let now = Instant::now();
+        let since = match self.pending {
+            Some((_, since)) => since,
+            None => now,
+        };
+        self.pending = Some((assessed, since));
+        if now.duration_since(since) < self.config.hold {
+            return None;
+        }
+
+        let from = self.level;
+        self.level = assessed;
+        self.pending = None;
+        if assessed == BatteryLevel::Critical {
+            self.critical_since = Some(now);
+        }
+        Some(BatteryEvent::Level { from, to: assessed, soc: bms.soc, min_cell, max_temp })
+    }
+
+    // Apply the current level to `cmd`, returns the step taken when it differs from the last one
+    pub fn filter_high(&mut self, cmd: &mut HighCmd) -> Option<BatteryEvent> {
+        let step = match self.level {
+            BatteryLevel::Normal => None,
+            BatteryLevel::Critical => {
+                let since = *self.critical_since.get_or_insert_with(Instant::now);
+                if since.elapsed() < self.config.stand_down {
+                    *cmd = HighCmd::new();
+                    cmd.set_mode(MotorModeHigh::StandDown);
+                    Some(BatteryEvent::StandDown)
+                } else {
+                    *cmd = HighCmd::damping();
+                    Some(BatteryEvent::Damping)
+                }
+            }
+            BatteryLevel::Restricted | BatteryLevel::Capped => self.restrict(cmd),
+        };
+        self.report(step)
+    }
+
+    // Every restriction applies, the first one is reported
+    fn restrict(&self, cmd: &mut HighCmd) -> Option<BatteryEvent> {
+        let mut step = None;
+        if let Some(mode) = cmd.mode().ok().filter(|mode| ENERGETIC_MODES.contains(mode)) {
+            step = Some(BatteryEvent::Refused(mode));
+            cmd.set_mode(MotorModeHigh::Idle);
+        }
+        if let Some(gait) = cmd.gait_type().ok().filter(|gait| ENERGETIC_GAITS.contains(gait)) {
+            step = step.or(Some(BatteryEvent::RefusedGait(gait)));
+            cmd.set_gait_type(GaitType::Trot);
+        }
+        if self.level == BatteryLevel::Capped {
+            let max = self.config.max_velocity;
+            let max_yaw = self.config.max_yaw_speed;
+            let requested = (cmd.velocity, cmd.yaw_speed);
+            cmd.velocity = [cmd.velocity[0].clamp(-max, max), cmd.velocity[1].clamp(-max, max)];
+            cmd.yaw_speed = cmd.yaw_speed.clamp(-max_yaw, max_yaw);
+            if (cmd.velocity, cmd.yaw_speed) != requested {
+                step = step.or(Some(BatteryEvent::SpeedCapped { velocity: requested.0, yaw_speed: requested.1 }));
+            }
+        }
+        step
+    }
+
+    // Low level only knows the critical step, all motors to damping
+    pub fn filter_low(&mut self, cmd: &mut MotorCmdArray) -> Option<BatteryEvent> {
+        let step = if self.level == BatteryLevel::Critical {
+            *cmd = MotorCmdArray::damping();
+            Some(BatteryEvent::Damping)
+        } else {
+            None
+        };
+        self.report(step)
+    }
+
+    fn report(&mut self, step: Option<BatteryEvent>) -> Option<BatteryEvent> {
+        // Caps compare by kind only, the requested speed changes every frame
+        let same = match (&step, &self.last_step) {
+            (Some(BatteryEvent::SpeedCapped { .. }), Some(BatteryEvent::SpeedCapped { .. })) => true,
+            (step, last) => step == last,
+        };
+        if same {
+            return None;
+        }
+        self.last_step = step.clone();
+        step
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+
+    fn bms(soc: u8, cell: u16, temp: u8) -> BmsState {
+        BmsState { soc, cell_vol: vec![cell; 10], bq_ntc: [temp, 25], mcu_ntc: [25, 25], ..BmsState::default() }
+    }
+
+    fn guard(hold_ms: u64, stand_down_ms: u64) -> BatteryGuard {
+        BatteryGuard::new(BatteryConfig {
+            hold: Duration::from_millis(hold_ms),
+            stand_down: Duration::from_millis(stand_down_ms),
+            ..BatteryConfig::default()
+        })
+    }
+
+    #[test]
+    fn each_level_by_soc_cell_and_temperature() {
+        let guard = guard(0, 0);
+        let levels = [
+            (bms(30, 3900, 30), bms(80, 3590, 30), bms(80, 3900, 50), BatteryLevel::Restricted),
+            (bms(15, 3900, 30), bms(80, 3440, 30), bms(80, 3900, 55), BatteryLevel::Capped),
+            (bms(5, 3900, 30), bms(80, 3290, 30), bms(80, 3900, 60), BatteryLevel::Critical),
+        ];
+        for (by_soc, by_cell, by_temp, level) in levels {
+            for state in [by_soc, by_cell, by_temp] {
+                assert_eq!(guard.assess(state.soc, BatteryGuard::min_cell(&state), BatteryGuard::max_temp(&state)), level);
+            }
+        }
+        // Cells that were not decoded are not an empty battery
+        assert_eq!(guard.assess(80, BatteryGuard::min_cell(&bms(80, 0, 30)), 30), BatteryLevel::Normal);
+    }
+
+    #[test]
+    fn level_change_has_to_hold() {
+        let mut guard = guard(30, 0);
+        assert_eq!(guard.update(&bms(25, 3900, 30)), None);
+        // Sagging back above the threshold restarts the clock
+        guard.update(&bms(80, 3900, 30));
+        thread::sleep(Duration::from_millis(40));
+        assert_eq!(guard.update(&bms(25, 3900, 30)), None);
+        assert_eq!(guard.level(), BatteryLevel::Normal);
+        thread::sleep(Duration::from_millis(40));
+        let event = guard.update(&bms(25, 3900, 30)).unwrap();
+        assert_eq!(
+            event,
+            BatteryEvent::Level { from: BatteryLevel::Normal, to: BatteryLevel::Restricted, soc: 25, min_cell: 3900, max_temp: 30 }
+        );
+        // Levels only go up until reset
+        assert_eq!(guard.update(&bms(80, 3900, 30)), None);
+        assert_eq!(guard.level(), BatteryLevel::Restricted);
+        guard.reset();
+        assert_eq!(guard.level(), BatteryLevel::Normal);
+    }
+
+    #[test]
+    fn critical_stands_down_then_damps() {
+        let mut guard = guard(0, 40);
+        guard.update(&bms(3, 3900, 30));
+        assert_eq!(guard.level(), BatteryLevel::Critical);
+
+        let mut cmd = HighCmd::new();
+        cmd.set_mode(MotorModeHigh::VelWalk);
+        assert_eq!(guard.filter_high(&mut cmd), Some(BatteryEvent::StandDown));
+        assert_eq!(cmd.mode(), Ok(MotorModeHigh::StandDown));
+        thread::sleep(Duration::from_millis(20));
+        let mut cmd = HighCmd::new();
+        assert_eq!(guard.filter_high(&mut cmd), None);
+        assert_eq!(cmd.mode(), Ok(MotorModeHigh::StandDown));
+
+        thread::sleep(Duration::from_millis(30));
+        let mut cmd = HighCmd::new();
+        assert_eq!(guard.filter_high(&mut cmd), Some(BatteryEvent::Damping));
+        assert_eq!(cmd.mode(), Ok(MotorModeHigh::Damping));
+        assert_eq!(guard.filter_high(&mut HighCmd::new()), None);
+    }
+
+    #[test]
+    fn steps_are_reported_once_per_change() {
+        let mut guard = guard(0, 0);
+        guard.update(&bms(12, 3900, 30));
+        assert_eq!(guard.level(), BatteryLevel::Capped);
+
+        let fast = |vx: f32| {
+            let mut cmd = HighCmd::new();
+            cmd.set_mode(MotorModeHigh::VelWalk);
+            cmd.velocity = [vx, 0.0];
+            cmd
+        };
+        let mut cmd = fast(1.0);
+        assert_eq!(guard.filter_high(&mut cmd), Some(BatteryEvent::SpeedCapped { velocity: [1.0, 0.0], yaw_speed: 0.0 }));
+        assert_eq!(cmd.velocity, [0.3, 0.0]);
+        // A different requested speed is still the same step
+        assert_eq!(guard.filter_high(&mut fast(0.8)), None);
+
+        let mut flip = HighCmd::new();
+        flip.set_mode(MotorModeHigh::Backflip);
+        assert_eq!(guard.filter_high(&mut flip), Some(BatteryEvent::Refused(MotorModeHigh::Backflip)));
+        assert_eq!(flip.mode(), Ok(MotorModeHigh::Idle));
+        let mut flip = HighCmd::new();
+        flip.set_mode(MotorModeHigh::Backflip);
+        assert_eq!(guard.filter_high(&mut flip), None);
+
+        // Nothing to restrict is a change as well, the next cap is reported again
+        assert_eq!(guard.filter_high(&mut fast(0.1)), None);
+        assert!(guard.filter_high(&mut fast(1.0)).is_some());
+    }
+}
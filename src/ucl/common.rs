@@ -1,8 +1,6 @@
 
-use std::mem::transmute;
 use std::convert::TryInto;
 use serde::Serialize;
-use serde_json::json;
 
 
 pub fn lib_version() -> &'static str {
@@ -45,64 +43,45 @@ pub fn get_voltage(cell_voltages: &[f32]) -> f32 {
     cell_voltages.iter().sum()
 }
 
+// Floats go over the wire as little endian IEEE 754, both ways
 pub fn float_to_hex(f: f32) -> [u8; 4] {
-    unsafe { transmute(f.to_le()) }
+    f.to_le_bytes()
 }
 
 pub fn hex_to_float(hex_bytes: &[u8]) -> f32 {
     let bytes: [u8; 4] = hex_bytes.try_into().expect("slice with incorrect length");
-    let int_representation = u32::from_le_bytes(bytes);
-    let float_representation: f32 = unsafe { transmute(int_representation.to_be()) };
-    float_representation
+    f32::from_le_bytes(bytes)
 }
 
-pub fn fraction_to_hex(fraction: f32, neg: bool) -> [u8; 1] {
-    let mut hex_value = (fraction * 256.0) as i32;
-    if neg {
-        hex_value = 255 + hex_value + 1;
-    }
-    [(hex_value & 0xFF) as u8] // Mask with 0xFF to ensure it's within byte range
-}
-
-pub fn hex_to_fraction(hex_byte: u8, neg: bool) -> f32 {
-    let fraction = hex_byte as f32 / 256.0;
-    if neg {
-        -1.0 + fraction.round()
-    } else {
-        fraction.round()
-    }
+// Torque is signed 8.8 fixed point: fraction byte first, then the integer byte. The Python
+// version splits it into fraction and sign by hand, which is the same thing up to ±127.99 Nm.
+pub fn tau_to_hex(tau: f32) -> [u8; 2] {
+    ((tau * 256.0).round() as i16).to_le_bytes()
 }
 
 pub fn hex_to_tau(hex_bytes: &[u8]) -> f32 {
-    let (int_bytes, fraction_byte) = hex_bytes.split_at(1);
-    let mut int_val = i8::from_le_bytes(int_bytes.try_into().unwrap()) as i32;
-
-    let neg = if int_val > 126 {
-        int_val -= 256;
-        true
-    } else {
-        false
-    };
-
-    int_val as f32 + hex_to_fraction(fraction_byte[0], neg)
+    let bytes: [u8; 2] = hex_bytes.try_into().expect("slice with incorrect length");
+    i16::from_le_bytes(bytes) as f32 / 256.0
 }
 
+// Kp is in 1/32 steps with one decimal kept, in the odd steps the original lib uses for the
+// tenths. Little endian, like the Python reverse of the hex string.
 pub fn kp_to_hex(kp: f32) -> [u8; 2] {
     let base = kp as i32;
     let frac = ((kp - base as f32) * 10.0).round() as i32;
 
-    let mut val = if frac < 5 {
+    let val = if frac < 5 {
         (base * 32) + frac * 3
     } else {
         (base * 32) + ((frac - 1) * 3) + 4
     };
 
-    let val_bytes = (val as u16).to_le_bytes();
-    [val_bytes[1], val_bytes[0]] // Reverse the bytes to match the Python 'reverse' call
+    (val as u16).to_le_bytes()
 }
 
-fn hex_to_kp(byte_arr: &[u8; 2]) -> f32 {
-    let val = ((byte_arr[0] as u16) << 8) | byte_arr[1] as u16; // Reconstruct the value (assumes big-endian input)
+pub fn hex_to_kp(hex_bytes: &[u8]) -> f32 {
+    let bytes: [u8; 2] = hex_bytes.try_into().expect("slice with incorrect length");
+    let val = u16::from_le_bytes(bytes);
     let base = val / 32;
     let remainder = val % 32;
     let frac = if remainder < 15 {
@@ -111,75 +90,27 @@ fn hex_to_kp(byte_arr: &[u8; 2]) -> f32 {
         (remainder as f32 - 4.0) / 3.0 + 1.0
     };
 
-    base as f32 + (frac / 10.0) // Combine the base and the fractional part
-}
-
-fn get_hex_frac(frac: f32) -> char {
-    match frac {
-        0.0 => '0',
-        0.1 => '1',
-        0.2 => '3',
-        0.3 => '4',
-        0.4 => '6',
-        0.5 => '8',
-        0.6 => '9',
-        0.7 => 'b',
-        0.8 => 'c',
-        0.9 => 'e',
-        _ => '0',
-    }
-}
-fn kd_to_hex(decimal: f32) -> [u8; 2] {
-    let integer_part = decimal as u16;
-    let fractional_part = ((decimal.fract() * 10.0).round() as u16) % 10; // Get only one digit after decimal
-    let hex_fractional_part = get_hex_frac(fractional_part as f32 / 10.0); // Convert back to fraction
-
-    // Format and parse the integer part, combine with fractional hex representation
-    let mut kd = format!("{:03x}{}", integer_part, hex_fractional_part).into_bytes();
-    kd.reverse();
-    let kd_array: [u8; 2] = [kd[2], kd[3]]; // Get last two bytes after reverse
-    kd_array
-}
-
-fn get_hex_frac(fraction: f32) -> char {
-    match fraction {
-        0.0 => '0',
-        0.1 => '1',
-        0.2 => '3',
-        0.3 => '4',
-        0.4 => '6',
-        0.5 => '8',
-        0.6 => '9',
-        0.7 => 'b',
-        0.8 => 'c',
-        0.9 => 'e',
-        _ => '0',
-    }
+    base as f32 + (frac.round() / 10.0) // Combine the base and the fractional part
 }
 
-fn get_frac_hex(frac: char) -> f32 {
-    match frac {
-        '0' => 0.0,
-        '1' => 0.1,
-        '3' => 0.2,
-        '4' => 0.3,
-        '6' => 0.4,
-        '8' => 0.5,
-        '9' => 0.6,
-        'b' => 0.7,
-        'c' => 0.8,
-        'e' => 0.9,
-        _ => 0.0,
-    }
-}
+// Kd keeps the integer in the upper 12 bits and one decimal in the lowest nibble, the
+// nibble values are the original lib's rounding of tenths to sixteenths
+const KD_TENTHS: [u16; 10] = [0x0, 0x1, 0x3, 0x4, 0x6, 0x8, 0x9, 0xb, 0xc, 0xe];
 
-fn hex_to_kd(hex_bytes: &[u8; 2]) -> f32 {
-    let hex_string = format!("{:02x}{:02x}", hex_bytes[0], hex_bytes[1]); // Convert to hex string
-    let int_part = u16::from_str_radix(&hex_string[0..3], 16).expect("Parse int error");
-    let frac_part = get_frac_hex(hex_string.chars().nth(3).unwrap());
+pub fn kd_to_hex(kd: f32) -> [u8; 2] {
+    let tenths = (kd * 10.0).round() as u16;
+    let val = ((tenths / 10) << 4) | KD_TENTHS[(tenths % 10) as usize];
+    val.to_le_bytes()
+}
 
-    int_part as f32 + frac_part
+pub fn hex_to_kd(hex_bytes: &[u8]) -> f32 {
+    let bytes: [u8; 2] = hex_bytes.try_into().expect("slice with incorrect length");
+    let val = u16::from_le_bytes(bytes);
+    // Nibbles the original lib does not produce count as no decimal, like its lookup table
+    let tenths = KD_TENTHS.iter().position(|nibble| *nibble == val & 0xf).unwrap_or(0);
+    (val >> 4) as f32 + tenths as f32 / 10.0
 }
+
 pub fn gen_crc(bytes: &[u8]) -> u32 {
     let mut crc: u32 = 0xFFFFFFFF;
     for chunk in bytes.chunks(4) {
@@ -187,12 +118,18 @@ pub fn gen_crc(bytes: &[u8]) -> u32 {
         for b in 0..32 {
             let x = (crc >> 31) & 1;
             crc <<= 1;
-            crc ^= ((x ^ ((j >> (31 - b)) & 1)) * 0x04C11DB7) as u32;
+            crc ^= (x ^ ((j >> (31 - b)) & 1)) * 0x04C11DB7;
         }
     }
     crc
 }
 
+// CRC of a frame as the dog computes it: over the whole 32 bit words in front of the 4 byte CRC trailer
+pub fn frame_crc(frame: &[u8]) -> u32 {
+    let words = (frame.len() - 4) / 4;
+    gen_crc(&frame[..words * 4])
+}
+
 pub fn encrypt_crc(mut crc_val: u32) -> [u8; 4] {
     let xor_val = 0xEDCAB9DE;
     crc_val ^= xor_val;
@@ -205,7 +142,15 @@ pub fn byte_print(bytes: &[u8]) -> String {
     bytes.iter().map(|byte| format!("{:02x}", byte)).collect::<Vec<String>>().join("")
 }
 
-fn dump_obj<T>(obj: &T)
+// Inverse of byte_print, None on odd length or non-hex characters
+pub fn bytes_from_hex(hex: &str) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..hex.len()).step_by(2).map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok()).collect()
+}
+
+pub fn dump_obj<T>(obj: &T)
 where
     T: Serialize,
 {
@@ -213,7 +158,7 @@ where
     println!("{}", serialized);
 }
 
-fn pretty_print_obj<T>(obj: &T, indent: usize, border: bool)
+pub fn pretty_print_obj<T>(obj: &T, indent: usize, border: bool)
 where
     T: serde::Serialize,
 {
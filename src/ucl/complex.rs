@@ -1,38 +1,55 @@
-use std::convert::TryInto;
+use std::fmt;
 use super::enums::MotorModeLow;
 use super::common::{float_to_hex, hex_to_float, hex_to_tau, tau_to_hex, hex_to_kp, kp_to_hex, hex_to_kd, kd_to_hex};
 
 // Here's a basic structure for Cartesian with no methods yet
-#[derive(Debug, Clone, Copy)]
-struct Cartesian {
-    x: f32,
-    y: f32,
-    z: f32,
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Cartesian {
+    pub x: f32,
+    pub y: f32,
+    pub z: f32,
+}
+
+impl Cartesian {
+    pub fn new(x: f32, y: f32, z: f32) -> Self {
+        Cartesian { x, y, z }
+    }
 }
 
 // Define a struct for BMS State
-#[derive(Debug, Clone)]
-struct BmsState {
-    version_h: u8,
-    version_l: u8,
-    bms_status: u8,
-    soc: u8, // State of Charge 0-100%
-    current: i32, // mA
-    cycle: u32,
-    bq_ntc: i8, // x1 degrees centigrade
-    mcu_ntc: i8, // x1 degrees centigrade
-    cell_vol: Vec<u16>, // cell voltage mV
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct BmsState {
+    pub version_h: u8,
+    pub version_l: u8,
+    pub bms_status: u8,
+    pub soc: u8, // State of Charge 0-100%
+    pub current: i32, // mA
+    pub cycle: u16,
+    pub bq_ntc: [u8; 2], // x1 degrees centigrade
+    pub mcu_ntc: [u8; 2], // x1 degrees centigrade
+    pub cell_vol: Vec<u16>, // cell voltage mV
+}
+
+impl BmsState {
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(version_h: u8, version_l: u8, bms_status: u8, soc: u8, current: i32, cycle: u16, bq_ntc: [u8; 2], mcu_ntc: [u8; 2], cell_vol: Vec<u16>) -> Self {
+        BmsState { version_h, version_l, bms_status, soc, current, cycle, bq_ntc, mcu_ntc, cell_vol }
+    }
 }
 
 // Define a struct for BMS Command
-#[derive(Debug, Clone)]
-struct BmsCmd {
-    off: u8,
-    reserve: [u8; 3],
+#[derive(Debug, Clone, PartialEq)]
+pub struct BmsCmd {
+    pub off: u8,
+    pub reserve: [u8; 3],
 }
 
 impl BmsCmd {
-    fn get_bytes(&self) -> Vec<u8> {
+    pub fn new(off: u8, reserve: [u8; 3]) -> Self {
+        BmsCmd { off, reserve }
+    }
+
+    pub fn get_bytes(&self) -> Vec<u8> {
         // Serialize the BmsCmd into bytes
         let mut bytes = Vec::new();
         bytes.push(self.off);
@@ -40,7 +57,7 @@ impl BmsCmd {
         bytes
     }
 
-    fn from_bytes(data: &[u8]) -> Self {
+    pub fn from_bytes(data: &[u8]) -> Self {
         // Deserialize bytes into a BmsCmd, assuming the slice is the correct size
         let off = data[0];
         let reserve = [data[1], data[2], data[3]];
@@ -49,43 +66,135 @@ impl BmsCmd {
 }
 
 // Define a struct for LED
-#[derive(Debug, Clone)]
-struct Led {
-    r: u8,
-    g: u8,
-    b: u8,
+#[derive(Debug, Clone, PartialEq)]
+pub struct Led {
+    pub r: u8,
+    pub g: u8,
+    pub b: u8,
+    padding: u8, // kept so decoded frames encode back byte for byte
 }
 
 impl Led {
-    fn get_bytes(&self) -> Vec<u8> {
+    pub fn new(r: u8, g: u8, b: u8) -> Self {
+        Led { r, g, b, padding: 0 }
+    }
+
+    pub fn get_bytes(&self) -> Vec<u8> {
         // Serialize the Led into bytes
-        vec![self.r, self.g, self.b, 0] // Adding a zero byte at the end as padding
+        vec![self.r, self.g, self.b, self.padding]
+    }
+
+    pub fn from_bytes(data: &[u8]) -> Self {
+        Led { r: data[0], g: data[1], b: data[2], padding: data[3] }
     }
 }
 
-#[derive(Debug, Clone)]
-struct MotorState {
-    mode: u8,
-    q: f32,        // current angle (unit: radian)
-    dq: f32,       // current velocity (unit: radian/second)
-    ddq: f32,      // current acceleration (unit: radian/second^2)
-    tau_est: f32,  // current estimated output torque (unit: N.m)
-    q_raw: f32,    // raw current angle (unit: radian)
-    dq_raw: f32,   // raw current velocity (unit: radian/second)
-    ddq_raw: f32,  // raw current acceleration
-    temperature: f32,
-    reserve: Vec<u8>, // Assuming reserve is a Vec<u8> for variable length data
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct MotorState {
+    pub mode: u8,
+    pub q: f32,        // current angle (unit: radian)
+    pub dq: f32,       // current velocity (unit: radian/second)
+    pub ddq: f32,      // current acceleration (unit: radian/second^2)
+    pub tau_est: f32,  // current estimated output torque (unit: N.m)
+    pub q_raw: f32,    // raw current angle (unit: radian)
+    pub dq_raw: f32,   // raw current velocity (unit: radian/second)
+    pub ddq_raw: f32,  // raw current acceleration
+    pub temperature: f32,
+    pub reserve: Vec<u8>, // 8 bytes in both HighState and LowState
 }
 
-#[derive(Debug, Clone)]
-struct Imu {
-    quaternion: (f32, f32, f32, f32), // normalized quaternion (w, x, y, z)
-    gyroscope: [f32; 3],              // angular velocity (unit: rad/s)
-    accelerometer: [f32; 3],          // acceleration (unit: m/s^2)
-    rpy: [f32; 3],                    // roll, pitch, yaw (unit: radians)
-    temperature: f32,
+impl MotorState {
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(mode: u8, q: f32, dq: f32, ddq: f32, tau_est: f32, q_raw: f32, dq_raw: f32, ddq_raw: f32, temperature: f32, reserve: &[u8]) -> Self {
+        MotorState { mode, q, dq, ddq, tau_est, q_raw, dq_raw, ddq_raw, temperature, reserve: reserve.to_vec() }
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Imu {
+    pub quaternion: [f32; 4],    // normalized quaternion (w, x, y, z)
+    pub gyroscope: [f32; 3],     // angular velocity (unit: rad/s)
+    pub accelerometer: [f32; 3], // acceleration (unit: m/s^2)
+    pub rpy: [f32; 3],           // roll, pitch, yaw (unit: radians)
+    pub temperature: f32,
 }
 
+impl Imu {
+    pub fn new(quaternion: [f32; 4], gyroscope: [f32; 3], accelerometer: [f32; 3], rpy: [f32; 3], temperature: f32) -> Self {
+        Imu { quaternion, gyroscope, accelerometer, rpy, temperature }
+    }
+}
+
+// What a Go1 joint motor takes, everything here also fits the fixed point fields of the frame
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct MotorSpec {
+    pub max_tau: f32, // Nm, either direction
+    pub max_kp: f32,  // Nm/rad
+    pub max_kd: f32,  // Nm/(rad/s)
+}
+
+// One MotorSpec per joint, the same on all four legs. Motor index i is joint i % 3 (hip, thigh,
+// calf); the 8 slots after the legs drive nothing and get the hip spec, the weakest.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct MotorSpecs {
+    pub hip: MotorSpec,
+    pub thigh: MotorSpec,
+    pub calf: MotorSpec,
+}
+
+impl MotorSpecs {
+    // Torque ratings from the published URDF (unitree_ros, go1_description/xacro/const.xacro):
+    // 23.7 Nm for hip and thigh, 35.55 Nm for the geared calf. The gains are far above what
+    // walking needs, higher ones only make the joint chatter.
+    pub fn go1() -> Self {
+        let hip = MotorSpec { max_tau: 23.7, max_kp: 300.0, max_kd: 25.0 };
+        MotorSpecs { hip, thigh: hip, calf: MotorSpec { max_tau: 35.55, ..hip } }
+    }
+
+    pub fn for_motor(&self, motor: usize) -> &MotorSpec {
+        match motor {
+            0..=11 if motor % 3 == 1 => &self.thigh,
+            0..=11 if motor % 3 == 2 => &self.calf,
+            _ => &self.hip,
+        }
+    }
+}
+
+impl Default for MotorSpecs {
+    fn default() -> Self {
+        Self::go1()
+    }
+}
+
+// Position and velocity that tell the motor not to control them, as in the official SDK
+pub const POS_STOP_F: f32 = 2.146e9;
+pub const VEL_STOP_F: f32 = 16000.0;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Validation {
+    Strict,  // invalid values are an error
+    Lenient, // invalid values are clamped, NaN and unknown modes fall back to doing nothing
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum MotorCmdError {
+    InvalidMode(u8), // only Damping and Servo can be commanded, Overheat is reported by the motor
+    NotFinite(&'static str),
+    OutOfRange { field: &'static str, value: f32, max: f32 },
+}
+
+impl fmt::Display for MotorCmdError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            MotorCmdError::InvalidMode(mode) => write!(f, "motor mode 0x{:02x} can not be commanded", mode),
+            MotorCmdError::NotFinite(field) => write!(f, "motor command {} is not a finite number", field),
+            MotorCmdError::OutOfRange { field, value, max } => write!(f, "motor command {} {} is outside the motor's range (max {})", field, value, max),
+        }
+    }
+}
+
+impl std::error::Error for MotorCmdError {}
+
 #[derive(Debug, Clone, PartialEq)]
 pub struct MotorCmd {
     mode: u8,                // Gewünschter Arbeitsmodus
@@ -103,19 +212,95 @@ impl MotorCmd {
         MotorCmd { mode, q, dq, tau, kp, kd, reserve }
     }
 
-    // Äquivalent zur getBytes-Methode in Python
-    pub fn get_bytes(&self) -> Vec<u8> {
+    pub fn mode(&self) -> u8 {
+        self.mode
+    }
+
+    pub fn q(&self) -> f32 {
+        self.q
+    }
+
+    pub fn dq(&self) -> f32 {
+        self.dq
+    }
+
+    pub fn tau(&self) -> f32 {
+        self.tau
+    }
+
+    pub fn kp(&self) -> f32 {
+        self.kp
+    }
+
+    pub fn kd(&self) -> f32 {
+        self.kd
+    }
+
+    pub fn reserve(&self) -> [u32; 3] {
+        self.reserve
+    }
+
+    // Check the command against `spec`, e.g. at construction with MotorCmd::new(..).validate(..)?
+    // get_bytes runs this too, so whatever is encoded fits the codecs. Strict returns the
+    // first problem, Lenient returns a
+    // command that is safe to encode: tau and the gains clamped into range, NaN torque or
+    // gains zeroed, a NaN target replaced by the stop value with its gain zeroed and an
+    // unknown mode turned into damping.
+    pub fn validate(&self, spec: &MotorSpec, validation: Validation) -> Result<MotorCmd, MotorCmdError> {
+        let strict = validation == Validation::Strict;
+        let mut cmd = self.clone();
+
+        if cmd.mode != MotorModeLow::Servo as u8 && cmd.mode != MotorModeLow::Damping as u8 {
+            if strict {
+                return Err(MotorCmdError::InvalidMode(cmd.mode));
+            }
+            return Ok(MotorCmd::new(MotorModeLow::Damping as u8, 0.0, 0.0, 0.0, 0.0, 0.0, cmd.reserve));
+        }
+
+        for (field, value) in [("q", cmd.q), ("dq", cmd.dq), ("tau", cmd.tau), ("kp", cmd.kp), ("kd", cmd.kd)] {
+            if !value.is_finite() && strict {
+                return Err(MotorCmdError::NotFinite(field));
+            }
+        }
+        if !cmd.q.is_finite() {
+            cmd.q = POS_STOP_F;
+            cmd.kp = 0.0;
+        }
+        if !cmd.dq.is_finite() {
+            cmd.dq = VEL_STOP_F;
+            cmd.kd = 0.0;
+        }
+
+        // Gains can not be negative, the codecs would wrap them into huge positive ones
+        let ranges = [("tau", -spec.max_tau, spec.max_tau), ("kp", 0.0, spec.max_kp), ("kd", 0.0, spec.max_kd)];
+        for ((field, min, max), value) in ranges.into_iter().zip([&mut cmd.tau, &mut cmd.kp, &mut cmd.kd]) {
+            if value.is_nan() {
+                *value = 0.0;
+            } else if *value < min || *value > max {
+                if strict {
+                    return Err(MotorCmdError::OutOfRange { field, value: *value, max });
+                }
+                *value = value.clamp(min, max);
+            }
+        }
+        Ok(cmd)
+    }
+
+    // Äquivalent zur getBytes-Methode in Python, validated first so NaN and out of range values
+    // never reach the codecs. Only Strict can fail.
+    pub fn get_bytes(&self, spec: &MotorSpec, validation: Validation) -> Result<Vec<u8>, MotorCmdError> {
+        let cmd = self.validate(spec, validation)?;
         let mut bytes = Vec::new();
-        bytes.push(self.mode);
-        bytes.extend_from_slice(&float_to_hex(self.q));
-        bytes.extend_from_slice(&float_to_hex(self.dq));
-        bytes.extend_from_slice(&tau_to_hex(self.tau));
-        bytes.extend_from_slice(&kp_to_hex(self.kp));
-        bytes.extend_from_slice(&kd_to_hex(self.kd));
-        for &val in &self.reserve {
+        bytes.push(cmd.mode);
+        bytes.extend_from_slice(&float_to_hex(cmd.q));
+        bytes.extend_from_slice(&float_to_hex(cmd.dq));
+        bytes.extend_from_slice(&tau_to_hex(cmd.tau));
+        bytes.extend_from_slice(&kp_to_hex(cmd.kp));
+        bytes.extend_from_slice(&kd_to_hex(cmd.kd));
+        for &val in &cmd.reserve {
             bytes.extend_from_slice(&val.to_le_bytes());
         }
-        bytes
+        Ok(bytes)
     }
 
     // Äquivalent zur fromBytes-Methode in Python
@@ -163,38 +348,44 @@ pub struct MotorCmdArray {
     unknown8: MotorCmd,
 }
 
+impl Default for MotorCmdArray {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl MotorCmdArray {
     pub fn new() -> Self {
-        let default_motor_cmd = MotorCmd::new(
-            MotorModeLow::Servo as u8, 
-            0.0, 
-            0.0, 
-            0.0, 
-            0.0, 
-            0.0, 
-            [0, 0, 0],
-        );
+        Self::filled(MotorCmd::new(MotorModeLow::Servo as u8, 0.0, 0.0, 0.0, 0.0, 0.0, [0, 0, 0]))
+    }
+
+    // All motors in damping mode with zero gains and torque
+    pub fn damping() -> Self {
+        Self::filled(MotorCmd::new(MotorModeLow::Damping as u8, 0.0, 0.0, 0.0, 0.0, 0.0, [0, 0, 0]))
+    }
+
+    fn filled(cmd: MotorCmd) -> Self {
         Self {
-            fr_0: default_motor_cmd,
-            fr_1: default_motor_cmd,
-            fr_2: default_motor_cmd,
-            fl_0: default_motor_cmd,
-            fl_1: default_motor_cmd,
-            fl_2: default_motor_cmd,
-            rr_0: default_motor_cmd,
-            rr_1: default_motor_cmd,
-            rr_2: default_motor_cmd,
-            rl_0: default_motor_cmd,
-            rl_1: default_motor_cmd,
-            rl_2: default_motor_cmd,
-            unknown1: default_motor_cmd,
-            unknown2: default_motor_cmd,
-            unknown3: default_motor_cmd,
-            unknown4: default_motor_cmd,
-            unknown5: default_motor_cmd,
-            unknown6: default_motor_cmd,
-            unknown7: default_motor_cmd,
-            unknown8: default_motor_cmd,
+            fr_0: cmd.clone(),
+            fr_1: cmd.clone(),
+            fr_2: cmd.clone(),
+            fl_0: cmd.clone(),
+            fl_1: cmd.clone(),
+            fl_2: cmd.clone(),
+            rr_0: cmd.clone(),
+            rr_1: cmd.clone(),
+            rr_2: cmd.clone(),
+            rl_0: cmd.clone(),
+            rl_1: cmd.clone(),
+            rl_2: cmd.clone(),
+            unknown1: cmd.clone(),
+            unknown2: cmd.clone(),
+            unknown3: cmd.clone(),
+            unknown4: cmd.clone(),
+            unknown5: cmd.clone(),
+            unknown6: cmd.clone(),
+            unknown7: cmd.clone(),
+            unknown8: cmd,
         }
     }
 
@@ -224,29 +415,56 @@ impl MotorCmdArray {
         }
     }
 
-    pub fn get_bytes(&self) -> Vec<u8> {
-        let mut bytes = Vec::new();
-        bytes.extend(self.fr_0.get_bytes());
-        bytes.extend(self.fr_1.get_bytes());
-        bytes.extend(self.fr_2.get_bytes());
-        bytes.extend(self.fl_0.get_bytes());
-        bytes.extend(self.fl_1.get_bytes());
-        bytes.extend(self.fl_2.get_bytes());
-        bytes.extend(self.rr_0.get_bytes());
-        bytes.extend(self.rr_1.get_bytes());
-        bytes.extend(self.rr_2.get_bytes());
-        bytes.extend(self.rl_0.get_bytes());
-        bytes.extend(self.rl_1.get_bytes());
-        bytes.extend(self.rl_2.get_bytes());
-        bytes.extend(self.unknown1.get_bytes());
-        bytes.extend(self.unknown2.get_bytes());
-        bytes.extend(self.unknown3.get_bytes());
-        bytes.extend(self.unknown4.get_bytes());
-        bytes.extend(self.unknown5.get_bytes());
-        bytes.extend(self.unknown6.get_bytes());
-        bytes.extend(self.unknown7.get_bytes());
-        bytes.extend(self.unknown8.get_bytes());
-        bytes
+    // Validate all 20 commands before encoding, Strict reports the motor index of the first bad one
+    // and leaves the array as it was
+    pub fn validate(&mut self, specs: &MotorSpecs, validation: Validation) -> Result<(), (usize, MotorCmdError)> {
+        let mut checked = Vec::with_capacity(20);
+        for motor in 0..20 {
+            if let Some(cmd) = self.get_motor_cmd(motor) {
+                checked.push(cmd.validate(specs.for_motor(motor), validation).map_err(|e| (motor, e))?);
+            }
+        }
+        for (motor, cmd) in checked.into_iter().enumerate() {
+            self.set_motor_cmd(motor, cmd);
+        }
+        Ok(())
+    }
+
+    pub fn get_motor_cmd(&self, motor_index: usize) -> Option<&MotorCmd> {
+        match motor_index {
+            0 => Some(&self.fr_0),
+            1 => Some(&self.fr_1),
+            2 => Some(&self.fr_2),
+            3 => Some(&self.fl_0),
+            4 => Some(&self.fl_1),
+            5 => Some(&self.fl_2),
+            6 => Some(&self.rr_0),
+            7 => Some(&self.rr_1),
+            8 => Some(&self.rr_2),
+            9 => Some(&self.rl_0),
+            10 => Some(&self.rl_1),
+            11 => Some(&self.rl_2),
+            12 => Some(&self.unknown1),
+            13 => Some(&self.unknown2),
+            14 => Some(&self.unknown3),
+            15 => Some(&self.unknown4),
+            16 => Some(&self.unknown5),
+            17 => Some(&self.unknown6),
+            18 => Some(&self.unknown7),
+            19 => Some(&self.unknown8),
+            _ => None,
+        }
+    }
+
+    // Each motor checked against the spec of its joint, Strict fails with the index of the first bad one
+    pub fn get_bytes(&self, specs: &MotorSpecs, validation: Validation) -> Result<Vec<u8>, (usize, MotorCmdError)> {
+        let mut bytes = Vec::with_capacity(27 * 20);
+        for motor in 0..20 {
+            if let Some(cmd) = self.get_motor_cmd(motor) {
+                bytes.extend(cmd.get_bytes(specs.for_motor(motor), validation).map_err(|e| (motor, e))?);
+            }
+        }
+        Ok(bytes)
     }
 
     fn get_chunk(data: &[u8], i: usize) -> &[u8] {
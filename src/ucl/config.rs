@@ -0,0 +1,306 @@
+use std::collections::HashMap;
+use std::env;
+use std::fmt;
+use std::fs;
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
+use std::path::Path;
+use serde::Deserialize;
+use super::unitreeConnection::{
+    LISTEN_PORT, SEND_PORT_LOW, SEND_PORT_HIGH, LOCAL_IP_WIFI, LOCAL_IP_ETH, ADDR_WIFI, ADDR_LOW, ADDR_HIGH,
+};
+
+// Config file used when neither the caller nor GO1_CONFIG names one
+pub const DEFAULT_CONFIG_FILE: &str = "go1.toml";
+pub const DEFAULT_PROFILE: &str = "wifi-high";
+
+// Environment variables that override single fields of the selected profile
+const ENV_CONFIG: &str = "GO1_CONFIG";
+const ENV_ROBOT_IP: &str = "GO1_ROBOT_IP";
+const ENV_SEND_PORT: &str = "GO1_SEND_PORT";
+const ENV_LOCAL_IP: &str = "GO1_LOCAL_IP";
+const ENV_LISTEN_PORT: &str = "GO1_LISTEN_PORT";
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConnectionProfile {
+    pub name: String,
+    pub robot_ip: Ipv4Addr,   // address of the dog
+    pub send_port: u16,       // 8082 for high level, 8007 for low level
+    pub local_ip: Ipv4Addr,   // address of our interface on the dog's network
+    pub listen_port: u16,     // port the dog sends its state to
+}
+
+impl ConnectionProfile {
+    pub fn new(name: &str, robot_ip: &str, send_port: u16, local_ip: &str, listen_port: u16) -> Self {
+        ConnectionProfile {
+            name: name.to_string(),
+            robot_ip: robot_ip.parse().expect("invalid built-in robot ip"),
+            send_port,
+            local_ip: local_ip.parse().expect("invalid built-in local ip"),
+            listen_port,
+        }
+    }
+
+    pub fn send_addr(&self) -> SocketAddr {
+        SocketAddr::V4(SocketAddrV4::new(self.robot_ip, self.send_port))
+    }
+
+    pub fn listen_addr(&self) -> SocketAddr {
+        SocketAddr::V4(SocketAddrV4::new(self.local_ip, self.listen_port))
+    }
+
+    // Apply GO1_* environment overrides on top of the profile
+    pub fn apply_env(&mut self) -> Result<(), ConfigError> {
+        if let Ok(value) = env::var(ENV_ROBOT_IP) {
+            self.robot_ip = parse_ip(&self.name, "robot_ip", &value)?;
+        }
+        if let Ok(value) = env::var(ENV_SEND_PORT) {
+            self.send_port = parse_port(&self.name, "send_port", &value)?;
+        }
+        if let Ok(value) = env::var(ENV_LOCAL_IP) {
+            self.local_ip = parse_ip(&self.name, "local_ip", &value)?;
+        }
+        if let Ok(value) = env::var(ENV_LISTEN_PORT) {
+            self.listen_port = parse_port(&self.name, "listen_port", &value)?;
+        }
+        self.validate()
+    }
+
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        if self.robot_ip.is_unspecified() || self.robot_ip.is_broadcast() {
+            return Err(ConfigError::invalid(&self.name, "robot_ip", format!("{} is not a robot address", self.robot_ip)));
+        }
+        if self.local_ip.is_broadcast() {
+            return Err(ConfigError::invalid(&self.name, "local_ip", format!("{} is not an interface address", self.local_ip)));
+        }
+        if self.send_port == 0 {
+            return Err(ConfigError::invalid(&self.name, "send_port", "port must not be 0".to_string()));
+        }
+        if self.listen_port == 0 {
+            return Err(ConfigError::invalid(&self.name, "listen_port", "port must not be 0".to_string()));
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug)]
+pub enum ConfigError {
+    Io(String, std::io::Error),
+    Parse(String, toml::de::Error),
+    UnknownProfile(String),
+    Invalid { profile: String, field: &'static str, reason: String },
+}
+
+impl ConfigError {
+    fn invalid(profile: &str, field: &'static str, reason: String) -> Self {
+        ConfigError::Invalid { profile: profile.to_string(), field, reason }
+    }
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ConfigError::Io(path, err) => write!(f, "could not read {}: {}", path, err),
+            ConfigError::Parse(path, err) => write!(f, "could not parse {}: {}", path, err),
+            ConfigError::UnknownProfile(name) => write!(f, "unknown connection profile '{}'", name),
+            ConfigError::Invalid { profile, field, reason } => {
+                write!(f, "profile '{}': invalid {}: {}", profile, field, reason)
+            }
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+// On-disk layout, one [profiles.<name>] table per profile
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ProfileFile {
+    #[serde(default)]
+    profiles: HashMap<String, RawProfile>,
+}
+
+// Everything is optional, unset fields come from the built-in profile named by `base`
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawProfile {
+    base: Option<String>,
+    robot_ip: Option<String>,
+    send_port: Option<i64>,
+    local_ip: Option<String>,
+    listen_port: Option<i64>,
+}
+
+// The four presets of the python SDK
+pub fn builtin_profiles() -> HashMap<String, ConnectionProfile> {
+    let presets = [
+        ConnectionProfile::new("wifi-high", ADDR_WIFI, SEND_PORT_HIGH, LOCAL_IP_WIFI, LISTEN_PORT),
+        ConnectionProfile::new("wifi-low", ADDR_LOW, SEND_PORT_LOW, LOCAL_IP_WIFI, LISTEN_PORT),
+        ConnectionProfile::new("eth-high", ADDR_HIGH, SEND_PORT_HIGH, LOCAL_IP_ETH, LISTEN_PORT),
+        ConnectionProfile::new("eth-low", ADDR_LOW, SEND_PORT_LOW, LOCAL_IP_ETH, LISTEN_PORT),
+    ];
+    presets.into_iter().map(|p| (p.name.clone(), p)).collect()
+}
+
+// Built-in profiles merged with the ones from `path`; file entries win on name clashes
+pub fn load_profiles(path: &Path) -> Result<HashMap<String, ConnectionProfile>, ConfigError> {
+    let path_str = path.display().to_string();
+    let text = fs::read_to_string(path).map_err(|e| ConfigError::Io(path_str.clone(), e))?;
+    parse_profiles(&text).map_err(|e| match e {
+        ConfigError::Parse(_, err) => ConfigError::Parse(path_str, err),
+        other => other,
+    })
+}
+
+pub fn parse_profiles(text: &str) -> Result<HashMap<String, ConnectionProfile>, ConfigError> {
+    let file: ProfileFile = toml::from_str(text).map_err(|e| ConfigError::Parse("<string>".to_string(), e))?;
+    let builtins = builtin_profiles();
+    let mut profiles = builtins.clone();
+
+    for (name, raw) in file.profiles.iter() {
+        let base_name = raw.base.as_deref().unwrap_or(DEFAULT_PROFILE);
+        let mut profile = match builtins.get(base_name) {
+            Some(base) => base.clone(),
+            None => return Err(ConfigError::invalid(name, "base", format!("'{}' is not a built-in profile", base_name))),
+        };
+        profile.name = name.clone();
+        if let Some(value) = &raw.robot_ip {
+            profile.robot_ip = parse_ip(name, "robot_ip", value)?;
+        }
+        if let Some(value) = raw.send_port {
+            profile.send_port = check_port(name, "send_port", value)?;
+        }
+        if let Some(value) = &raw.local_ip {
+            profile.local_ip = parse_ip(name, "local_ip", value)?;
+        }
+        if let Some(value) = raw.listen_port {
+            profile.listen_port = check_port(name, "listen_port", value)?;
+        }
+        profile.validate()?;
+        profiles.insert(name.clone(), profile);
+    }
+    Ok(profiles)
+}
+
+// Resolve a profile by name: GO1_CONFIG or ./go1.toml if present, then GO1_* overrides
+pub fn load_profile(name: &str) -> Result<ConnectionProfile, ConfigError> {
+    let profiles = match env::var(ENV_CONFIG) {
+        Ok(path) => load_profiles(Path::new(&path))?,
+        Err(_) if Path::new(DEFAULT_CONFIG_FILE).exists() => load_profiles(Path::new(DEFAULT_CONFIG_FILE))?,
+        Err(_) => builtin_profiles(),
+    };
+    let mut profile = profiles.get(name).cloned().ok_or_else(|| ConfigError::UnknownProfile(name.to_string()))?;
+    profile.apply_env()?;
+    Ok(profile)
+}
+
+fn parse_ip(profile: &str, field: &'static str, value: &str) -> Result<Ipv4Addr, ConfigError> {
+    value.trim().parse().map_err(|_| ConfigError::invalid(profile, field, format!("'{}' is not an IPv4 address", value)))
+}
+
+fn parse_port(profile: &str, field: &'static str, value: &str) -> Result<u16, ConfigError> {
+    let port: i64 = value.trim().parse().map_err(|_| ConfigError::invalid(profile, field, format!("'{}' is not a number", value)))?;
+    check_port(profile, field, port)
+}
+
+fn check_port(profile: &str, field: &'static str, value: i64) -> Result<u16, ConfigError> {
+    if value <= 0 || value > u16::MAX as i64 {
+        return Err(ConfigError::invalid(profile, field, format!("{} is outside 1-65535", value)));
+    }
+    Ok(value as u16)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // The field a ConfigError::Invalid blames, and that the message names it
+    fn invalid_field(text: &str) -> &'static str {
+        let err = parse_profiles(text).unwrap_err();
+        match err {
+            ConfigError::Invalid { field, .. } => {
+                assert!(err.to_string().contains(field), "{}", err);
+                field
+            }
+            other => panic!("expected an invalid field, got {}", other),
+        }
+    }
+
+    #[test]
+    fn profiles_inherit_from_their_base() {
+        let profiles = parse_profiles(
+            r#"
+            [profiles.lab]
+            base = "eth-low"
+            listen_port = 9000
+
+            [profiles.field]
+            robot_ip = "10.0.0.2"
+
+            [profiles.wifi-high]
+            send_port = 8083
+            "#,
+        )
+        .unwrap();
+
+        let lab = &profiles["lab"];
+        assert_eq!(lab.name, "lab");
+        assert_eq!(lab.robot_ip, ADDR_LOW.parse::<Ipv4Addr>().unwrap());
+        assert_eq!((lab.send_port, lab.listen_port), (SEND_PORT_LOW, 9000));
+        assert_eq!(lab.local_ip, LOCAL_IP_ETH.parse::<Ipv4Addr>().unwrap());
+
+        // No base means the default profile
+        let field = &profiles["field"];
+        assert_eq!(field.robot_ip, Ipv4Addr::new(10, 0, 0, 2));
+        assert_eq!((field.send_port, field.local_ip), (SEND_PORT_HIGH, LOCAL_IP_WIFI.parse().unwrap()));
+
+        // File entries replace built-ins of the same name, the others stay
+        assert_eq!(profiles["wifi-high"].send_port, 8083);
+        assert_eq!(profiles["eth-high"], builtin_profiles()["eth-high"]);
+    }
+
+    #[test]
+    fn errors_name_the_field() {
+        assert_eq!(invalid_field("[profiles.a]\nrobot_ip = \"192.168.12\""), "robot_ip");
+        assert_eq!(invalid_field("[profiles.a]\nlocal_ip = \"eth0\""), "local_ip");
+        assert_eq!(invalid_field("[profiles.a]\nrobot_ip = \"0.0.0.0\""), "robot_ip");
+        assert_eq!(invalid_field("[profiles.a]\nsend_port = 0"), "send_port");
+        assert_eq!(invalid_field("[profiles.a]\nsend_port = 70000"), "send_port");
+        assert_eq!(invalid_field("[profiles.a]\nlisten_port = -1"), "listen_port");
+        assert_eq!(invalid_field("[profiles.a]\nbase = \"usb-high\""), "base");
+
+        let err = parse_profiles("[profiles.a]\nbase = \"usb-high\"").unwrap_err();
+        assert_eq!(err.to_string(), "profile 'a': invalid base: 'usb-high' is not a built-in profile");
+        assert!(matches!(parse_profiles("[profiles.a]\nrobot = \"1.2.3.4\""), Err(ConfigError::Parse(..))));
+    }
+
+    #[test]
+    fn check_port_bounds() {
+        assert_eq!(check_port("p", "send_port", 1).unwrap(), 1);
+        assert_eq!(check_port("p", "send_port", 65535).unwrap(), 65535);
+        assert!(check_port("p", "send_port", 65536).is_err());
+        assert!(check_port("p", "send_port", 0).is_err());
+        assert!(parse_port("p", "listen_port", " 8090 ").is_ok());
+        assert!(parse_port("p", "listen_port", "80a").is_err());
+    }
+
+    // The only test that touches GO1_*, the environment is shared by the whole test binary
+    #[test]
+    fn env_overrides_the_profile() {
+        let vars = [ENV_ROBOT_IP, ENV_SEND_PORT, ENV_LOCAL_IP, ENV_LISTEN_PORT];
+        let mut profile = builtin_profiles()["eth-high"].clone();
+        env::set_var(ENV_ROBOT_IP, "192.168.123.20");
+        env::set_var(ENV_LISTEN_PORT, "9100");
+        let applied = profile.apply_env();
+        env::set_var(ENV_SEND_PORT, "99999");
+        let mut other = builtin_profiles()["eth-high"].clone();
+        let rejected = other.apply_env();
+        for var in vars {
+            env::remove_var(var);
+        }
+
+        applied.unwrap();
+        assert_eq!(profile.robot_ip, Ipv4Addr::new(192, 168, 123, 20));
+        assert_eq!((profile.send_port, profile.listen_port), (SEND_PORT_HIGH, 9100));
+        assert!(matches!(rejected, Err(ConfigError::Invalid { field: "send_port", .. })));
+    }
+}
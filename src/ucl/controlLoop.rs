@@ -0,0 +1,239 @@
+use std::thread;
+use std::time::{Duration, Instant};
+use super::transport::Transport;
+use super::realtime::{self, RealtimeConfig};
+
+// Sleep until this close to the deadline, then spin for the rest
+const SPIN_MARGIN: Duration = Duration::from_micros(100);
+// Histogram resolution, in buckets per nominal period (covers 0..2 periods)
+const HISTOGRAM_BUCKETS_PER_PERIOD: u32 = 10;
+
+// What the control closure wants done this cycle
+pub enum Step {
+    Send(Vec<u8>),
+    Idle,
+    Stop,
+}
+
+// Handed to the control closure once per cycle
+pub struct Tick<'a> {
+    pub count: u64,
+    pub state: Option<&'a [u8]>, // newest datagram received so far
+    pub fresh: bool,             // true if `state` arrived since the previous tick
+    pub late_by: Duration,       // how far behind its deadline this tick woke up
+}
+
+#[derive(Debug, Clone)]
+pub struct PeriodHistogram {
+    pub bucket_width: Duration,
+    pub buckets: Vec<u64>,
+    pub overflow: u64, // periods longer than the last bucket
+}
+
+impl PeriodHistogram {
+    fn new(period: Duration) -> Self {
+        PeriodHistogram {
+            bucket_width: period / HISTOGRAM_BUCKETS_PER_PERIOD,
+            buckets: vec![0; 2 * HISTOGRAM_BUCKETS_PER_PERIOD as usize],
+            overflow: 0,
+        }
+    }
+
+    fn record(&mut self, measured: Duration) {
+        let index = (measured.as_nanos() / self.bucket_width.as_nanos().max(1)) as usize;
+        match self.buckets.get_mut(index) {
+            Some(bucket) => *bucket += 1,
+            None => self.overflow += 1,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct LoopStats {
+    pub period: Duration,
+    pub ticks: u64,
+    pub overruns: u64,           // cycles whose work ran past the next deadline
+    pub missed_deadlines: u64,   // deadlines skipped entirely because of overruns
+    pub worst_latency: Duration, // worst wake-up lateness against the absolute deadline
+    pub worst_work: Duration,    // worst time spent in the closure plus send
+    pub histogram: PeriodHistogram,
+}
+
+impl LoopStats {
+    fn new(period: Duration) -> Self {
+        LoopStats {
+            period,
+            ticks: 0,
+            overruns: 0,
+            missed_deadlines: 0,
+            worst_latency: Duration::ZERO,
+            worst_work: Duration::ZERO,
+            histogram: PeriodHistogram::new(period),
+        }
+    }
+
+    pub fn summary(&self) -> String {
+        let mut out = format!(
+            "ticks: {}, overruns: {}, missed deadlines: {}, worst latency: {:?}, worst work: {:?}\n",
+            self.ticks, self.overruns, self.missed_deadlines, self.worst_latency, self.worst_work
+        );
+        let width = self.histogram.bucket_width;
+        for (i, count) in self.histogram.buckets.iter().enumerate() {
+            if *count > 0 {
+                out.push_str(&format!("  {:>8?} - {:>8?}: {}\n", width * i as u32, width * (i as u32 + 1), count));
+            }
+        }
+        if self.histogram.overflow > 0 {
+            out.push_str(&format!("  {:>8?} +         : {}\n", width * self.histogram.buckets.len() as u32, self.histogram.overflow));
+        }
+        out
+    }
+}
+
+pub struct ControlLoop {
+    period: Duration,
+    stats: LoopStats,
+    realtime: RealtimeConfig,
+}
+
+impl ControlLoop {
+    pub fn new(frequency_hz: u32) -> Self {
+        assert!(frequency_hz > 0, "control loop frequency must be positive");
+        Self::with_period(Duration::from_nanos(1_000_000_000 / frequency_hz as u64))
+    }
+
+    pub fn with_period(period: Duration) -> Self {
+        ControlLoop { period, stats: LoopStats::new(period), realtime: RealtimeConfig::default() }
+    }
+
+    // Applied to the thread that calls run(), right before the first cycle
+    pub fn with_realtime(mut self, config: RealtimeConfig) -> Self {
+        self.realtime = config;
+        self
+    }
+
+    pub fn stats(&self) -> &LoopStats {
+        &self.stats
+    }
+
+    // Call `step` once per period until it returns Step::Stop. Deadlines are absolute
+    // (start + n * period) so sleep jitter does not accumulate into drift.
+    pub fn run<T, F>(&mut self, conn: &T, mut step: F)
+    where
+        T: Transport + ?Sized,
+        F: FnMut(&Tick) -> Step,
+    {
+        if !self.realtime.is_empty() {
+            realtime::apply_or_warn(&self.realtime, "control loop");
+        }
+
+        let mut latest: Option<Vec<u8>> = None;
+        let mut deadline = Instant::now() + self.period;
+        let mut last_wake: Option<Instant> = None;
+
+        loop {
+            sleep_until(deadline);
+            let woke = Instant::now();
+            let late_by = woke.saturating_duration_since(deadline);
+            self.stats.worst_latency = self.stats.worst_latency.max(late_by);
+            if let Some(previous) = last_wake {
+                self.stats.histogram.record(woke - previous);
+            }
+            last_wake = Some(woke);
+
+            let mut data = conn.get_data();
+            let fresh = !data.is_empty();
+            if let Some(packet) = data.pop() {
+                latest = Some(packet);
+            }
+
+            let tick = Tick { count: self.stats.ticks, state: latest.as_deref(), fresh, late_by };
+            let action = step(&tick);
+            self.stats.ticks += 1;
+            match action {
+                Step::Send(cmd) => conn.send(&cmd),
+                Step::Idle => {}
+                Step::Stop => break,
+            }
+
+            let done = Instant::now();
+            self.stats.worst_work = self.stats.worst_work.max(done - woke);
+            deadline += self.period;
+            if done > deadline {
+                // Skip the deadlines we already blew instead of bursting to catch up
+                let behind = (done - deadline).as_nanos() / self.period.as_nanos();
+                self.stats.overruns += 1;
+                self.stats.missed_deadlines += behind as u64 + 1;
+                deadline += self.period * (behind as u32 + 1);
+            }
+        }
+    }
+}
+
+fn sleep_until(deadline: Instant) {
+    let now = Instant::now();
+    if deadline <= now {
+        return;
+    }
+    let remaining = deadline - now;
+    if remaining > SPIN_MARGIN {
+        thread::sleep(remaining - SPIN_MARGIN);
+    }
+    while Instant::now() < deadline {
+        std::hint::spin_loop();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ucl::enums::MotorModeHigh;
+    use crate::ucl::highCmd::HighCmd;
+    use crate::ucl::highState::HighState;
+    use crate::ucl::mock::{MockConfig, MockRobot, STATE_PERIOD};
+    use crate::ucl::transport::SimTransport;
+
+    #[test]
+    fn runs_until_stop_against_the_mock() {
+        let sim = SimTransport::new(MockRobot::new(MockConfig::default())).with_tick(STATE_PERIOD);
+        sim.start_recv();
+        let mut control = ControlLoop::with_period(Duration::from_millis(2));
+        let mut counts = Vec::new();
+        let mut fresh = 0;
+        let mut walking = false;
+        control.run(&sim, |tick| {
+            counts.push(tick.count);
+            fresh += tick.fresh as u32;
+            if let Some(state) = tick.state {
+                let mut hstate = HighState::new();
+                hstate.parse_data(state);
+                walking = hstate.velocity[0] > 0.05;
+            }
+            if tick.count == 50 {
+                return Step::Stop;
+            }
+            let mut cmd = HighCmd::new();
+            cmd.set_mode(MotorModeHigh::VelWalk);
+            cmd.velocity = [0.3, 0.0];
+            Step::Send(cmd.build_cmd(false))
+        });
+
+        assert_eq!(counts, (0..=50).collect::<Vec<u64>>());
+        assert_eq!(control.stats().ticks, 51);
+        assert!(fresh > 40, "{} fresh ticks", fresh);
+        assert!(walking);
+        let periods: u64 = control.stats().histogram.buckets.iter().sum::<u64>() + control.stats().histogram.overflow;
+        assert_eq!(periods, 50);
+    }
+
+    #[test]
+    fn idle_sends_nothing() {
+        let sim = SimTransport::new(MockRobot::new(MockConfig::default())).with_tick(STATE_PERIOD);
+        sim.start_recv();
+        let mut control = ControlLoop::new(1000);
+        control.run(&sim, |tick| if tick.count == 10 { Step::Stop } else { Step::Idle });
+        // The mock only streams once it got a command
+        assert!(sim.get_data().is_empty());
+        assert!(control.stats().summary().starts_with("ticks: 11,"));
+    }
+}
@@ -0,0 +1,209 @@
+use std::time::{Duration, Instant};
+use super::complex::MotorCmdArray;
+use super::enums::RemoteKey;
+use super::highCmd::HighCmd;
+use super::highState::HighState;
+use super::lowState::LowState;
+
+// Keys currently held on the remote, one RemoteKey::mask() bit each
+pub fn remote_keys(wireless_remote: &[u8; 40]) -> u16 {
+    u16::from_le_bytes([wireless_remote[2], wireless_remote[3]])
+}
+
+#[derive(Debug, Clone)]
+pub struct DeadmanConfig {
+    pub keys: Vec<RemoteKey>,  // all of them have to be held
+    pub stale_after: Duration, // without a state carrying remote data for this long, motion stops
+}
+
+impl Default for DeadmanConfig {
+    fn default() -> Self {
+        // R2 is free, the RC combinations for standing and level switching all use L1/L2
+        DeadmanConfig { keys: vec![RemoteKey::R2], stale_after: Duration::from_millis(100) }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DeadmanState {
+    Held,
+    Released,
+    Stale, // no remote data yet, or too old
+}
+
+// Motion only while the chosen remote keys are held. Feed every received state with the time it
+// arrived (Transport::get_data_timestamped), then pass every outgoing command through
+// filter_high/filter_low:
+//   deadman.update_high(&hstate, received);
+//   deadman.filter_high(&mut hcmd);
+// Staleness counts from that arrival time, so feeding the same old state again does not keep
+// motion allowed.
+#[derive(Debug, Clone)]
+pub struct Deadman {
+    config: DeadmanConfig,
+    mask: u16,
+    keys: u16,
+    received: Option<Instant>, // arrival of the newest remote data
+    stops: u64,
+}
+
+impl Deadman {
+    pub fn new(config: DeadmanConfig) -> Self {
+        let mask = config.keys.iter().fold(0, |mask, key| mask | key.mask());
+        Deadman { config, mask, keys: 0, received: None, stops: 0 }
+    }
+
+    // `received` is when the datagram carrying `wireless_remote` arrived. Data older than what
+    // was already seen is ignored.
+    pub fn update(&mut self, wireless_remote: &[u8; 40], received: Instant) -> DeadmanState {
+        if self.received.is_none_or(|newest| received >= newest) {
+            self.keys = remote_keys(wireless_remote);
+            self.received = Some(received);
+        }
+        self.state()
+    }
+
+    pub fn update_high(&mut self, state: &HighState, received: Instant) -> DeadmanState {
+        self.update(&state.wireless_remote, received)
+    }
+
+    pub fn update_low(&mut self, state: &LowState, received: Instant) -> DeadmanState {
+        self.update(&state.wireless_remote, received)
+    }
+
+    pub fn state(&self) -> DeadmanState {
+        match self.received {
+            Some(at) if at.elapsed() < self.config.stale_after => {
+                if self.mask != 0 && self.keys & self.mask == self.mask { DeadmanState::Held } else { DeadmanState::Released }
+            }
+            _ => DeadmanState::Stale,
+        }
+    }
+
+    pub fn allows_motion(&self) -> bool {
+        self.state() == DeadmanState::Held
+    }
+
+    // Commands replaced so far
+    pub fn stops(&self) -> u64 {
+        self.stops
+    }
+
+    // Stand still (Idle, zero velocity) unless the keys are held. Returns true if `cmd` was replaced.
+    pub fn filter_high(&mut self, cmd: &mut HighCmd) -> bool {
+        if self.allows_motion() {
+            return false;
+        }
+        *cmd = HighCmd::new();
+        self.stops += 1;
+        true
+    }
+
+    // Low level has no standing still that is safe in every pose, so damping
+    pub fn filter_low(&mut self, cmd: &mut MotorCmdArray) -> bool {
+        if self.allows_motion() {
+            return false;
+        }
+        *cmd = MotorCmdArray::damping();
+        self.stops += 1;
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ucl::complex::MotorCmd;
+    use crate::ucl::enums::{MotorModeHigh, MotorModeLow};
+
+    fn remote(keys: &[RemoteKey]) -> [u8; 40] {
+        let mut wireless_remote = [0; 40];
+        let mask = keys.iter().fold(0, |mask, key| mask | key.mask());
+        wireless_remote[2..4].copy_from_slice(&mask.to_le_bytes());
+        wireless_remote
+    }
+
+    fn deadman() -> Deadman {
+        Deadman::new(DeadmanConfig { keys: vec![RemoteKey::R2, RemoteKey::L1], stale_after: Duration::from_millis(30) })
+    }
+
+    fn walk() -> HighCmd {
+        let mut hcmd = HighCmd::new();
+        hcmd.set_mode(MotorModeHigh::VelWalk);
+        hcmd.velocity = [0.4, 0.0];
+        hcmd
+    }
+
+    #[test]
+    fn held_released_and_nothing_yet() {
+        let mut deadman = deadman();
+        assert_eq!(deadman.state(), DeadmanState::Stale);
+        assert_eq!(deadman.update(&remote(&[RemoteKey::R2, RemoteKey::L1, RemoteKey::A]), Instant::now()), DeadmanState::Held);
+        assert!(deadman.allows_motion());
+        // Only one of the two keys
+        assert_eq!(deadman.update(&remote(&[RemoteKey::R2]), Instant::now()), DeadmanState::Released);
+        assert_eq!(deadman.update(&remote(&[]), Instant::now()), DeadmanState::Released);
+    }
+
+    #[test]
+    fn stale_counts_from_the_arrival_time() {
+        let mut deadman = deadman();
+        let received = Instant::now();
+        assert_eq!(deadman.update(&remote(&[RemoteKey::R2, RemoteKey::L1]), received), DeadmanState::Held);
+        thread_sleep(40);
+        assert_eq!(deadman.state(), DeadmanState::Stale);
+
+        // The same old state fed again every cycle does not revive it
+        for _ in 0..3 {
+            assert_eq!(deadman.update(&remote(&[RemoteKey::R2, RemoteKey::L1]), received), DeadmanState::Stale);
+        }
+        // Arrived long ago, e.g. drained late from the socket
+        let old = Instant::now() - Duration::from_millis(100);
+        assert_eq!(deadman.update(&remote(&[RemoteKey::R2, RemoteKey::L1]), old), DeadmanState::Stale);
+        assert_eq!(deadman.update(&remote(&[RemoteKey::R2, RemoteKey::L1]), Instant::now()), DeadmanState::Held);
+    }
+
+    #[test]
+    fn older_data_does_not_override_newer() {
+        let mut deadman = deadman();
+        let now = Instant::now();
+        deadman.update(&remote(&[]), now);
+        assert_eq!(deadman.update(&remote(&[RemoteKey::R2, RemoteKey::L1]), now - Duration::from_millis(5)), DeadmanState::Released);
+    }
+
+    #[test]
+    fn filter_high_stands_still_unless_held() {
+        let mut deadman = deadman();
+        let mut cmd = walk();
+        assert!(deadman.filter_high(&mut cmd));
+        assert_eq!((cmd.mode(), cmd.velocity), (Ok(MotorModeHigh::Idle), [0.0, 0.0]));
+
+        deadman.update(&remote(&[RemoteKey::R2, RemoteKey::L1]), Instant::now());
+        let mut cmd = walk();
+        assert!(!deadman.filter_high(&mut cmd));
+        assert_eq!(cmd.mode(), Ok(MotorModeHigh::VelWalk));
+
+        deadman.update(&remote(&[RemoteKey::L1]), Instant::now());
+        assert!(deadman.filter_high(&mut cmd));
+        assert_eq!(deadman.stops(), 2);
+    }
+
+    #[test]
+    fn filter_low_damps_unless_held() {
+        let mut deadman = deadman();
+        let mut cmd = MotorCmdArray::new();
+        cmd.set_motor_cmd(0, MotorCmd::new(MotorModeLow::Servo as u8, 0.3, 0.0, 2.0, 20.0, 1.0, [0, 0, 0]));
+        deadman.update(&remote(&[RemoteKey::R2, RemoteKey::L1]), Instant::now());
+        assert!(!deadman.filter_low(&mut cmd));
+        assert_eq!(cmd.get_motor_cmd(0).unwrap().kp(), 20.0);
+
+        thread_sleep(40);
+        assert!(deadman.filter_low(&mut cmd));
+        for motor in 0..20 {
+            assert_eq!(cmd.get_motor_cmd(motor).unwrap().mode(), MotorModeLow::Damping as u8);
+        }
+    }
+
+    fn thread_sleep(ms: u64) {
+        std::thread::sleep(Duration::from_millis(ms));
+    }
+}
@@ -0,0 +1,180 @@
+use std::net::{IpAddr, SocketAddr, UdpSocket};
+use std::thread;
+use std::time::{Duration, Instant};
+use super::common::{decode_sn, decode_version};
+use super::config::{builtin_profiles, ConnectionProfile};
+use super::enums::Level;
+use super::highCmd::HighCmd;
+use super::lowCmd::LowCmd;
+use super::unitreeConnection::SEND_PORT_LOW;
+
+pub const HIGH_STATE_LEN: usize = 1087;
+pub const LOW_STATE_LEN: usize = 807;
+
+#[derive(Debug, Clone)]
+pub struct DiscoveredRobot {
+    pub profile: String,     // name of the profile that reached the robot
+    pub level: Level,
+    pub local_ip: IpAddr,    // our interface on the robot's network
+    pub robot_addr: SocketAddr,
+    pub sn: [u8; 8],
+    pub product: String,     // e.g. Go1_AIR
+    pub id: String,
+    pub hardware_version: String,
+    pub software_version: String,
+    pub round_trip: Duration, // time from init frame to first state reply
+}
+
+// Probe the standard wifi/ethernet endpoints of the dog
+pub fn discover(timeout: Duration) -> Vec<DiscoveredRobot> {
+    let mut profiles: Vec<ConnectionProfile> = builtin_profiles().into_values().collect();
+    profiles.sort_by(|a, b| a.name.cmp(&b.name));
+    discover_profiles(&profiles, timeout)
+}
+
+// Probe every profile in parallel. Profiles whose local ip does not exist on this host are
+// skipped, so the same list works on wifi, ethernet and on the dog's own boards.
+pub fn discover_profiles(profiles: &[ConnectionProfile], timeout: Duration) -> Vec<DiscoveredRobot> {
+    let handles: Vec<_> = profiles
+        .iter()
+        .cloned()
+        .map(|profile| thread::spawn(move || probe(&profile, timeout)))
+        .collect();
+    handles.into_iter().filter_map(|h| h.join().ok().flatten()).collect()
+}
+
+pub fn profile_level(profile: &ConnectionProfile) -> Level {
+    if profile.send_port == SEND_PORT_LOW {
+        Level::Low
+    } else {
+        Level::High
+    }
+}
+
+// The empty command the examples send to tell the dog where to stream its state
+pub fn init_frame(level: Level) -> Vec<u8> {
+    match level {
+        Level::High => HighCmd::new().build_cmd(false),
+        Level::Low => LowCmd::new().build_cmd(false),
+    }
+}
+
+// Send the init frame from an ephemeral port and wait for the first state reply
+pub fn probe(profile: &ConnectionProfile, timeout: Duration) -> Option<DiscoveredRobot> {
+    let local_ip = IpAddr::V4(profile.local_ip);
+    let socket = UdpSocket::bind((local_ip, 0)).ok()?; // interface not present here
+    let robot_addr = profile.send_addr();
+    let level = profile_level(profile);
+
+    let started = Instant::now();
+    socket.send_to(&init_frame(level), robot_addr).ok()?;
+
+    let mut buffer = [0; 2048];
+    loop {
+        let remaining = timeout.checked_sub(started.elapsed())?;
+        if remaining.is_zero() {
+            return None;
+        }
+        socket.set_read_timeout(Some(remaining)).ok()?;
+        let (size, from) = match socket.recv_from(&mut buffer) {
+            Ok(received) => received,
+            Err(_) => return None,
+        };
+        if from.ip() != robot_addr.ip() {
+            continue;
+        }
+        if let Some(reply_level) = classify_state(&buffer[..size]) {
+            return Some(describe(profile, local_ip, robot_addr, reply_level, &buffer[..size], started.elapsed()));
+        }
+    }
+}
+
+// HighState and LowState replies differ in length and level flag
+pub fn classify_state(data: &[u8]) -> Option<Level> {
+    match (data.len(), data.get(2)) {
+        (HIGH_STATE_LEN, Some(0x00)) => Some(Level::High),
+        (LOW_STATE_LEN, Some(0xff)) => Some(Level::Low),
+        _ => None,
+    }
+}
+
+fn describe(profile: &ConnectionProfile, local_ip: IpAddr, robot_addr: SocketAddr, level: Level, data: &[u8], round_trip: Duration) -> DiscoveredRobot {
+    let mut sn = [0; 8];
+    sn.copy_from_slice(&data[4..12]);
+    let mut version = [0; 6];
+    version.copy_from_slice(&data[12..18]);
+    let mut sn_short = [0; 6];
+    sn_short.copy_from_slice(&sn[..6]);
+
+    let (product, id) = decode_sn(&sn_short);
+    let (hardware_version, software_version) = decode_version(&version);
+    DiscoveredRobot {
+        profile: profile.name.clone(),
+        level,
+        local_ip,
+        robot_addr,
+        sn,
+        product,
+        id,
+        hardware_version,
+        software_version,
+        round_trip,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ucl::mock::{MockConfig, UdpMock};
+
+    fn dog(level: Level, id: u8) -> UdpMock {
+        let config = MockConfig { sn: [0x4d, 0x4f, 0x43, 0x4b, 0, 0, 0, id], level, ..MockConfig::default() };
+        UdpMock::bind(IpAddr::from([127, 0, 0, 1]), 0, 0, config).unwrap()
+    }
+
+    fn profile(name: &str, robot: SocketAddr) -> ConnectionProfile {
+        ConnectionProfile::new(name, "127.0.0.1", robot.port(), "127.0.0.1", 0)
+    }
+
+    #[test]
+    fn classify_state_by_length_and_flag() {
+        let mut high = vec![0u8; HIGH_STATE_LEN];
+        let mut low = vec![0u8; LOW_STATE_LEN];
+        low[2] = 0xff;
+        assert_eq!(classify_state(&high), Some(Level::High));
+        assert_eq!(classify_state(&low), Some(Level::Low));
+        assert_eq!(classify_state(&high[..HIGH_STATE_LEN - 1]), None);
+        assert_eq!(classify_state(&low[..2]), None);
+        assert_eq!(classify_state(&[]), None);
+        high[2] = 0xff;
+        low[2] = 0x00;
+        assert_eq!((classify_state(&high), classify_state(&low)), (None, None));
+    }
+
+    #[test]
+    fn probe_finds_the_mock() {
+        let mock = dog(Level::High, 7);
+        let robot = probe(&profile("mock", mock.high_addr()), Duration::from_secs(1)).unwrap();
+        assert_eq!((robot.profile.as_str(), robot.level, robot.robot_addr), ("mock", Level::High, mock.high_addr()));
+        assert_eq!(robot.sn, [0x4d, 0x4f, 0x43, 0x4b, 0, 0, 0, 7]);
+        assert!(robot.round_trip < Duration::from_secs(1));
+    }
+
+    #[test]
+    fn discover_profiles_skips_what_does_not_answer() {
+        let (high, low) = (dog(Level::High, 1), dog(Level::Low, 2));
+        let silent = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let profiles = [
+            profile("high", high.high_addr()),
+            profile("low", low.low_addr()),
+            profile("silent", silent.local_addr().unwrap()),
+            ConnectionProfile::new("elsewhere", "192.0.2.1", 8082, "192.0.2.14", 0), // no such interface here
+        ];
+        let started = Instant::now();
+        let mut found = discover_profiles(&profiles, Duration::from_millis(300));
+        assert!(started.elapsed() < Duration::from_secs(1), "probes run in parallel");
+        found.sort_by(|a, b| a.profile.cmp(&b.profile));
+        let summary: Vec<_> = found.iter().map(|robot| (robot.profile.as_str(), robot.level, robot.sn[7])).collect();
+        assert_eq!(summary, vec![("high", Level::High, 1), ("low", Level::Low, 2)]);
+    }
+}
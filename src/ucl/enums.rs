@@ -1,4 +1,4 @@
-#[derive(Debug, PartialEq, Eq)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum MotorModeHigh {
     Idle = 0,
     ForceStand,
@@ -16,7 +16,7 @@ pub enum MotorModeHigh {
     Dance2,
 }
 
-#[derive(Debug, PartialEq, Eq)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum GaitType {
     Idle = 0,
     Trot,
@@ -25,14 +25,14 @@ pub enum GaitType {
     TrotObstacle,
 }
 
-#[derive(Debug, PartialEq, Eq)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum SpeedLevel {
     LowSpeed = 0,
     MediumSpeed,
     HighSpeed,
 }
 
-#[derive(Debug, PartialEq, Eq)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum Motor {
     Fr0 = 0,
     Fr1,
@@ -48,9 +48,103 @@ pub enum Motor {
     Rl2,
 }
 
-#[derive(Debug, PartialEq, Eq)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum MotorModeLow {
     Damping = 0x00,
     Servo = 0x0A,
     Overheat = 0x08,
 }
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Level {
+    High = 0x00,
+    Low = 0xff,
+}
+
+// Keys of the wireless remote, the value is the bit in the u16 at wireless_remote[2..4]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum RemoteKey {
+    R1 = 0,
+    L1,
+    Start,
+    Select,
+    R2,
+    L2,
+    F1,
+    F2,
+    A,
+    B,
+    X,
+    Y,
+    Up,
+    Right,
+    Down,
+    Left,
+}
+
+impl RemoteKey {
+    pub const fn mask(self) -> u16 {
+        1 << self as u16
+    }
+}
+
+// The official SDK's LeggedType, only needed where the models differ (joint limits)
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum RobotModel {
+    Aliengo,
+    A1,
+    Go1,
+}
+
+// Decoding the mode bytes of received frames, Err carries the unknown value
+impl TryFrom<u8> for MotorModeHigh {
+    type Error = u8;
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        match value {
+            0 => Ok(MotorModeHigh::Idle),
+            1 => Ok(MotorModeHigh::ForceStand),
+            2 => Ok(MotorModeHigh::VelWalk),
+            3 => Ok(MotorModeHigh::PosWalk),
+            4 => Ok(MotorModeHigh::Path),
+            5 => Ok(MotorModeHigh::StandDown),
+            6 => Ok(MotorModeHigh::StandUp),
+            7 => Ok(MotorModeHigh::Damping),
+            8 => Ok(MotorModeHigh::Recovery),
+            9 => Ok(MotorModeHigh::Backflip),
+            10 => Ok(MotorModeHigh::Jumpyaw),
+            11 => Ok(MotorModeHigh::Straighthand),
+            12 => Ok(MotorModeHigh::Dance1),
+            13 => Ok(MotorModeHigh::Dance2),
+            _ => Err(value),
+        }
+    }
+}
+
+impl TryFrom<u8> for GaitType {
+    type Error = u8;
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        match value {
+            0 => Ok(GaitType::Idle),
+            1 => Ok(GaitType::Trot),
+            2 => Ok(GaitType::TrotRunning),
+            3 => Ok(GaitType::ClimbStair),
+            4 => Ok(GaitType::TrotObstacle),
+            _ => Err(value),
+        }
+    }
+}
+
+impl TryFrom<u8> for SpeedLevel {
+    type Error = u8;
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        match value {
+            0 => Ok(SpeedLevel::LowSpeed),
+            1 => Ok(SpeedLevel::MediumSpeed),
+            2 => Ok(SpeedLevel::HighSpeed),
+            _ => Err(value),
+        }
+    }
+}
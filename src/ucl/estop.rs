@@ -0,0 +1,293 @@
+use std::io;
+use std::panic;
+use std::process;
+use std::sync::atomic::{AtomicBool, AtomicI32, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+use super::enums::Level;
+use super::highCmd::HighCmd;
+use super::keepalive::CommandSlot;
+use super::lowCmd::LowCmd;
+use super::transport::Transport;
+
+// Last signal caught by the handler, 0 for none. The handler itself only stores it,
+// everything else happens on the watcher thread.
+static CAUGHT_SIGNAL: AtomicI32 = AtomicI32::new(0);
+const SIGNAL_POLL: Duration = Duration::from_millis(5);
+
+#[derive(Debug, Clone)]
+pub struct EStopConfig {
+    pub duration: Duration, // how long damping frames are sent after a trip
+    pub period: Duration,
+}
+
+impl Default for EStopConfig {
+    fn default() -> Self {
+        // Long enough for a walking dog to settle, at the rate the dog expects commands
+        EStopConfig { duration: Duration::from_secs(3), period: Duration::from_millis(2) }
+    }
+}
+
+type SendFn = Box<dyn Fn(&[u8]) -> io::Result<()> + Send + Sync>;
+type HaltFn = Box<dyn Fn() -> bool + Send + Sync>;
+
+// Emergency stop for one connection. Share it as Arc<EStop>, any thread can trip it:
+//   let estop = Arc::new(EStop::new(Arc::clone(&conn), Level::High, EStopConfig::default())
+//       .with_keepalive(conn.command_slot()));
+//   estop::install_signal_hook(Arc::clone(&estop))?;
+//   estop::install_panic_hook(Arc::clone(&estop));
+pub struct EStop {
+    send: SendFn,
+    halt: HaltFn,
+    frame: Vec<u8>,
+    config: EStopConfig,
+    keepalive: Option<Arc<CommandSlot>>,
+    tripped: AtomicBool,
+    reason: Mutex<Option<String>>,
+    done: (Mutex<bool>, Condvar), // set once the damping frames went out
+}
+
+impl EStop {
+    pub fn new<T>(transport: Arc<T>, level: Level, config: EStopConfig) -> Self
+    where
+        T: Transport + Send + Sync + ?Sized + 'static,
+    {
+        let frame = match level {
+            Level::High => HighCmd::damping().build_cmd(false),
+            Level::Low => LowCmd::damping().build_cmd(false),
+        };
+        let gate = Arc::clone(&transport);
+        EStop {
+            halt: Box::new(move || gate.halt()),
+            send: Box::new(move |frame: &[u8]| transport.send_halted(frame)),
+            frame,
+            config,
+            keepalive: None,
+            tripped: AtomicBool::new(false),
+            reason: Mutex::new(None),
+            done: (Mutex::new(false), Condvar::new()),
+        }
+    }
+
+    // Stop this keepalive on a trip too. Not needed for a keepalive running on the halted transport
+    // itself (UnitreeConnection::start_keepalive, keepalive::start), the halt already blocks it.
+    pub fn with_keepalive(mut self, slot: Arc<CommandSlot>) -> Self {
+        self.keepalive = Some(slot);
+        self
+    }
+
+    pub fn is_tripped(&self) -> bool {
+        self.tripped.load(Ordering::SeqCst)
+    }
+
+    // Why it was tripped, None while it was not
+    pub fn reason(&self) -> Option<String> {
+        self.reason.lock().unwrap().clone()
+    }
+
+    // Halt the transport, so no other sender (control loop, keepalive, set_command) gets a frame
+    // through any more, and send damping frames for the configured duration. Blocks until they
+    // went out, also when another thread tripped first, so the caller may exit right after.
+    pub fn trip(&self, reason: &str) {
+        if self.tripped.swap(true, Ordering::SeqCst) {
+            // Bounded, a panic while sending would otherwise leave the panic hook waiting forever
+            let (done, finished) = &self.done;
+            let timeout = self.config.duration + Duration::from_secs(1);
+            let _ = finished.wait_timeout_while(done.lock().unwrap(), timeout, |done| !*done);
+            return;
+        }
+        eprintln!("[estop] tripped: {}", reason);
+        *self.reason.lock().unwrap() = Some(reason.to_string());
+        if !(self.halt)() {
+            eprintln!("[estop] this transport can not be halted, other senders are not blocked");
+        }
+        if let Some(slot) = &self.keepalive {
+            slot.stop();
+        }
+
+        // Errors are only counted, a dropped frame must not end the stop early or panic the hook
+        let started = Instant::now();
+        let (mut sent, mut failed) = (0, 0);
+        while started.elapsed() < self.config.duration {
+            sent += 1;
+            if (self.send)(&self.frame).is_err() {
+                failed += 1;
+            }
+            thread::sleep(self.config.period);
+        }
+        if failed > 0 {
+            eprintln!("[estop] {} of {} damping frames could not be sent", failed, sent);
+        }
+
+        let (done, finished) = &self.done;
+        *done.lock().unwrap() = true;
+        finished.notify_all();
+    }
+}
+
+extern "C" fn on_signal(signal: libc::c_int) {
+    CAUGHT_SIGNAL.store(signal, Ordering::SeqCst);
+}
+
+// Trip on SIGINT and SIGTERM, then exit with the usual 128 + signal code
+pub fn install_signal_hook(estop: Arc<EStop>) -> io::Result<()> {
+    for signal in [libc::SIGINT, libc::SIGTERM] {
+        let handler = on_signal as extern "C" fn(libc::c_int) as libc::sighandler_t;
+        if unsafe { libc::signal(signal, handler) } == libc::SIG_ERR {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    thread::spawn(move || loop {
+        let signal = CAUGHT_SIGNAL.load(Ordering::SeqCst);
+        if signal != 0 {
+            estop.trip(&format!("signal {}", signal));
+            process::exit(128 + signal);
+        }
+        thread::sleep(SIGNAL_POLL);
+    });
+    Ok(())
+}
+
+// Trip on a panic in any thread. The panic message is printed first, the previous hook still runs.
+pub fn install_panic_hook(estop: Arc<EStop>) {
+    let previous = panic::take_hook();
+    panic::set_hook(Box::new(move |info| {
+        previous(info);
+        estop.trip(&format!("panic: {}", info));
+    }));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ucl::controlLoop::Step;
+    use crate::ucl::enums::MotorModeLow;
+    use crate::ucl::keepalive::{self, KeepaliveConfig};
+    use crate::ucl::transport::channel_pair;
+
+    fn short() -> EStopConfig {
+        EStopConfig { duration: Duration::from_millis(30), period: Duration::from_millis(2) }
+    }
+
+    #[test]
+    fn low_level_trip_sends_decodable_damping() {
+        let (ours, dog) = channel_pair();
+        let ours = Arc::new(ours);
+        let slot = keepalive::start(Arc::clone(&ours), KeepaliveConfig::low());
+        slot.set(&LowCmd::new().build_cmd(false));
+        let estop = EStop::new(Arc::clone(&ours), Level::Low, short()).with_keepalive(Arc::clone(&slot));
+
+        estop.trip("test");
+        assert!(estop.is_tripped());
+        assert_eq!(estop.reason().as_deref(), Some("test"));
+        assert!(!slot.is_running());
+
+        // The keepalive may have got one more frame out, everything after it is damping
+        let frames = dog.get_data();
+        let last = LowCmd::from_bytes(frames.last().unwrap()).unwrap();
+        for motor in 0..12 {
+            assert_eq!(last.motor_cmd.get_motor_cmd(motor).unwrap().mode(), MotorModeLow::Damping as u8);
+        }
+        assert!(frames.iter().rev().take(5).all(|frame| frame == &estop.frame));
+    }
+
+    #[test]
+    fn high_level_frame_is_damping() {
+        let (ours, _dog) = channel_pair();
+        let estop = EStop::new(Arc::new(ours), Level::High, short());
+        let hcmd = HighCmd::from_bytes(&estop.frame).unwrap();
+        assert_eq!(hcmd.mode(), Ok(crate::ucl::enums::MotorModeHigh::Damping));
+    }
+
+    // A link that is down: send panics like UnitreeConnection::send, try_send fails
+    struct DeadLink;
+
+    impl Transport for DeadLink {
+        fn send(&self, _data: &[u8]) {
+            panic!("send on a dead link");
+        }
+
+        fn try_send(&self, _data: &[u8]) -> io::Result<()> {
+            Err(io::Error::new(io::ErrorKind::NetworkUnreachable, "down"))
+        }
+
+        fn get_data_timestamped(&self) -> Vec<(Instant, Vec<u8>)> {
+            Vec::new()
+        }
+
+        fn last_received(&self) -> Option<Instant> {
+            None
+        }
+    }
+
+    fn walk() -> Vec<u8> {
+        let mut hcmd = HighCmd::new();
+        hcmd.set_mode(crate::ucl::enums::MotorModeHigh::VelWalk);
+        hcmd.velocity = [0.5, 0.0];
+        hcmd.build_cmd(false)
+    }
+
+    #[test]
+    fn control_loop_and_keepalive_can_not_override_a_trip() {
+        let (ours, dog) = channel_pair();
+        let ours = Arc::new(ours);
+        let slot = keepalive::start(Arc::clone(&ours), KeepaliveConfig::high());
+        slot.set(&walk());
+        let estop = Arc::new(EStop::new(Arc::clone(&ours), Level::High, short()));
+
+        // The control loop keeps sending walk frames while another thread trips
+        let tripper = Arc::clone(&estop);
+        let trip = thread::spawn(move || tripper.trip("test"));
+        let mut control = crate::ucl::controlLoop::ControlLoop::with_period(Duration::from_millis(1));
+        control.run(&*ours, |_| {
+            slot.set(&walk());
+            if trip.is_finished() { Step::Stop } else { Step::Send(walk()) }
+        });
+        trip.join().unwrap();
+
+        // Walk frames from before the trip, then nothing but damping
+        let frames = dog.get_data();
+        let first_damping = frames.iter().position(|frame| *frame == estop.frame).unwrap();
+        assert!(frames[first_damping..].iter().all(|frame| *frame == estop.frame));
+
+        assert!(ours.try_send(&walk()).is_err());
+        ours.send(&walk());
+        slot.set(&walk());
+        thread::sleep(Duration::from_millis(10));
+        assert!(dog.get_data().is_empty());
+        slot.stop();
+    }
+
+    #[test]
+    fn halted_connection_drops_commands() {
+        use std::net::{IpAddr, Ipv4Addr, UdpSocket};
+        use crate::ucl::unitreeConnection::UnitreeConnection;
+
+        let dog = UdpSocket::bind("127.0.0.1:0").unwrap();
+        dog.set_read_timeout(Some(Duration::from_millis(50))).unwrap();
+        let conn = Arc::new(UnitreeConnection::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 0, dog.local_addr().unwrap()));
+        conn.start_keepalive(KeepaliveConfig::high());
+        conn.set_command(&walk());
+        let estop = EStop::new(Arc::clone(&conn), Level::High, short());
+        estop.trip("test");
+        assert!(conn.is_halted());
+
+        let mut buffer = [0; 2048];
+        while dog.recv_from(&mut buffer).is_ok() {} // everything sent up to now
+        conn.set_command(&walk());
+        conn.send(&walk());
+        conn.send_to(&walk(), dog.local_addr().unwrap());
+        assert!(conn.try_send(&walk()).is_err());
+        assert!(dog.recv_from(&mut buffer).is_err());
+    }
+
+    #[test]
+    fn send_errors_do_not_end_the_trip() {
+        let estop = EStop::new(Arc::new(DeadLink), Level::Low, short());
+        let started = Instant::now();
+        estop.trip("link down");
+        assert!(started.elapsed() >= short().duration);
+        assert!(estop.is_tripped());
+    }
+}
@@ -0,0 +1,300 @@
+use std::time::{Duration, Instant};
+use super::complex::{Imu, MotorCmdArray};
+use super::enums::MotorModeHigh;
+use super::highCmd::HighCmd;
+use super::highState::HighState;
+use super::lowState::LowState;
+
+#[derive(Debug, Clone)]
+pub struct FallConfig {
+    pub max_roll: f32,        // rad
+    pub max_pitch: f32,       // rad
+    pub free_fall_accel: f32, // m/s^2, accelerometer magnitude below this means falling
+    pub contact_force: u16,   // foot_force above this counts as ground contact
+    pub tip_tilt: f32,        // rad, roll or pitch above this with too few feet down is a tip-over
+    pub min_contacts: usize,
+    pub hold: Duration, // how long a condition has to last, walking alone produces short spikes
+    // Backflip and Jumpyaw go upside down and through the air on purpose, detection is off while
+    // one is commanded or reported and for this long after, until the dog has landed
+    pub acrobatic_grace: Duration,
+}
+
+impl Default for FallConfig {
+    fn default() -> Self {
+        FallConfig {
+            max_roll: 0.8,
+            max_pitch: 0.8,
+            free_fall_accel: 3.0,
+            contact_force: 20,
+            tip_tilt: 0.4,
+            min_contacts: 2,
+            hold: Duration::from_millis(100),
+            acrobatic_grace: Duration::from_millis(500),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FallReason {
+    Tilt { roll: f32, pitch: f32 },
+    FreeFall { accel: f32 },
+    TipOver { contacts: usize },
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct FallEvent {
+    pub reason: FallReason,
+    pub rpy: [f32; 3],
+    pub at: Instant,
+}
+
+pub type FallCallback = Box<dyn FnMut(&FallEvent) + Send>;
+
+// Watches the IMU and the foot contacts for a fall. Once it triggers the monitor stays fallen,
+// overrides every outgoing command with damping and leaves it to the application whether to
+// send Recovery. Call reset() once the dog is back on its feet.
+pub struct FallMonitor {
+    config: FallConfig,
+    suspect: Option<(Instant, FallReason)>,
+    fallen: Option<FallEvent>,
+    acrobatic_until: Option<Instant>,
+    callbacks: Vec<FallCallback>,
+}
+
+fn is_acrobatic(mode: MotorModeHigh) -> bool {
+    matches!(mode, MotorModeHigh::Backflip | MotorModeHigh::Jumpyaw)
+}
+
+impl FallMonitor {
+    pub fn new(config: FallConfig) -> Self {
+        FallMonitor { config, suspect: None, fallen: None, acrobatic_until: None, callbacks: Vec::new() }
+    }
+
+    // Called once per fall, when the monitor triggers
+    pub fn on_fall<F>(&mut self, callback: F)
+    where
+        F: FnMut(&FallEvent) + Send + 'static,
+    {
+        self.callbacks.push(Box::new(callback));
+    }
+
+    pub fn has_fallen(&self) -> bool {
+        self.fallen.is_some()
+    }
+
+    pub fn fall(&self) -> Option<&FallEvent> {
+        self.fallen.as_ref()
+    }
+
+    pub fn reset(&mut self) {
+        self.suspect = None;
+        self.fallen = None;
+        self.acrobatic_until = None;
+    }
+
+    // The mode about to be sent, so detection is already off when the flip starts and before
+    // the state reports it
+    pub fn commanded(&mut self, mode: MotorModeHigh) {
+        if is_acrobatic(mode) {
+            self.acrobatic_until = Some(Instant::now() + self.config.acrobatic_grace);
+        }
+    }
+
+    // True while an acrobatic mode is commanded or reported, or its grace period runs
+    pub fn is_suppressed(&self) -> bool {
+        self.acrobatic_until.is_some_and(|until| Instant::now() < until)
+    }
+
+    pub fn update_high(&mut self, state: &HighState) -> Option<FallEvent> {
+        if let Ok(mode) = state.mode() {
+            self.commanded(mode);
+        }
+        self.update(&state.imu, &state.foot_force)
+    }
+
+    pub fn update_low(&mut self, state: &LowState) -> Option<FallEvent> {
+        self.update(&state.imu, &state.foot_force)
+    }
+
+    // Feed every received state, returns the event in the cycle the monitor triggers
+    pub fn update(&mut self, imu: &Imu, foot_force: &[u16; 4]) -> Option<FallEvent> {
+        if self.fallen.is_some() {
+            return None;
+        }
+        let now = Instant::now();
+        // A failed flip still ends on the back, which is caught once the grace period is over
+        if self.is_suppressed() {
+            self.suspect = None;
+            return None;
+        }
+        let Some(reason) = self.check(imu, foot_force) else {
+            self.suspect = None;
+            return None;
+        };
+
+        // The reason may change while falling (tilt, then lost contact), the clock keeps running
+        let since = match self.suspect {
+            Some((since, _)) => since,
+            None => now,
+        };
+        self.suspect = Some((since, reason));
+        if now.duration_since(since) < self.config.hold {
+            return None;
+        }
+
+        let event = FallEvent { reason, rpy: imu.rpy, at: now };
+        for callback in self.callbacks.iter_mut() {
+            callback(&event);
+        }
+        self.fallen = Some(event.clone());
+        Some(event)
+    }
+
+    fn check(&self, imu: &Imu, foot_force: &[u16; 4]) -> Option<FallReason> {
+        let (roll, pitch) = (imu.rpy[0], imu.rpy[1]);
+        if roll.abs() > self.config.max_roll || pitch.abs() > self.config.max_pitch {
+            return Some(FallReason::Tilt { roll, pitch });
+        }
+        let accel = imu.accelerometer.iter().map(|a| a * a).sum::<f32>().sqrt();
+        if accel < self.config.free_fall_accel {
+            return Some(FallReason::FreeFall { accel });
+        }
+        let contacts = foot_force.iter().filter(|f| **f > self.config.contact_force).count();
+        let tilted = roll.abs() > self.config.tip_tilt || pitch.abs() > self.config.tip_tilt;
+        if tilted && contacts < self.config.min_contacts {
+            return Some(FallReason::TipOver { contacts });
+        }
+        None
+    }
+
+    // Damping instead of `cmd` while fallen, a Recovery command from the application goes through.
+    // Returns true if the command was replaced.
+    pub fn override_high(&self, cmd: &mut HighCmd) -> bool {
+        if self.fallen.is_none() || cmd.mode() == Ok(MotorModeHigh::Recovery) {
+            return false;
+        }
+        *cmd = HighCmd::damping();
+        true
+    }
+
+    // Zero torque damping on every motor while fallen
+    pub fn override_low(&self, cmd: &mut MotorCmdArray) -> bool {
+        if self.fallen.is_none() {
+            return false;
+        }
+        *cmd = MotorCmdArray::damping();
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ucl::enums::MotorModeLow;
+    use std::sync::{Arc, Mutex};
+    use std::thread;
+
+    const STANDING: [u16; 4] = [100; 4];
+
+    fn imu(roll: f32, pitch: f32, accel: f32) -> Imu {
+        Imu { rpy: [roll, pitch, 0.0], accelerometer: [0.0, 0.0, accel], ..Imu::default() }
+    }
+
+    fn monitor(hold_ms: u64, grace_ms: u64) -> FallMonitor {
+        FallMonitor::new(FallConfig {
+            hold: Duration::from_millis(hold_ms),
+            acrobatic_grace: Duration::from_millis(grace_ms),
+            ..FallConfig::default()
+        })
+    }
+
+    #[test]
+    fn tilt_has_to_last_for_hold() {
+        let mut fall = monitor(30, 0);
+        assert!(fall.update(&imu(1.0, 0.0, 9.8), &STANDING).is_none());
+        // A spike that goes away restarts the clock
+        fall.update(&imu(0.0, 0.0, 9.8), &STANDING);
+        thread::sleep(Duration::from_millis(40));
+        assert!(fall.update(&imu(0.0, 1.0, 9.8), &STANDING).is_none());
+        thread::sleep(Duration::from_millis(40));
+        let event = fall.update(&imu(0.0, 1.0, 9.8), &STANDING).unwrap();
+        assert_eq!(event.reason, FallReason::Tilt { roll: 0.0, pitch: 1.0 });
+        assert!(fall.has_fallen());
+    }
+
+    #[test]
+    fn free_fall_and_tip_over() {
+        let mut fall = monitor(0, 0);
+        assert_eq!(fall.update(&imu(0.0, 0.0, 1.0), &STANDING).unwrap().reason, FallReason::FreeFall { accel: 1.0 });
+
+        let mut fall = monitor(0, 0);
+        assert!(fall.update(&imu(0.5, 0.0, 9.8), &STANDING).is_none());
+        let event = fall.update(&imu(0.5, 0.0, 9.8), &[100, 0, 0, 0]).unwrap();
+        assert_eq!(event.reason, FallReason::TipOver { contacts: 1 });
+    }
+
+    #[test]
+    fn triggers_once_and_calls_back() {
+        let calls = Arc::new(Mutex::new(0));
+        let counter = Arc::clone(&calls);
+        let mut fall = monitor(0, 0);
+        fall.on_fall(move |_| *counter.lock().unwrap() += 1);
+        assert!(fall.update(&imu(1.0, 0.0, 9.8), &STANDING).is_some());
+        assert!(fall.update(&imu(1.0, 0.0, 9.8), &STANDING).is_none());
+        assert_eq!(*calls.lock().unwrap(), 1);
+    }
+
+    #[test]
+    fn overrides_until_reset() {
+        let mut fall = monitor(0, 0);
+        let mut cmd = HighCmd::new();
+        cmd.velocity = [0.5, 0.0];
+        assert!(!fall.override_high(&mut cmd));
+        assert_eq!(cmd.velocity, [0.5, 0.0]);
+
+        fall.update(&imu(1.0, 0.0, 9.8), &STANDING);
+        assert!(fall.override_high(&mut cmd));
+        assert_eq!(cmd.mode(), Ok(MotorModeHigh::Damping));
+        let mut recovery = HighCmd::new();
+        recovery.set_mode(MotorModeHigh::Recovery);
+        assert!(!fall.override_high(&mut recovery));
+        assert_eq!(recovery.mode(), Ok(MotorModeHigh::Recovery));
+        let mut low = MotorCmdArray::new();
+        assert!(fall.override_low(&mut low));
+        assert_eq!(low.get_motor_cmd(0).unwrap().mode(), MotorModeLow::Damping as u8);
+
+        fall.reset();
+        assert!(!fall.has_fallen());
+        assert!(!fall.override_high(&mut HighCmd::new()));
+    }
+
+    #[test]
+    fn acrobatics_suppress_detection_until_grace_ends() {
+        let mut fall = monitor(0, 50);
+        fall.commanded(MotorModeHigh::Backflip);
+        assert!(fall.is_suppressed());
+        assert!(fall.update(&imu(3.0, 0.0, 9.8), &[0; 4]).is_none());
+        assert!(fall.update(&imu(0.0, 0.0, 0.5), &[0; 4]).is_none());
+
+        // Reported by the dog counts as well
+        let mut state = HighState::new();
+        state.mode = MotorModeHigh::Jumpyaw as u8;
+        state.imu = imu(0.0, 3.0, 9.8);
+        thread::sleep(Duration::from_millis(30));
+        assert!(fall.update_high(&state).is_none());
+
+        // Still on its back once the flip is over
+        state.mode = MotorModeHigh::Idle as u8;
+        thread::sleep(Duration::from_millis(60));
+        assert!(!fall.is_suppressed());
+        assert!(fall.update_high(&state).is_some());
+    }
+
+    #[test]
+    fn other_modes_do_not_suppress() {
+        let mut fall = monitor(0, 50);
+        fall.commanded(MotorModeHigh::VelWalk);
+        assert!(!fall.is_suppressed());
+        assert!(fall.update(&imu(1.0, 0.0, 9.8), &STANDING).is_some());
+    }
+}
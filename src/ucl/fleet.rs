@@ -0,0 +1,317 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::io;
+use std::net::IpAddr;
+use std::thread;
+use std::time::{Duration, Instant};
+use super::common::byte_print;
+use super::config::ConnectionProfile;
+use super::discovery::{classify_state, init_frame, profile_level};
+use super::enums::Level;
+use super::estop::EStopConfig;
+use super::highCmd::HighCmd;
+use super::highState::HighState;
+use super::lowCmd::LowCmd;
+use super::unitreeConnection::UnitreeConnection;
+
+#[derive(Debug)]
+pub enum FleetError {
+    PortInUse(String),       // another member already listens on this local ip/port
+    Bind(String, io::Error), // local ip/port could not be bound, e.g. taken by another program
+    NoReply(String),         // profile name, the robot never answered the init frame
+    DuplicateRobot(String),  // SN already in the fleet
+    UnknownRobot(String),
+    Send(String, io::Error), // SN, the command could not be sent
+}
+
+impl fmt::Display for FleetError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            FleetError::PortInUse(addr) => write!(f, "local address {} is already used by another robot", addr),
+            FleetError::Bind(addr, e) => write!(f, "could not bind local address {}: {}", addr, e),
+            FleetError::NoReply(profile) => write!(f, "no state reply on profile '{}'", profile),
+            FleetError::DuplicateRobot(sn) => write!(f, "robot {} is already part of the fleet", sn),
+            FleetError::UnknownRobot(sn) => write!(f, "no robot {} in the fleet", sn),
+            FleetError::Send(sn, e) => write!(f, "could not send to robot {}: {}", sn, e),
+        }
+    }
+}
+
+impl std::error::Error for FleetError {}
+
+pub struct FleetMember {
+    pub sn: String, // hex SN as printed by byte_print
+    pub profile: ConnectionProfile,
+    pub level: Level,
+    conn: UnitreeConnection,
+    latest: Option<Vec<u8>>,
+    last_update: Option<Instant>,
+}
+
+impl FleetMember {
+    pub fn latest_state(&self) -> Option<&[u8]> {
+        self.latest.as_deref()
+    }
+
+    pub fn last_update(&self) -> Option<Instant> {
+        self.last_update
+    }
+
+    pub fn send(&self, cmd: &[u8]) -> io::Result<()> {
+        self.conn.try_send(cmd)
+    }
+}
+
+// One session per dog, each on its own local port or interface, keyed by SN
+pub struct Fleet {
+    members: HashMap<String, FleetMember>,
+    estop: EStopConfig, // how long and how often emergency_stop sends damping, as for one robot
+}
+
+impl Default for Fleet {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Fleet {
+    pub fn new() -> Self {
+        Fleet { members: HashMap::new(), estop: EStopConfig::default() }
+    }
+
+    pub fn with_estop(mut self, config: EStopConfig) -> Self {
+        self.estop = config;
+        self
+    }
+
+    // Open a session with `profile`, handshake and key it by the SN of the first state reply.
+    // Give every robot its own listen_port (or local_ip); 0 picks a free port.
+    pub fn add(&mut self, profile: ConnectionProfile, timeout: Duration) -> Result<String, FleetError> {
+        if profile.listen_port != 0 {
+            let clash = self.members.values().any(|m| {
+                m.profile.listen_port == profile.listen_port
+                    && (m.profile.local_ip == profile.local_ip || m.profile.local_ip.is_unspecified() || profile.local_ip.is_unspecified())
+            });
+            if clash {
+                return Err(FleetError::PortInUse(profile.listen_addr().to_string()));
+            }
+        }
+
+        let level = profile_level(&profile);
+        let conn = UnitreeConnection::try_new(IpAddr::V4(profile.local_ip), profile.listen_port, profile.send_addr())
+            .map_err(|e| FleetError::Bind(profile.listen_addr().to_string(), e))?;
+        conn.start_recv();
+        conn.try_send(&init_frame(level)).map_err(|_| FleetError::NoReply(profile.name.clone()))?;
+
+        let started = Instant::now();
+        let first_state = loop {
+            if let Some(packet) = conn.get_data().into_iter().rev().find(|p| classify_state(p).is_some()) {
+                break packet;
+            }
+            if started.elapsed() >= timeout {
+                return Err(FleetError::NoReply(profile.name.clone()));
+            }
+            thread::sleep(Duration::from_millis(10));
+        };
+
+        let sn = byte_print(&first_state[4..12]);
+        if self.members.contains_key(&sn) {
+            return Err(FleetError::DuplicateRobot(sn));
+        }
+        let member = FleetMember {
+            sn: sn.clone(),
+            profile,
+            level,
+            conn,
+            latest: Some(first_state),
+            last_update: Some(Instant::now()),
+        };
+        self.members.insert(sn.clone(), member);
+        Ok(sn)
+    }
+
+    pub fn remove(&mut self, sn: &str) -> Option<FleetMember> {
+        self.members.remove(sn)
+    }
+
+    pub fn sns(&self) -> Vec<String> {
+        let mut sns: Vec<String> = self.members.keys().cloned().collect();
+        sns.sort();
+        sns
+    }
+
+    pub fn get(&self, sn: &str) -> Option<&FleetMember> {
+        self.members.get(sn)
+    }
+
+    // Drain every session and keep only the newest state per robot
+    pub fn poll(&mut self) {
+        for member in self.members.values_mut() {
+            if let Some(packet) = member.conn.get_data().pop() {
+                member.latest = Some(packet);
+                member.last_update = Some(Instant::now());
+            }
+        }
+    }
+
+    pub fn latest_state(&self, sn: &str) -> Option<&[u8]> {
+        self.members.get(sn).and_then(|m| m.latest_state())
+    }
+
+    pub fn high_state(&self, sn: &str) -> Option<HighState> {
+        let packet = self.latest_state(sn)?;
+        if classify_state(packet) != Some(Level::High) {
+            return None;
+        }
+        let mut hstate = HighState::new();
+        hstate.parse_data(packet);
+        Some(hstate)
+    }
+
+    pub fn send(&self, sn: &str, cmd: &[u8]) -> Result<(), FleetError> {
+        let member = self.members.get(sn).ok_or_else(|| FleetError::UnknownRobot(sn.to_string()))?;
+        member.send(cmd).map_err(|e| FleetError::Send(sn.to_string(), e))
+    }
+
+    // Same frame to every robot of the given level. One that can not be reached does not keep
+    // the others from getting it, the failures are returned.
+    pub fn broadcast(&self, level: Level, cmd: &[u8]) -> Vec<FleetError> {
+        self.members
+            .values()
+            .filter(|m| m.level == level)
+            .filter_map(|m| m.send(cmd).err().map(|e| FleetError::Send(m.sn.clone(), e)))
+            .collect()
+    }
+
+    // Hold until `at`, then send each robot its frame back to back so they start together.
+    // Pick `at` a little in the future (e.g. Instant::now() + 100ms) so every frame is ready.
+    // Every robot gets its frame even if one send fails, the first failure is returned.
+    pub fn start_at(&self, at: Instant, cmds: &HashMap<String, Vec<u8>>) -> Result<(), FleetError> {
+        if let Some(unknown) = cmds.keys().find(|sn| !self.members.contains_key(*sn)) {
+            return Err(FleetError::UnknownRobot(unknown.clone()));
+        }
+        let wait = at.saturating_duration_since(Instant::now());
+        if !wait.is_zero() {
+            thread::sleep(wait);
+        }
+        let mut failed = None;
+        for (sn, cmd) in cmds.iter() {
+            if let Err(e) = self.members[sn].send(cmd) {
+                failed.get_or_insert(FleetError::Send(sn.clone(), e));
+            }
+        }
+        failed.map_or(Ok(()), Err)
+    }
+
+    // Damping to every robot, high level and low level alike, for as long as EStop would send it
+    // to one. Every session is halted first (see Transport::halt), so nothing else reaches the
+    // dogs any more. A robot that can not be reached must not stop the others from getting
+    // theirs, so send errors are only counted.
+    pub fn emergency_stop(&self) {
+        let high = HighCmd::damping().build_cmd(false);
+        let low = LowCmd::damping().build_cmd(false);
+        for member in self.members.values() {
+            member.conn.halt();
+        }
+        let started = Instant::now();
+        let mut failed: HashMap<&str, u64> = HashMap::new();
+        while started.elapsed() < self.estop.duration {
+            for member in self.members.values() {
+                let frame = match member.level {
+                    Level::High => &high,
+                    Level::Low => &low,
+                };
+                if member.conn.send_halted(frame).is_err() {
+                    *failed.entry(&member.sn).or_default() += 1;
+                }
+            }
+            thread::sleep(self.estop.period);
+        }
+        for (sn, count) in failed {
+            eprintln!("[fleet] {} damping frames could not be sent to {}", count, sn);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::UdpSocket;
+    use crate::ucl::enums::MotorModeHigh;
+    use crate::ucl::mock::{MockConfig, UdpMock};
+
+    fn dog(id: u8) -> UdpMock {
+        let config = MockConfig { sn: [0x4d, 0x4f, 0x43, 0x4b, 0, 0, 0, id], ..MockConfig::default() };
+        UdpMock::bind(IpAddr::from([127, 0, 0, 1]), 0, 0, config).unwrap()
+    }
+
+    fn profile(name: &str, dog: &UdpMock, listen_port: u16) -> ConnectionProfile {
+        ConnectionProfile::new(name, "127.0.0.1", dog.high_addr().port(), "127.0.0.1", listen_port)
+    }
+
+    fn short_estop() -> EStopConfig {
+        EStopConfig { duration: Duration::from_millis(50), period: Duration::from_millis(2) }
+    }
+
+    const TIMEOUT: Duration = Duration::from_secs(1);
+
+    #[test]
+    fn add_and_remove() {
+        let (one, two) = (dog(1), dog(2));
+        let mut fleet = Fleet::new();
+        let first = fleet.add(profile("one", &one, 0), TIMEOUT).unwrap();
+        let second = fleet.add(profile("two", &two, 0), TIMEOUT).unwrap();
+        assert_eq!((first.as_str(), second.as_str()), ("4d4f434b00000001", "4d4f434b00000002"));
+        assert_eq!(fleet.sns(), vec![first.clone(), second.clone()]);
+        assert!(matches!(fleet.add(profile("again", &one, 0), TIMEOUT), Err(FleetError::DuplicateRobot(sn)) if sn == first));
+
+        fleet.send(&first, &HighCmd::new().build_cmd(false)).unwrap();
+        thread::sleep(Duration::from_millis(20));
+        fleet.poll();
+        assert!(fleet.high_state(&first).is_some());
+
+        assert_eq!(fleet.remove(&first).map(|member| member.sn), Some(first.clone()));
+        assert_eq!(fleet.sns(), vec![second]);
+        assert!(matches!(fleet.send(&first, &[0]), Err(FleetError::UnknownRobot(_))));
+
+        let silent = ConnectionProfile::new("nobody", "127.0.0.1", 9, "127.0.0.1", 0);
+        assert!(matches!(fleet.add(silent, Duration::from_millis(50)), Err(FleetError::NoReply(name)) if name == "nobody"));
+    }
+
+    #[test]
+    fn port_clash_and_bind_failure_are_errors() {
+        let (one, two) = (dog(1), dog(2));
+        let taken = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let port = taken.local_addr().unwrap().port();
+        let mut fleet = Fleet::new();
+        assert!(matches!(fleet.add(profile("one", &one, port), TIMEOUT), Err(FleetError::Bind(..))));
+
+        drop(taken);
+        fleet.add(profile("one", &one, port), TIMEOUT).unwrap();
+        assert!(matches!(fleet.add(profile("two", &two, port), TIMEOUT), Err(FleetError::PortInUse(_))));
+    }
+
+    #[test]
+    fn emergency_stop_reaches_the_others_when_one_is_gone() {
+        let (one, two) = (dog(1), dog(2));
+        let mut fleet = Fleet::new().with_estop(short_estop());
+        let alive = fleet.add(profile("one", &one, 0), TIMEOUT).unwrap();
+        let gone = fleet.add(profile("two", &two, 0), TIMEOUT).unwrap();
+        let mut walk = HighCmd::new();
+        walk.set_mode(MotorModeHigh::VelWalk);
+        fleet.send(&alive, &walk.build_cmd(false)).unwrap();
+        drop(two);
+        thread::sleep(Duration::from_millis(100)); // its sockets are closed now, sends get refused
+
+        let started = Instant::now();
+        fleet.emergency_stop();
+        assert!(started.elapsed() >= short_estop().duration);
+        thread::sleep(Duration::from_millis(20));
+        assert_eq!(one.robot.lock().unwrap().mode, MotorModeHigh::Damping);
+
+        // Halted, nothing else goes out any more
+        assert!(matches!(fleet.send(&alive, &walk.build_cmd(false)), Err(FleetError::Send(..))));
+        assert_eq!(fleet.broadcast(Level::High, &walk.build_cmd(false)).len(), 2);
+        assert_eq!(fleet.get(&gone).map(|member| member.level), Some(Level::High));
+    }
+}
@@ -1,6 +1,8 @@
-use ucl::enums::{MotorModeHigh, GaitType, SpeedLevel};
-use ucl::common::{float_to_hex, encrypt_crc, gen_crc, byte_print};
-use ucl::complex::{Led, BmsCmd};
+use super::enums::{MotorModeHigh, GaitType, SpeedLevel};
+use super::common::{float_to_hex, hex_to_float, encrypt_crc, frame_crc};
+use super::complex::{Led, BmsCmd};
+
+const HIGH_CMD_LEN: usize = 129;
 
 #[derive(Debug, Clone)]
 pub struct HighCmd {
@@ -10,21 +12,29 @@ pub struct HighCmd {
     sn: [u8; 8],
     version: [u8; 8],
     band_width: [u8; 2],
-    mode: MotorModeHigh,
-    gait_type: GaitType,
-    speed_level: SpeedLevel,
-    foot_raise_height: f32,
-    body_height: f32,
-    position: [f32; 2],
-    euler: [f32; 3],
-    velocity: [f32; 2],
-    yaw_speed: f32,
-    bms: BmsCmd,
-    led: Led,
-    wireless_remote: [u8; 40],
+    // Raw bytes so values this build does not know survive a decode, see mode() and set_mode()
+    pub mode: u8,
+    pub gait_type: u8,
+    pub speed_level: u8,
+    pub foot_raise_height: f32,
+    pub body_height: f32,
+    pub position: [f32; 2],
+    pub euler: [f32; 3],
+    pub velocity: [f32; 2],
+    pub yaw_speed: f32,
+    pub bms: BmsCmd,
+    pub led: Led,
+    pub wireless_remote: [u8; 40],
     reserve: [u8; 4],
+    spare: [u8; 8], // bytes 117..125, zero in every frame seen so far
     crc: Option<[u8; 4]>,
-    encrypt: bool,
+    pub encrypt: bool,
+}
+
+impl Default for HighCmd {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl HighCmd {
@@ -36,9 +46,9 @@ impl HighCmd {
             sn: [0; 8],
             version: [0; 8],
             band_width: [0; 2],
-            mode: MotorModeHigh::Idle,
-            gait_type: GaitType::Idle,
-            speed_level: SpeedLevel::LowSpeed,
+            mode: MotorModeHigh::Idle as u8,
+            gait_type: GaitType::Idle as u8,
+            speed_level: SpeedLevel::LowSpeed as u8,
             foot_raise_height: 0.0,
             body_height: 0.0,
             position: [0.0, 0.0],
@@ -49,22 +59,55 @@ impl HighCmd {
             led: Led::new(0, 0, 0),
             wireless_remote: [0; 40],
             reserve: [0; 4],
+            spare: [0; 8],
             crc: None,
             encrypt: false,
         }
     }
 
+    // Idle command with the motors in damping, the fallback for every stop path
+    pub fn damping() -> Self {
+        let mut cmd = Self::new();
+        cmd.set_mode(MotorModeHigh::Damping);
+        cmd
+    }
+
+    // Err carries the byte when it is not a known value
+    pub fn mode(&self) -> Result<MotorModeHigh, u8> {
+        MotorModeHigh::try_from(self.mode)
+    }
+
+    pub fn set_mode(&mut self, mode: MotorModeHigh) {
+        self.mode = mode as u8;
+    }
+
+    pub fn gait_type(&self) -> Result<GaitType, u8> {
+        GaitType::try_from(self.gait_type)
+    }
+
+    pub fn set_gait_type(&mut self, gait_type: GaitType) {
+        self.gait_type = gait_type as u8;
+    }
+
+    pub fn speed_level(&self) -> Result<SpeedLevel, u8> {
+        SpeedLevel::try_from(self.speed_level)
+    }
+
+    pub fn set_speed_level(&mut self, speed_level: SpeedLevel) {
+        self.speed_level = speed_level as u8;
+    }
+
     pub fn build_cmd(&mut self, debug: bool) -> Vec<u8> {
-        let mut cmd = vec![0; 129];
+        let mut cmd = vec![0; HIGH_CMD_LEN];
         cmd[0..2].copy_from_slice(&self.head);
         cmd[2] = self.level_flag;
         cmd[3] = self.frame_reserve;
         cmd[4..12].copy_from_slice(&self.sn);
         cmd[12..20].copy_from_slice(&self.version);
         cmd[20..22].copy_from_slice(&self.band_width);
-        cmd[22] = self.mode as u8; // Directly using enum value as u8
-        cmd[23] = self.gait_type as u8; // Same as above
-        cmd[24] = self.speed_level as u8; // Same as above
+        cmd[22] = self.mode;
+        cmd[23] = self.gait_type;
+        cmd[24] = self.speed_level;
         cmd[25..29].copy_from_slice(&float_to_hex(self.foot_raise_height));
         cmd[29..33].copy_from_slice(&float_to_hex(self.body_height));
         cmd[33..37].copy_from_slice(&float_to_hex(self.position[0]));
@@ -79,14 +122,15 @@ impl HighCmd {
         cmd[69..73].copy_from_slice(&self.led.get_bytes());
         cmd[73..113].copy_from_slice(&self.wireless_remote);
         cmd[113..117].copy_from_slice(&self.reserve);
+        cmd[117..125].copy_from_slice(&self.spare);
 
-        let crc_part = &mut cmd[125..129]; // Last four bytes for CRC
-        let crc_value = if self.encrypt {
-            encrypt_crc(gen_crc(&cmd[..125])) // encrypt_crc and gen_crc should return [u8; 4]
+        let crc = if self.encrypt {
+            encrypt_crc(frame_crc(&cmd))
         } else {
-            gen_crc(&cmd[..125]) // gen_crc should return [u8; 4]
+            frame_crc(&cmd).to_le_bytes()
         };
-        crc_part.copy_from_slice(&crc_value);
+        cmd[125..129].copy_from_slice(&crc);
+        self.crc = Some(crc);
 
         // Debug printing
         if debug {
@@ -95,4 +139,102 @@ impl HighCmd {
 
         cmd
     }
-}
\ No newline at end of file
+
+    // Decode a 129 byte frame, e.g. one sniffed from the official app. Whether the CRC
+    // was encrypted is detected, so build_cmd on the result gives back the same bytes.
+    pub fn from_bytes(data: &[u8]) -> Result<HighCmd, &'static str> {
+        if data.len() != HIGH_CMD_LEN {
+            return Err("Incorrect byte length for HighCmd");
+        }
+        if data[0..2] != [0xFE, 0xEF] || data[2] != 0x00 {
+            return Err("Not a high level command frame");
+        }
+        let crc: [u8; 4] = data[125..129].try_into().unwrap();
+        let encrypt = if crc == frame_crc(data).to_le_bytes() {
+            false
+        } else if crc == encrypt_crc(frame_crc(data)) {
+            true
+        } else {
+            return Err("CRC mismatch");
+        };
+
+        let mut hcmd = HighCmd::new();
+        hcmd.head = [data[0], data[1]];
+        hcmd.level_flag = data[2];
+        hcmd.frame_reserve = data[3];
+        hcmd.sn.copy_from_slice(&data[4..12]);
+        hcmd.version.copy_from_slice(&data[12..20]);
+        hcmd.band_width.copy_from_slice(&data[20..22]);
+        hcmd.mode = data[22];
+        hcmd.gait_type = data[23];
+        hcmd.speed_level = data[24];
+        hcmd.foot_raise_height = hex_to_float(&data[25..29]);
+        hcmd.body_height = hex_to_float(&data[29..33]);
+        hcmd.position = [hex_to_float(&data[33..37]), hex_to_float(&data[37..41])];
+        hcmd.euler = [hex_to_float(&data[41..45]), hex_to_float(&data[45..49]), hex_to_float(&data[49..53])];
+        hcmd.velocity = [hex_to_float(&data[53..57]), hex_to_float(&data[57..61])];
+        hcmd.yaw_speed = hex_to_float(&data[61..65]);
+        hcmd.bms = BmsCmd::from_bytes(&data[65..69]);
+        hcmd.led = Led::from_bytes(&data[69..73]);
+        hcmd.wireless_remote.copy_from_slice(&data[73..113]);
+        hcmd.reserve.copy_from_slice(&data[113..117]);
+        hcmd.spare.copy_from_slice(&data[117..125]);
+        hcmd.crc = Some(crc);
+        hcmd.encrypt = encrypt;
+        Ok(hcmd)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ucl::impairment::SplitMix64;
+
+    // Random content with a valid header and CRC
+    fn random_frame(seed: u64, encrypt: bool) -> Vec<u8> {
+        let mut rng = SplitMix64::new(seed);
+        let mut frame: Vec<u8> = (0..HIGH_CMD_LEN).map(|_| rng.next_u64() as u8).collect();
+        frame[0..3].copy_from_slice(&[0xFE, 0xEF, 0x00]);
+        let crc = if encrypt { encrypt_crc(frame_crc(&frame)) } else { frame_crc(&frame).to_le_bytes() };
+        frame[125..129].copy_from_slice(&crc);
+        frame
+    }
+
+    #[test]
+    fn from_bytes_then_build_cmd_gives_the_same_frame() {
+        for seed in 0..20 {
+            let frame = random_frame(seed, seed % 2 == 0);
+            let mut hcmd = HighCmd::from_bytes(&frame).unwrap();
+            assert_eq!(hcmd.build_cmd(false), frame, "seed {}", seed);
+        }
+    }
+
+    #[test]
+    fn unknown_mode_gait_and_speed_survive() {
+        let mut hcmd = HighCmd::new();
+        hcmd.mode = 42;
+        hcmd.gait_type = 7;
+        hcmd.speed_level = 3;
+        let frame = hcmd.build_cmd(false);
+
+        let decoded = HighCmd::from_bytes(&frame).unwrap();
+        assert_eq!((decoded.mode(), decoded.gait_type(), decoded.speed_level()), (Err(42), Err(7), Err(3)));
+        assert_eq!(frame[22..25], [42, 7, 3]);
+
+        hcmd.set_mode(MotorModeHigh::VelWalk);
+        hcmd.set_gait_type(GaitType::ClimbStair);
+        hcmd.set_speed_level(SpeedLevel::HighSpeed);
+        assert_eq!(hcmd.build_cmd(false)[22..25], [2, 3, 2]);
+        assert_eq!(hcmd.mode(), Ok(MotorModeHigh::VelWalk));
+    }
+
+    #[test]
+    fn from_bytes_rejects_bad_frames() {
+        let mut frame = random_frame(1, false);
+        assert_eq!(HighCmd::from_bytes(&frame[..128]).err(), Some("Incorrect byte length for HighCmd"));
+        frame[50] ^= 0x10;
+        assert_eq!(HighCmd::from_bytes(&frame).err(), Some("CRC mismatch"));
+        frame[2] = 0xff;
+        assert_eq!(HighCmd::from_bytes(&frame).err(), Some("Not a high level command frame"));
+    }
+}
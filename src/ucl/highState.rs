@@ -1,57 +1,72 @@
-use super::enums::{MotorModeHigh, GaitType, SpeedLevel};
-use super::common::{float_to_hex, hex_to_float, encrypt_crc, gen_crc, byte_print};
-use super::complex::{Cartesian, Led, BmsState, Imu, MotorState};
+use super::enums::{MotorModeHigh, GaitType};
+use super::common::{float_to_hex, hex_to_float, frame_crc};
+use super::complex::{Cartesian, BmsState, Imu, MotorState};
+
+const HIGH_STATE_LEN: usize = 1087;
+// MotorState as the SDK packs it into the high level frame: mode u8, q, dq, ddq, tauEst, q_raw,
+// dq_raw, ddq_raw f32, temperature i8, reserve u32[2] = 1 + 28 + 1 + 8 = 38 bytes. The offsets
+// after it only add up with 38: 22 header + 53 IMU = 75, 75 + 20 * 38 = 835 where the BMS starts
+// (parse_data read the BMS there even when it stepped through the motors by 32), 835 + 34 BMS +
+// 16 foot forces = 885 for mode, and on to wirelessRemote at 1039 and the CRC at 1083 of 1087.
+// With 32 the motors would end at 715 and leave 120 bytes nothing accounts for.
+const MOTOR_STATE_LEN: usize = 38;
 
 #[derive(Debug, Clone)]
 pub struct HighState {
-    head: [u8; 2],
-    level_flag: u8,
-    frame_reserve: u8,
-    sn: [u8; 8],
-    version: [u8; 8],
-    band_width: [u8; 4],
-    imu: Imu,
-    motor_state: Vec<MotorState>,
-    bms: BmsState,
-    foot_force: Vec<u16>,
-    foot_force_est: Vec<u16>,
-    mode: MotorModeHigh,
-    progress: f32,
-    gait_type: GaitType,
-    foot_raise_height: f32,
-    position: [f32; 2],
-    body_height: f32,
-    velocity: [f32; 3],
-    yaw_speed: f32,
-    range_obstacle: [f32; 4],
-    foot_position_to_body: Vec<Cartesian>,
-    foot_speed_to_body: Vec<Cartesian>,
-    wireless_remote: [u8; 40],
-    reserve: [u8; 4],
-    crc: [u8; 4],
+    pub head: [u8; 2],
+    pub level_flag: u8,
+    pub frame_reserve: u8,
+    pub sn: [u8; 8],
+    pub version: [u8; 8],
+    pub band_width: [u8; 2],
+    pub imu: Imu,
+    pub motor_state: Vec<MotorState>,
+    pub bms: BmsState,
+    pub foot_force: [u16; 4],
+    pub foot_force_est: [u16; 4],
+    pub mode: u8, // raw, see mode()
+    pub progress: f32,
+    pub gait_type: u8, // raw, see gait_type()
+    pub foot_raise_height: f32,
+    pub position: [f32; 3],
+    pub body_height: f32,
+    pub velocity: [f32; 3],
+    pub yaw_speed: f32,
+    pub range_obstacle: [f32; 4],
+    pub foot_position_to_body: Vec<Cartesian>,
+    pub foot_speed_to_body: Vec<Cartesian>,
+    pub wireless_remote: [u8; 40],
+    pub reserve: [u8; 4],
+    pub crc: [u8; 4],
+}
+
+impl Default for HighState {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl HighState {
     pub fn new() -> Self {
         HighState {
-            head: [0; 2],
+            head: [0xFE, 0xEF],
             level_flag: 0,
             frame_reserve: 0,
             sn: [0; 8],
             version: [0; 8],
-            band_width: [0; 4],
+            band_width: [0; 2],
             imu: Imu::default(),
-            motor_state: vec![MotorState::default(); 20],
-            bms: BmsState::default(),
-            foot_force: vec![0; 4],
-            foot_force_est: vec![0; 4],
-            mode: MotorModeHigh::Idle,
+            motor_state: vec![MotorState { reserve: vec![0; 8], ..MotorState::default() }; 20],
+            bms: BmsState { cell_vol: vec![0; 10], ..BmsState::default() },
+            foot_force: [0; 4],
+            foot_force_est: [0; 4],
+            mode: MotorModeHigh::Idle as u8,
             progress: 0.0,
-            gait_type: GaitType::Idle,
+            gait_type: GaitType::Idle as u8,
             foot_raise_height: 0.0,
-            position: [0.0, 0.0],
+            position: [0.0; 3],
             body_height: 0.0,
-            velocity: [0.0, 0.0, 0.0],
+            velocity: [0.0; 3],
             yaw_speed: 0.0,
             range_obstacle: [0.0; 4],
             foot_position_to_body: vec![Cartesian::default(); 4],
@@ -61,134 +76,292 @@ impl HighState {
             crc: [0; 4],
         }
     }
-        // Convert data slice to BmsState
-        pub fn data_to_bms_state(&self, data: &[u8]) -> BmsState {
-            let version_h = data[0];
-            let version_l = data[1];
-            let bms_status = data[2];
-            let soc = data[3];
-            let current = i32::from_le_bytes([data[4], data[5], data[6], data[7]]);
-            let cycle = u16::from_le_bytes([data[8], data[9]]);
-            let bq_ntc = [data[10], data[11]];
-            let mcu_ntc = [data[12], data[13]];
-            let mut cell_vol = vec![];
-            for i in (14..34).step_by(2) {
-                cell_vol.push(u16::from_le_bytes([data[i], data[i + 1]]));
-            }
-            BmsState::new(version_h, version_l, bms_status, soc, current, cycle, bq_ntc, mcu_ntc, cell_vol)
+
+    // Err carries the byte when a newer firmware reports a mode this build does not know
+    pub fn mode(&self) -> Result<MotorModeHigh, u8> {
+        MotorModeHigh::try_from(self.mode)
+    }
+
+    pub fn gait_type(&self) -> Result<GaitType, u8> {
+        GaitType::try_from(self.gait_type)
+    }
+
+    // Convert data slice to BmsState
+    pub fn data_to_bms_state(&self, data: &[u8]) -> BmsState {
+        let version_h = data[0];
+        let version_l = data[1];
+        let bms_status = data[2];
+        let soc = data[3];
+        let current = i32::from_le_bytes([data[4], data[5], data[6], data[7]]);
+        let cycle = u16::from_le_bytes([data[8], data[9]]);
+        let bq_ntc = [data[10], data[11]];
+        let mcu_ntc = [data[12], data[13]];
+        let mut cell_vol = vec![];
+        for i in (14..34).step_by(2) {
+            cell_vol.push(u16::from_le_bytes([data[i], data[i + 1]]));
+        }
+        BmsState::new(version_h, version_l, bms_status, soc, current, cycle, bq_ntc, mcu_ntc, cell_vol)
+    }
+
+    // Convert data slice to Imu
+    pub fn data_to_imu(&self, data: &[u8]) -> Imu {
+        let quaternion = [
+            hex_to_float(&data[0..4]),
+            hex_to_float(&data[4..8]),
+            hex_to_float(&data[8..12]),
+            hex_to_float(&data[12..16]),
+        ];
+        let gyroscope = [
+            hex_to_float(&data[16..20]),
+            hex_to_float(&data[20..24]),
+            hex_to_float(&data[24..28]),
+        ];
+        let accelerometer = [
+            hex_to_float(&data[28..32]),
+            hex_to_float(&data[32..36]),
+            hex_to_float(&data[36..40]),
+        ];
+        let rpy = [
+            hex_to_float(&data[40..44]),
+            hex_to_float(&data[44..48]),
+            hex_to_float(&data[48..52]),
+        ];
+        let temperature = data[52] as f32; // Assuming temperature is just a byte to f32
+        Imu::new(quaternion, gyroscope, accelerometer, rpy, temperature)
+    }
+
+    // Convert data slice to MotorState
+    pub fn data_to_motor_state(&self, data: &[u8]) -> MotorState {
+        let mode = data[0];
+        let q = hex_to_float(&data[1..5]);
+        let dq = hex_to_float(&data[5..9]);
+        let ddq = hex_to_float(&data[9..13]);
+        let tau_est = hex_to_float(&data[13..17]);
+        let q_raw = hex_to_float(&data[17..21]);
+        let dq_raw = hex_to_float(&data[21..25]);
+        let ddq_raw = hex_to_float(&data[25..29]);
+        let temperature = data[29] as f32; // Assuming temperature is just a byte to f32
+        MotorState::new(mode, q, dq, ddq, tau_est, q_raw, dq_raw, ddq_raw, temperature, &data[30..38])
+    }
+
+    // Parse a byte array to fill the HighState struct's fields
+    pub fn parse_data(&mut self, data: &[u8]) {
+        self.head = [data[0], data[1]];
+        self.level_flag = data[2];
+        self.frame_reserve = data[3];
+        self.sn.copy_from_slice(&data[4..12]);
+        self.version.copy_from_slice(&data[12..20]);
+        self.band_width.copy_from_slice(&data[20..22]);
+        self.imu = self.data_to_imu(&data[22..75]);
+        self.motor_state.clear();
+        for i in 0..20 {
+            let start = 75 + i * MOTOR_STATE_LEN;
+            self.motor_state.push(self.data_to_motor_state(&data[start..start + MOTOR_STATE_LEN]));
+        }
+        self.bms = self.data_to_bms_state(&data[835..869]);
+
+        for i in 0..4 {
+            self.foot_force[i] = u16::from_le_bytes([data[869 + i * 2], data[870 + i * 2]]);
+            self.foot_force_est[i] = u16::from_le_bytes([data[877 + i * 2], data[878 + i * 2]]);
+        }
+
+        self.mode = data[885];
+        self.progress = hex_to_float(&data[886..890]);
+        self.gait_type = data[890];
+        self.foot_raise_height = hex_to_float(&data[891..895]);
+        for i in 0..3 {
+            self.position[i] = hex_to_float(&data[895 + i * 4..899 + i * 4]);
+            self.velocity[i] = hex_to_float(&data[911 + i * 4..915 + i * 4]);
+        }
+        self.body_height = hex_to_float(&data[907..911]);
+        self.yaw_speed = hex_to_float(&data[923..927]);
+        for i in 0..4 {
+            self.range_obstacle[i] = hex_to_float(&data[927 + i * 4..931 + i * 4]);
+        }
+
+        self.foot_position_to_body = (0..4).map(|i| {
+            Cartesian::new(
+                hex_to_float(&data[(i * 12) + 943..(i * 12) + 947]),
+                hex_to_float(&data[(i * 12) + 947..(i * 12) + 951]),
+                hex_to_float(&data[(i * 12) + 951..(i * 12) + 955]),
+            )
+        }).collect();
+
+        self.foot_speed_to_body = (0..4).map(|i| {
+            Cartesian::new(
+                hex_to_float(&data[(i * 12) + 991..(i * 12) + 995]),
+                hex_to_float(&data[(i * 12) + 995..(i * 12) + 999]),
+                hex_to_float(&data[(i * 12) + 999..(i * 12) + 1003]),
+            )
+        }).collect();
+
+        self.wireless_remote.copy_from_slice(&data[1039..1079]);
+        self.reserve.copy_from_slice(&data[1079..1083]);
+        self.crc.copy_from_slice(&data[1083..1087]);
+    }
+
+    // Encode back into the 1087 byte frame, the inverse of parse_data. The stored CRC is
+    // written as is, use build_state for a frame with a fresh one.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut data = vec![0; HIGH_STATE_LEN];
+        data[0..2].copy_from_slice(&self.head);
+        data[2] = self.level_flag;
+        data[3] = self.frame_reserve;
+        data[4..12].copy_from_slice(&self.sn);
+        data[12..20].copy_from_slice(&self.version);
+        data[20..22].copy_from_slice(&self.band_width);
+        write_imu(&mut data[22..75], &self.imu);
+        for (i, motor) in self.motor_state.iter().take(20).enumerate() {
+            let start = 75 + i * MOTOR_STATE_LEN;
+            write_motor_state(&mut data[start..start + MOTOR_STATE_LEN], motor);
+        }
+        write_bms_state(&mut data[835..869], &self.bms);
+
+        for i in 0..4 {
+            data[869 + i * 2..871 + i * 2].copy_from_slice(&self.foot_force[i].to_le_bytes());
+            data[877 + i * 2..879 + i * 2].copy_from_slice(&self.foot_force_est[i].to_le_bytes());
+        }
+
+        data[885] = self.mode;
+        data[886..890].copy_from_slice(&float_to_hex(self.progress));
+        data[890] = self.gait_type;
+        data[891..895].copy_from_slice(&float_to_hex(self.foot_raise_height));
+        for i in 0..3 {
+            data[895 + i * 4..899 + i * 4].copy_from_slice(&float_to_hex(self.position[i]));
+            data[911 + i * 4..915 + i * 4].copy_from_slice(&float_to_hex(self.velocity[i]));
+        }
+        data[907..911].copy_from_slice(&float_to_hex(self.body_height));
+        data[923..927].copy_from_slice(&float_to_hex(self.yaw_speed));
+        for i in 0..4 {
+            data[927 + i * 4..931 + i * 4].copy_from_slice(&float_to_hex(self.range_obstacle[i]));
+        }
+
+        for (i, foot) in self.foot_position_to_body.iter().take(4).enumerate() {
+            write_cartesian(&mut data[943 + i * 12..955 + i * 12], foot);
+        }
+        for (i, foot) in self.foot_speed_to_body.iter().take(4).enumerate() {
+            write_cartesian(&mut data[991 + i * 12..1003 + i * 12], foot);
+        }
+
+        data[1039..1079].copy_from_slice(&self.wireless_remote);
+        data[1079..1083].copy_from_slice(&self.reserve);
+        data[1083..1087].copy_from_slice(&self.crc);
+        data
+    }
+
+    // Like the dog sends it: recompute the CRC, store it and encode
+    pub fn build_state(&mut self) -> Vec<u8> {
+        let mut data = self.to_bytes();
+        self.crc = frame_crc(&data).to_le_bytes();
+        data[1083..1087].copy_from_slice(&self.crc);
+        data
+    }
+}
+
+fn write_imu(data: &mut [u8], imu: &Imu) {
+    let floats = imu.quaternion.iter().chain(&imu.gyroscope).chain(&imu.accelerometer).chain(&imu.rpy);
+    for (i, value) in floats.enumerate() {
+        data[i * 4..i * 4 + 4].copy_from_slice(&float_to_hex(*value));
+    }
+    data[52] = imu.temperature as u8;
+}
+
+fn write_motor_state(data: &mut [u8], motor: &MotorState) {
+    data[0] = motor.mode;
+    let floats = [motor.q, motor.dq, motor.ddq, motor.tau_est, motor.q_raw, motor.dq_raw, motor.ddq_raw];
+    for (i, value) in floats.iter().enumerate() {
+        data[1 + i * 4..5 + i * 4].copy_from_slice(&float_to_hex(*value));
+    }
+    data[29] = motor.temperature as u8;
+    let reserve = motor.reserve.len().min(8);
+    data[30..30 + reserve].copy_from_slice(&motor.reserve[..reserve]);
+}
+
+fn write_bms_state(data: &mut [u8], bms: &BmsState) {
+    data[0] = bms.version_h;
+    data[1] = bms.version_l;
+    data[2] = bms.bms_status;
+    data[3] = bms.soc;
+    data[4..8].copy_from_slice(&bms.current.to_le_bytes());
+    data[8..10].copy_from_slice(&bms.cycle.to_le_bytes());
+    data[10..12].copy_from_slice(&bms.bq_ntc);
+    data[12..14].copy_from_slice(&bms.mcu_ntc);
+    for (i, cell) in bms.cell_vol.iter().take(10).enumerate() {
+        data[14 + i * 2..16 + i * 2].copy_from_slice(&cell.to_le_bytes());
+    }
+}
+
+fn write_cartesian(data: &mut [u8], point: &Cartesian) {
+    data[0..4].copy_from_slice(&float_to_hex(point.x));
+    data[4..8].copy_from_slice(&float_to_hex(point.y));
+    data[8..12].copy_from_slice(&float_to_hex(point.z));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ucl::impairment::SplitMix64;
+
+    #[test]
+    fn parse_data_then_to_bytes_gives_the_same_frame() {
+        // Every byte is a field, random content has to come back unchanged
+        for seed in 0..20 {
+            let mut rng = SplitMix64::new(seed);
+            let frame: Vec<u8> = (0..HIGH_STATE_LEN).map(|_| rng.next_u64() as u8).collect();
+            let mut hstate = HighState::new();
+            hstate.parse_data(&frame);
+            assert_eq!(hstate.to_bytes(), frame, "seed {}", seed);
         }
-    
-        // Convert data slice to Imu
-        pub fn data_to_imu(&self, data: &[u8]) -> Imu {
-            let quaternion = [
-                hex_to_float(&data[0..4]),
-                hex_to_float(&data[4..8]),
-                hex_to_float(&data[8..12]),
-                hex_to_float(&data[12..16]),
-            ];
-            let gyroscope = [
-                hex_to_float(&data[16..20]),
-                hex_to_float(&data[20..24]),
-                hex_to_float(&data[24..28]),
-            ];
-            let accelerometer = [
-                hex_to_float(&data[28..32]),
-                hex_to_float(&data[32..36]),
-                hex_to_float(&data[36..40]),
-            ];
-            let rpy = [
-                hex_to_float(&data[40..44]),
-                hex_to_float(&data[44..48]),
-                hex_to_float(&data[48..52]),
-            ];
-            let temperature = data[52] as f32; // Assuming temperature is just a byte to f32
-            Imu::new(quaternion, gyroscope, accelerometer, rpy, temperature)
+    }
+
+    #[test]
+    fn unknown_mode_and_gait_survive() {
+        let mut hstate = HighState::new();
+        hstate.mode = 0x20;
+        hstate.gait_type = 9;
+        hstate.velocity = [0.25, -0.5, 0.0];
+        let frame = hstate.build_state();
+        assert_eq!(frame[1083..1087], frame_crc(&frame).to_le_bytes());
+
+        let mut parsed = HighState::new();
+        parsed.parse_data(&frame);
+        assert_eq!((parsed.mode(), parsed.gait_type()), (Err(0x20), Err(9)));
+        assert_eq!(parsed.velocity, [0.25, -0.5, 0.0]);
+        assert_eq!(parsed.to_bytes(), frame);
+    }
+
+    // Laid out field by field at the SDK offsets, not through to_bytes, so a wrong stride or
+    // offset in parse_data and to_bytes can not cancel out
+    #[test]
+    fn fields_sit_at_the_sdk_offsets() {
+        let mut frame = vec![0u8; HIGH_STATE_LEN];
+        frame[..3].copy_from_slice(&[0xfe, 0xef, 0xee]);
+        for motor in 0..20 {
+            let at = 75 + motor * 38;
+            frame[at] = 10;
+            frame[at + 1..at + 5].copy_from_slice(&(motor as f32 * 0.1).to_le_bytes());
+            frame[at + 29] = 30 + motor as u8;
         }
-    
-        // Convert data slice to MotorState
-        pub fn data_to_motor_state(&self, data: &[u8]) -> MotorState {
-            let mode = MotorModeHigh::from(data[0]); // Needs a conversion method from u8 to enum
-            let q = hex_to_float(&data[1..5]);
-            let dq = hex_to_float(&data[5..9]);
-            let ddq = hex_to_float(&data[9..13]);
-            let tau_est = hex_to_float(&data[13..17]);
-            let q_raw = hex_to_float(&data[17..21]);
-            let dq_raw = hex_to_float(&data[21..25]);
-            let ddq_raw = hex_to_float(&data[25..29]);
-            let temperature = data[29] as f32; // Assuming temperature is just a byte to f32
-            let reserve = [data[30], data[31]];
-            MotorState::new(mode, q, dq, ddq, tau_est, q_raw, dq_raw, ddq_raw, temperature, reserve)
+        frame[835 + 3] = 87; // BMS SOC
+        frame[869..871].copy_from_slice(&120u16.to_le_bytes()); // foot force FR
+        frame[885] = MotorModeHigh::VelWalk as u8;
+        frame[890] = GaitType::Trot as u8;
+        frame[911..915].copy_from_slice(&0.4f32.to_le_bytes()); // velocity x
+        frame[923..927].copy_from_slice(&(-0.3f32).to_le_bytes()); // yaw speed
+        frame[1039 + 2] = 0x02; // R2 held
+        let crc = frame_crc(&frame);
+        frame[1083..].copy_from_slice(&crc.to_le_bytes());
+
+        let mut hstate = HighState::new();
+        hstate.parse_data(&frame);
+        for (motor, state) in hstate.motor_state.iter().enumerate() {
+            assert_eq!((state.mode, state.q, state.temperature), (10, motor as f32 * 0.1, 30.0 + motor as f32), "motor {}", motor);
         }
-    
-        // Parse a byte array to fill the HighState struct's fields
-        pub fn parse_data(&mut self, data: &[u8]) {
-            self.head = [data[0], data[1]];
-            self.level_flag = data[2];
-            self.frame_reserve = data[3];
-            self.sn.copy_from_slice(&data[4..12]);
-            self.version.copy_from_slice(&data[12..20]);
-            self.band_width.copy_from_slice(&data[20..24]);
-            self.imu = self.data_to_imu(&data[22..75]);
-            self.motor_state.clear();
-            for i in 0..20 {
-                self.motor_state.push(self.data_to_motor_state(&data[75 + i * 32..107 + i * 32]));
-            }
-            self.bms = self.data_to_bms_state(&data[835..869]);
-
-            self.foot_force = [
-                u16::from_le_bytes([data[869], data[870]]),
-                u16::from_le_bytes([data[871], data[872]]),
-                u16::from_le_bytes([data[873], data[874]]),
-                u16::from_le_bytes([data[875], data[876]]),
-            ];
-
-            self.foot_force_est = [
-                u16::from_le_bytes([data[877], data[878]]),
-                u16::from_le_bytes([data[879], data[880]]),
-                u16::from_le_bytes([data[881], data[882]]),
-                u16::from_le_bytes([data[883], data[884]]),
-            ];
-
-            self.mode = MotorModeHigh::from(data[885]); // Requires appropriate conversion from u8 to MotorModeHigh
-            self.progress = hex_to_float(&data[886..890]);
-            self.gait_type = GaitType::from(data[890]); // Requires appropriate conversion from u8 to GaitType
-            self.foot_raise_height = hex_to_float(&data[891..895]);
-            self.position = [
-                hex_to_float(&data[895..899]),
-                hex_to_float(&data[899..903]),
-            ];
-            self.body_height = hex_to_float(&data[907..911]);
-            self.velocity = [
-                hex_to_float(&data[911..915]),
-                hex_to_float(&data[915..919]),
-            ];
-            self.yaw_speed = hex_to_float(&data[923..927]);
-            
-            self.range_obstacle = [
-                hex_to_float(&data[927..931]),
-                hex_to_float(&data[931..935]),
-                hex_to_float(&data[935..939]),
-                hex_to_float(&data[939..943]),
-            ];
-
-            self.foot_position_2_body = (0..4).map(|i| {
-                Cartesian::new(
-                    hex_to_float(&data[(i * 12) + 943..(i * 12) + 947]),
-                    hex_to_float(&data[(i * 12) + 947..(i * 12) + 951]),
-                    hex_to_float(&data[(i * 12) + 951..(i * 12) + 955]),
-                )
-            }).collect();
-
-            self.foot_speed_2_body = (0..4).map(|i| {
-                Cartesian::new(
-                    hex_to_float(&data[(i * 12) + 991..(i * 12) + 995]),
-                    hex_to_float(&data[(i * 12) + 995..(i * 12) + 999]),
-                    hex_to_float(&data[(i * 12) + 999..(i * 12) + 1003]),
-                )
-            }).collect();
-
-            self.wireless_remote.copy_from_slice(&data[1039..1079]);
-            self.reserve.copy_from_slice(&data[1079..1083]);
-            self.crc = Some([data[1083], data[1084], data[1085], data[1086]]);
-            }
-    }
\ No newline at end of file
+        assert_eq!(hstate.bms.soc, 87);
+        assert_eq!(hstate.foot_force[0], 120);
+        assert_eq!((hstate.mode(), hstate.gait_type()), (Ok(MotorModeHigh::VelWalk), Ok(GaitType::Trot)));
+        assert_eq!((hstate.velocity[0], hstate.yaw_speed), (0.4, -0.3));
+        assert_eq!(hstate.wireless_remote[2], 0x02);
+        assert_eq!(hstate.build_state(), frame);
+    }
+}
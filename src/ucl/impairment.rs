@@ -0,0 +1,379 @@
+use std::cmp::Reverse;
+use std::collections::BinaryHeap;
+use std::io;
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+use super::transport::Transport;
+use super::unitreeConnection::UnitreeConnection;
+
+// Impairments for one direction. Probabilities are 0.0..=1.0.
+#[derive(Debug, Clone, Default)]
+pub struct ImpairmentConfig {
+    pub latency: Duration,
+    pub jitter: Duration,   // uniform extra delay on top of latency
+    pub loss: f64,
+    pub duplicate: f64,
+    pub reorder: f64,       // held back by `reorder_delay` so later packets overtake it
+    pub reorder_delay: Duration,
+    pub corrupt: f64,       // one random bit flipped
+}
+
+impl ImpairmentConfig {
+    // Roughly what we see on the dog's hotspot a few meters away
+    pub fn wifi_hotspot() -> Self {
+        ImpairmentConfig {
+            latency: Duration::from_millis(3),
+            jitter: Duration::from_millis(4),
+            loss: 0.02,
+            duplicate: 0.002,
+            reorder: 0.01,
+            reorder_delay: Duration::from_millis(6),
+            corrupt: 0.0005,
+        }
+    }
+
+    // Bursty, e.g. at the edge of the hotspot's range
+    pub fn wifi_bad() -> Self {
+        ImpairmentConfig {
+            latency: Duration::from_millis(10),
+            jitter: Duration::from_millis(30),
+            loss: 0.15,
+            duplicate: 0.01,
+            reorder: 0.05,
+            reorder_delay: Duration::from_millis(20),
+            corrupt: 0.002,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ImpairmentStats {
+    pub packets: u64,
+    pub dropped: u64,
+    pub duplicated: u64,
+    pub reordered: u64,
+    pub corrupted: u64,
+}
+
+// SplitMix64, small and good enough to pick which packets get hit. Same seed, same decisions.
+#[derive(Debug, Clone)]
+pub struct SplitMix64 {
+    state: u64,
+}
+
+impl SplitMix64 {
+    pub fn new(seed: u64) -> Self {
+        SplitMix64 { state: seed }
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        self.state = self.state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+
+    // Uniform in [0, 1)
+    pub fn next_f64(&mut self) -> f64 {
+        (self.next_u64() >> 11) as f64 / (1u64 << 53) as f64
+    }
+
+    fn chance(&mut self, probability: f64) -> bool {
+        probability > 0.0 && self.next_f64() < probability
+    }
+
+    fn up_to(&mut self, max: Duration) -> Duration {
+        max.mul_f64(self.next_f64())
+    }
+}
+
+// Packets waiting for their release time, ordered by (due, sequence) so equal times keep their order
+type DelayQueue = BinaryHeap<Reverse<(Instant, u64, Vec<u8>)>>;
+
+// Decides the fate of the packets of one direction
+struct Channel {
+    config: ImpairmentConfig,
+    rng: SplitMix64,
+    stats: ImpairmentStats,
+    next_seq: u64,
+}
+
+impl Channel {
+    fn new(config: ImpairmentConfig, seed: u64) -> Self {
+        Channel { config, rng: SplitMix64::new(seed), stats: ImpairmentStats::default(), next_seq: 0 }
+    }
+
+    // Queue `packet`, seen at `at`, with zero, one or two copies
+    fn push(&mut self, queue: &mut DelayQueue, at: Instant, packet: &[u8]) {
+        self.stats.packets += 1;
+        if self.rng.chance(self.config.loss) {
+            self.stats.dropped += 1;
+            return;
+        }
+        let copies = if self.rng.chance(self.config.duplicate) {
+            self.stats.duplicated += 1;
+            2
+        } else {
+            1
+        };
+        for _ in 0..copies {
+            let mut data = packet.to_vec();
+            if !data.is_empty() && self.rng.chance(self.config.corrupt) {
+                self.stats.corrupted += 1;
+                let bit = (self.rng.next_u64() % (data.len() as u64 * 8)) as usize;
+                data[bit / 8] ^= 1 << (bit % 8);
+            }
+            let mut delay = self.config.latency + self.rng.up_to(self.config.jitter);
+            if self.rng.chance(self.config.reorder) {
+                self.stats.reordered += 1;
+                delay += self.config.reorder_delay;
+            }
+            queue.push(Reverse((at + delay, self.next_seq, data)));
+            self.next_seq += 1;
+        }
+    }
+}
+
+// Pop everything due by `now`, in release order
+fn take_due(queue: &mut DelayQueue, now: Instant) -> Vec<(Instant, Vec<u8>)> {
+    let mut due = Vec::new();
+    while let Some(Reverse((at, _, _))) = queue.peek() {
+        if *at > now {
+            break;
+        }
+        let Reverse((at, _, data)) = queue.pop().unwrap();
+        due.push((at, data));
+    }
+    due
+}
+
+struct TxState {
+    channel: Channel,
+    queue: DelayQueue,
+    running: bool,
+}
+
+struct RxState {
+    channel: Channel,
+    queue: DelayQueue,
+    last_recv: Option<Instant>,
+}
+
+// Transport with configurable latency, jitter, loss, duplication, reordering and
+// corruption on both the command (tx) and the state (rx) path.
+pub struct ImpairedConnection<T: Transport + Send + Sync + 'static = UnitreeConnection> {
+    conn: Arc<T>,
+    tx: Arc<(Mutex<TxState>, Condvar)>,
+    rx: Mutex<RxState>,
+}
+
+impl<T: Transport + Send + Sync + 'static> ImpairedConnection<T> {
+    pub fn new(conn: T, tx: ImpairmentConfig, rx: ImpairmentConfig, seed: u64) -> Self {
+        let mut seeds = SplitMix64::new(seed);
+        let conn = Arc::new(conn);
+        let tx_state = Arc::new((
+            Mutex::new(TxState { channel: Channel::new(tx, seeds.next_u64()), queue: BinaryHeap::new(), running: true }),
+            Condvar::new(),
+        ));
+
+        // Delayed commands go out from their own thread so they leave on time even if nobody calls us
+        let sender = Arc::clone(&conn);
+        let shared = Arc::clone(&tx_state);
+        thread::spawn(move || {
+            let (lock, wakeup) = &*shared;
+            let mut state = lock.lock().unwrap();
+            while state.running {
+                for (_, data) in take_due(&mut state.queue, Instant::now()) {
+                    sender.send(&data);
+                }
+                state = match state.queue.peek().map(|Reverse((at, _, _))| *at) {
+                    Some(at) => wakeup.wait_timeout(state, at.saturating_duration_since(Instant::now())).unwrap().0,
+                    None => wakeup.wait(state).unwrap(),
+                };
+            }
+        });
+
+        ImpairedConnection {
+            conn,
+            tx: tx_state,
+            rx: Mutex::new(RxState { channel: Channel::new(rx, seeds.next_u64()), queue: BinaryHeap::new(), last_recv: None }),
+        }
+    }
+
+    // Same impairments both ways
+    pub fn symmetric(conn: T, config: ImpairmentConfig, seed: u64) -> Self {
+        Self::new(conn, config.clone(), config, seed)
+    }
+
+    pub fn start_recv(&self) {
+        self.conn.start_recv();
+    }
+
+    pub fn send(&self, cmd: &[u8]) {
+        let (lock, wakeup) = &*self.tx;
+        let mut state = lock.lock().unwrap();
+        let TxState { channel, queue, .. } = &mut *state;
+        channel.push(queue, Instant::now(), cmd);
+        wakeup.notify_one();
+    }
+
+    pub fn get_data(&self) -> Vec<Vec<u8>> {
+        self.get_data_timestamped().into_iter().map(|(_, packet)| packet).collect()
+    }
+
+    // Timestamps are the (delayed) times the datagrams were released to us
+    pub fn get_data_timestamped(&self) -> Vec<(Instant, Vec<u8>)> {
+        let arrived = self.conn.get_data_timestamped();
+        let mut state = self.rx.lock().unwrap();
+        let RxState { channel, queue, .. } = &mut *state;
+        for (at, packet) in arrived.iter() {
+            channel.push(queue, *at, packet);
+        }
+        let due = take_due(queue, Instant::now());
+        if let Some((at, _)) = due.last() {
+            state.last_recv = Some(*at);
+        }
+        due
+    }
+
+    pub fn last_received(&self) -> Option<Instant> {
+        self.rx.lock().unwrap().last_recv
+    }
+
+    pub fn tx_stats(&self) -> ImpairmentStats {
+        self.tx.0.lock().unwrap().channel.stats
+    }
+
+    pub fn rx_stats(&self) -> ImpairmentStats {
+        self.rx.lock().unwrap().channel.stats
+    }
+
+    pub fn inner(&self) -> &T {
+        &self.conn
+    }
+}
+
+impl<T: Transport + Send + Sync + 'static> Drop for ImpairedConnection<T> {
+    fn drop(&mut self) {
+        let (lock, wakeup) = &*self.tx;
+        lock.lock().unwrap().running = false;
+        wakeup.notify_one();
+    }
+}
+
+impl<T: Transport + Send + Sync + 'static> Transport for ImpairedConnection<T> {
+    fn start_recv(&self) {
+        ImpairedConnection::start_recv(self)
+    }
+
+    fn send(&self, data: &[u8]) {
+        ImpairedConnection::send(self, data)
+    }
+
+    fn get_data_timestamped(&self) -> Vec<(Instant, Vec<u8>)> {
+        ImpairedConnection::get_data_timestamped(self)
+    }
+
+    fn last_received(&self) -> Option<Instant> {
+        ImpairedConnection::last_received(self)
+    }
+
+    // Frames still queued for the link go to the halted inner transport and are dropped there
+    fn halt(&self) -> bool {
+        self.conn.halt()
+    }
+
+    // Straight to the link, an e-stop frame is not delayed or lost on purpose
+    fn send_halted(&self, data: &[u8]) -> io::Result<()> {
+        self.conn.send_halted(data)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ucl::transport::channel_pair;
+
+    fn harsh() -> ImpairmentConfig {
+        ImpairmentConfig {
+            latency: Duration::from_millis(1),
+            jitter: Duration::from_millis(2),
+            loss: 0.2,
+            duplicate: 0.2,
+            reorder: 0.2,
+            reorder_delay: Duration::from_millis(5),
+            corrupt: 0.2,
+        }
+    }
+
+    fn packet(n: u16) -> Vec<u8> {
+        n.to_le_bytes().repeat(8)
+    }
+
+    // Release order and content of 200 packets pushed at the same instant
+    fn decisions(seed: u64) -> (Vec<Vec<u8>>, ImpairmentStats) {
+        let mut channel = Channel::new(harsh(), seed);
+        let mut queue = DelayQueue::new();
+        let at = Instant::now();
+        for n in 0..200 {
+            channel.push(&mut queue, at, &packet(n));
+        }
+        let released = take_due(&mut queue, at + Duration::from_secs(1)).into_iter().map(|(_, data)| data).collect();
+        (released, channel.stats)
+    }
+
+    #[test]
+    fn same_seed_same_decisions() {
+        let (released, stats) = decisions(7);
+        assert_eq!(decisions(7), (released.clone(), stats));
+        assert_ne!(decisions(8).0, released);
+
+        assert_eq!(stats.packets, 200);
+        assert!(stats.dropped > 0 && stats.duplicated > 0 && stats.reordered > 0 && stats.corrupted > 0, "{:?}", stats);
+        assert_eq!(released.len() as u64, stats.packets - stats.dropped + stats.duplicated);
+        // Sequence numbers of the packets that were not corrupted, reordered ones come later
+        let numbers: Vec<u16> = released
+            .iter()
+            .filter(|data| data.chunks(2).all(|pair| pair == &data[..2]))
+            .map(|data| u16::from_le_bytes([data[0], data[1]]))
+            .collect();
+        assert!(numbers.windows(2).any(|pair| pair[0] > pair[1]));
+    }
+
+    // Through the sender thread: timing may shuffle the order, what arrives does not change
+    fn through_channel(seed: u64) -> (Vec<Vec<u8>>, ImpairmentStats) {
+        let (ours, dog) = channel_pair();
+        let conn = ImpairedConnection::new(ours, harsh(), ImpairmentConfig::default(), seed);
+        for n in 0..200 {
+            conn.send(&packet(n));
+        }
+        thread::sleep(Duration::from_millis(50));
+        let mut arrived = dog.get_data();
+        arrived.sort();
+        (arrived, conn.tx_stats())
+    }
+
+    #[test]
+    fn same_seed_same_packets_over_a_transport() {
+        let (arrived, stats) = through_channel(42);
+        assert_eq!(through_channel(42), (arrived.clone(), stats));
+        assert_eq!(arrived.len() as u64, stats.packets - stats.dropped + stats.duplicated);
+        assert_ne!(through_channel(43).0, arrived);
+    }
+
+    #[test]
+    fn clean_config_passes_everything() {
+        let (ours, dog) = channel_pair();
+        let conn = ImpairedConnection::symmetric(ours, ImpairmentConfig::default(), 1);
+        for n in 0..50 {
+            conn.send(&packet(n));
+        }
+        thread::sleep(Duration::from_millis(20));
+        assert_eq!(dog.get_data(), (0..50).map(packet).collect::<Vec<_>>());
+        dog.send(&packet(9));
+        assert_eq!(conn.get_data(), [packet(9)]);
+        assert_eq!(conn.tx_stats(), ImpairmentStats { packets: 50, ..ImpairmentStats::default() });
+    }
+}
@@ -0,0 +1,355 @@
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+use super::enums::Level;
+use super::highCmd::HighCmd;
+use super::lowCmd::LowCmd;
+use super::transport::Transport;
+
+#[derive(Debug, Clone)]
+pub struct KeepaliveConfig {
+    pub period: Duration,      // retransmit interval
+    pub stale_after: Duration, // without set_command for this long the command is treated as stale
+    // A stale HighCmd keeps going out with its velocity and yaw speed ramped down linearly to
+    // zero over this, then the idle frame follows. Zero, or a frame that is not a HighCmd,
+    // switches to the idle frame right away.
+    pub decay: Duration,
+    pub idle_cmd: Vec<u8>,
+    pub watchdog: Option<Duration>, // the application has to feed() the slot within this, None to not watch
+}
+
+impl KeepaliveConfig {
+    // 500 Hz, slows down to standing still (Idle mode, zero velocity) over 300 ms after 200 ms
+    pub fn high() -> Self {
+        KeepaliveConfig {
+            period: Duration::from_millis(2),
+            stale_after: Duration::from_millis(200),
+            decay: Duration::from_millis(300),
+            idle_cmd: HighCmd::new().build_cmd(false),
+            watchdog: None,
+        }
+    }
+
+    // Low level has no "stand still" frame that is safe in every pose, so go limp instead
+    pub fn low() -> Self {
+        KeepaliveConfig {
+            period: Duration::from_millis(2),
+            stale_after: Duration::from_millis(50),
+            decay: Duration::ZERO,
+            idle_cmd: LowCmd::damping().build_cmd(false),
+            watchdog: None,
+        }
+    }
+
+    pub fn for_level(level: Level) -> Self {
+        match level {
+            Level::High => Self::high(),
+            Level::Low => Self::low(),
+        }
+    }
+
+    pub fn with_decay(mut self, decay: Duration) -> Self {
+        self.decay = decay;
+        self
+    }
+
+    // Unlike stale_after, which any set_command satisfies (e.g. a network thread forwarding an
+    // old velocity), the watchdog needs a feed() from the control loop itself every cycle
+    pub fn with_watchdog(mut self, deadline: Duration) -> Self {
+        self.watchdog = Some(deadline);
+        self
+    }
+}
+
+// The "current command" the background thread keeps sending
+#[derive(Debug, Default)]
+pub struct CommandSlot {
+    current: Mutex<Option<(Instant, Vec<u8>)>>,
+    running: AtomicBool,
+    generation: AtomicU64, // bumped by every spawn, a thread from an earlier one stops sending
+    sent: AtomicU64,
+    stale_sent: AtomicU64, // frames that were the idle fallback or a decaying stale command
+    fed: Mutex<Option<Instant>>,
+    starved: Mutex<Option<(Instant, u64)>>, // watchdog trip time and frames_sent then, while it is tripped
+    watchdog_trips: AtomicU64,
+}
+
+impl CommandSlot {
+    pub fn set(&self, cmd: &[u8]) {
+        *self.current.lock().unwrap() = Some((Instant::now(), cmd.to_vec()));
+    }
+
+    // Drop the current command, the thread sends the idle frame from now on
+    pub fn clear(&self) {
+        *self.current.lock().unwrap() = None;
+    }
+
+    // Time since the last set, None if there is no current command
+    pub fn age(&self) -> Option<Duration> {
+        self.current.lock().unwrap().as_ref().map(|(at, _)| at.elapsed())
+    }
+
+    pub fn is_running(&self) -> bool {
+        self.running.load(Ordering::SeqCst)
+    }
+
+    pub fn frames_sent(&self) -> u64 {
+        self.sent.load(Ordering::Relaxed)
+    }
+
+    pub fn idle_frames_sent(&self) -> u64 {
+        self.stale_sent.load(Ordering::Relaxed)
+    }
+
+    pub fn stop(&self) {
+        self.running.store(false, Ordering::SeqCst);
+    }
+
+    // Once per control cycle when the keepalive runs with a watchdog. After a trip the idle frame
+    // is sent until the next feed, the command from before the trip is not resumed.
+    pub fn feed(&self) {
+        let now = Instant::now();
+        let last = self.fed.lock().unwrap().replace(now);
+        if let Some((tripped, frames)) = self.starved.lock().unwrap().take() {
+            let gap = last.map(|at| now.duration_since(at)).unwrap_or_default();
+            eprintln!(
+                "[watchdog] fed again after {:?} without a feed, {:?} on the idle command, {} frames",
+                gap,
+                now.duration_since(tripped),
+                self.frames_sent() - frames
+            );
+        }
+    }
+
+    pub fn is_starved(&self) -> bool {
+        self.starved.lock().unwrap().is_some()
+    }
+
+    // Deadlines missed so far
+    pub fn watchdog_trips(&self) -> u64 {
+        self.watchdog_trips.load(Ordering::Relaxed)
+    }
+
+    // Trip when the last feed is older than `deadline`, the current command is dropped
+    fn check_watchdog(&self, deadline: Duration) {
+        let Some(fed) = *self.fed.lock().unwrap() else { return };
+        let mut starved = self.starved.lock().unwrap();
+        let since = fed.elapsed();
+        if starved.is_some() || since <= deadline {
+            return;
+        }
+        *starved = Some((Instant::now(), self.frames_sent()));
+        self.watchdog_trips.fetch_add(1, Ordering::Relaxed);
+        let age = self.age();
+        self.clear();
+        eprintln!(
+            "[watchdog] deadline of {:?} missed, last feed {:?} ago, command {:?} old, switching to the idle command",
+            deadline, since, age
+        );
+    }
+
+    // Current command, its decay once it has gone stale, or the idle frame after that or when
+    // the watchdog tripped
+    fn frame(&self, config: &KeepaliveConfig) -> (Vec<u8>, bool) {
+        if self.is_starved() {
+            return (config.idle_cmd.clone(), true);
+        }
+        match self.current.lock().unwrap().as_ref() {
+            Some((at, cmd)) => {
+                let age = at.elapsed();
+                if age < config.stale_after {
+                    return (cmd.clone(), false);
+                }
+                let decayed = decay(cmd, age - config.stale_after, config.decay);
+                (decayed.unwrap_or_else(|| config.idle_cmd.clone()), true)
+            }
+            None => (config.idle_cmd.clone(), true),
+        }
+    }
+}
+
+// `cmd` with velocity and yaw speed scaled by what is left of `decay`, None once it ran out or
+// if `cmd` is not a HighCmd frame
+fn decay(cmd: &[u8], stale_for: Duration, decay: Duration) -> Option<Vec<u8>> {
+    if stale_for >= decay {
+        return None;
+    }
+    let mut hcmd = HighCmd::from_bytes(cmd).ok()?;
+    let left = 1.0 - stale_for.as_secs_f32() / decay.as_secs_f32();
+    hcmd.velocity = [hcmd.velocity[0] * left, hcmd.velocity[1] * left];
+    hcmd.yaw_speed *= left;
+    Some(hcmd.build_cmd(false))
+}
+
+// Keepalive over any transport, set_command/clear_command go through the returned slot
+pub fn start<T>(transport: Arc<T>, config: KeepaliveConfig) -> Arc<CommandSlot>
+where
+    T: Transport + Send + Sync + ?Sized + 'static,
+{
+    let slot = Arc::new(CommandSlot::default());
+    spawn(move |frame: &[u8]| transport.send(frame), Arc::clone(&slot), config);
+    slot
+}
+
+// Retransmit the slot through `send` every `period` until stopped. Returns false if a keepalive was already running.
+pub fn spawn<S>(send: S, slot: Arc<CommandSlot>, config: KeepaliveConfig) -> bool
+where
+    S: Fn(&[u8]) + Send + 'static,
+{
+    if slot.running.swap(true, Ordering::SeqCst) {
+        return false;
+    }
+    // A stop() right before this may not have been noticed by the old thread yet
+    let generation = slot.generation.fetch_add(1, Ordering::SeqCst) + 1;
+    // The first deadline counts from the start, an application that never feeds trips too
+    if config.watchdog.is_some() {
+        *slot.fed.lock().unwrap() = Some(Instant::now());
+        *slot.starved.lock().unwrap() = None;
+    }
+    thread::spawn(move || {
+        let mut deadline = Instant::now();
+        while slot.is_running() && slot.generation.load(Ordering::SeqCst) == generation {
+            if let Some(watchdog) = config.watchdog {
+                slot.check_watchdog(watchdog);
+            }
+            let (frame, stale) = slot.frame(&config);
+            send(&frame);
+            slot.sent.fetch_add(1, Ordering::Relaxed);
+            if stale {
+                slot.stale_sent.fetch_add(1, Ordering::Relaxed);
+            }
+
+            // Absolute deadlines so the rate does not drift, skip ahead if we fell behind
+            deadline += config.period;
+            let now = Instant::now();
+            if deadline > now {
+                thread::sleep(deadline - now);
+            } else {
+                deadline = now;
+            }
+        }
+    });
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ucl::enums::MotorModeHigh;
+    use crate::ucl::transport::channel_pair;
+
+    fn config(period_ms: u64) -> KeepaliveConfig {
+        KeepaliveConfig { period: Duration::from_millis(period_ms), ..KeepaliveConfig::high() }
+    }
+
+    #[test]
+    fn restart_right_after_stop_runs_one_thread() {
+        let (ours, dog) = channel_pair();
+        let ours = Arc::new(ours);
+        let slot = Arc::new(CommandSlot::default());
+        for _ in 0..3 {
+            let transport = Arc::clone(&ours);
+            assert!(spawn(move |frame: &[u8]| transport.send(frame), Arc::clone(&slot), config(5)));
+            slot.stop();
+        }
+        let transport = Arc::clone(&ours);
+        assert!(spawn(move |frame: &[u8]| transport.send(frame), Arc::clone(&slot), config(5)));
+        assert!(!spawn(|_: &[u8]| {}, Arc::clone(&slot), config(5)));
+
+        thread::sleep(Duration::from_millis(20));
+        dog.get_data();
+        thread::sleep(Duration::from_millis(100));
+        let frames = dog.get_data().len();
+        slot.stop();
+        // 20 frames from one thread, four threads would send 80
+        assert!((10..30).contains(&frames), "{} frames in 100 ms", frames);
+    }
+
+    #[test]
+    fn stale_command_falls_back_to_idle() {
+        let (ours, dog) = channel_pair();
+        let config = KeepaliveConfig { stale_after: Duration::from_millis(20), ..config(2) };
+        let idle = config.idle_cmd.clone();
+        let slot = start(Arc::new(ours), config);
+        slot.set(b"walk");
+        thread::sleep(Duration::from_millis(10));
+        assert!(dog.get_data().iter().all(|frame| frame == b"walk"));
+        thread::sleep(Duration::from_millis(30));
+        dog.get_data();
+        thread::sleep(Duration::from_millis(10));
+        let frames = dog.get_data();
+        slot.stop();
+        assert!(!frames.is_empty() && frames.iter().all(|frame| *frame == idle));
+        assert!(slot.idle_frames_sent() > 0);
+    }
+
+    #[test]
+    fn stale_high_command_slows_down_before_idle() {
+        let (ours, dog) = channel_pair();
+        let config = KeepaliveConfig { stale_after: Duration::from_millis(20), ..config(2) }.with_decay(Duration::from_millis(100));
+        let idle = config.idle_cmd.clone();
+        let mut walk = HighCmd::new();
+        walk.set_mode(MotorModeHigh::VelWalk);
+        walk.velocity = [0.8, -0.2];
+        walk.yaw_speed = 1.0;
+        let slot = start(Arc::new(ours), config);
+        slot.set(&walk.build_cmd(false));
+        thread::sleep(Duration::from_millis(180));
+        let frames = dog.get_data();
+        slot.stop();
+
+        let decoded: Vec<HighCmd> = frames.iter().take_while(|frame| **frame != idle).map(|frame| HighCmd::from_bytes(frame).unwrap()).collect();
+        assert!(decoded.iter().all(|hcmd| hcmd.mode() == Ok(MotorModeHigh::VelWalk)));
+        for pair in decoded.windows(2) {
+            assert!(pair[1].velocity[0] <= pair[0].velocity[0] && pair[1].yaw_speed <= pair[0].yaw_speed);
+            assert!(pair[1].velocity[1] >= pair[0].velocity[1]); // -0.2 towards zero
+        }
+        let slowing = decoded.iter().filter(|hcmd| hcmd.velocity[0] > 0.1 && hcmd.velocity[0] < 0.7).count();
+        assert!(slowing > 10, "{} frames on the way down", slowing);
+        // And idle for good at the end
+        let rest = &frames[decoded.len()..];
+        assert!(rest.len() > 10 && rest.iter().all(|frame| *frame == idle));
+    }
+
+    #[test]
+    fn watchdog_trips_and_recovers_on_feed() {
+        let (ours, dog) = channel_pair();
+        let config = KeepaliveConfig { stale_after: Duration::from_secs(10), ..config(2) }.with_watchdog(Duration::from_millis(20));
+        let idle = config.idle_cmd.clone();
+        let slot = start(Arc::new(ours), config);
+        for _ in 0..5 {
+            slot.set(b"walk");
+            slot.feed();
+            thread::sleep(Duration::from_millis(5));
+        }
+        assert!(!slot.is_starved());
+        assert!(dog.get_data().iter().all(|frame| frame == b"walk"));
+
+        // The control loop hangs, but something else keeps the command fresh
+        for _ in 0..20 {
+            if slot.is_starved() {
+                break;
+            }
+            slot.set(b"walk");
+            thread::sleep(Duration::from_millis(5));
+        }
+        assert!(slot.is_starved());
+        assert_eq!(slot.age(), None);
+        thread::sleep(Duration::from_millis(5));
+        assert_eq!(slot.watchdog_trips(), 1);
+        assert_eq!(dog.get_data().last(), Some(&idle));
+
+        // A feed ends the trip, the old command is not resumed
+        slot.feed();
+        assert!(!slot.is_starved());
+        thread::sleep(Duration::from_millis(10));
+        assert!(dog.get_data().contains(&idle));
+        slot.set(b"walk");
+        slot.feed();
+        thread::sleep(Duration::from_millis(10));
+        assert_eq!(dog.get_data().last().map(|frame| frame.as_slice()), Some(&b"walk"[..]));
+        slot.stop();
+        assert_eq!(slot.watchdog_trips(), 1);
+    }
+}
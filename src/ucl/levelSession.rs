@@ -0,0 +1,322 @@
+use std::fmt;
+use std::net::{IpAddr, SocketAddr};
+use std::thread;
+use std::time::{Duration, Instant};
+use super::config::ConnectionProfile;
+use super::discovery::{classify_state, init_frame};
+use super::enums::{Level, MotorModeHigh, MotorModeLow};
+use super::highState::HighState;
+use super::lowCmd::LowCmd;
+use super::unitreeConnection::UnitreeConnection;
+
+// The level can only be switched by the operator, there is no known command frame for it.
+// The RC sequence from the README, the dog ends up lying limp in low level:
+pub const TO_LOW_STEPS: [&str; 4] = ["L2 + A", "L2 + A", "L2 + B", "L1 + L2 + START"];
+// Air/Pro dogs boot into high level, a power cycle is the way back
+pub const TO_HIGH_STEPS: [&str; 1] = ["power cycle the robot"];
+
+const LOW_STATE_MOTORS: usize = 75;
+
+#[derive(Debug)]
+pub enum SessionError {
+    UnknownLevel,                          // no state received yet
+    AlreadyAt(Level),
+    UnsafeTransition(Level, &'static str), // target level, why it was refused
+    NoState(Level),                        // the operator did not switch in time, or the switch did not take
+    WrongLevel { frame: Level, robot: Level },
+}
+
+impl fmt::Display for SessionError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SessionError::UnknownLevel => write!(f, "no state received yet, the robot's level is unknown"),
+            SessionError::AlreadyAt(level) => write!(f, "robot is already at {:?} level", level),
+            SessionError::UnsafeTransition(level, reason) => write!(f, "refusing to switch to {:?} level: {}", level, reason),
+            SessionError::NoState(level) => write!(f, "no {:?} level state within the operator timeout", level),
+            SessionError::WrongLevel { frame, robot } => {
+                write!(f, "{:?} level frame while the robot is at {:?} level, switch levels first", frame, robot)
+            }
+        }
+    }
+}
+
+impl std::error::Error for SessionError {}
+
+#[derive(Debug, Clone)]
+pub struct LevelSwitchConfig {
+    pub announce_period: Duration,  // init frames to both ports while waiting, 20 ms to 100 ms is plenty
+    pub operator_timeout: Duration, // how long the operator gets for the RC sequence or the power cycle
+    pub max_speed: f32,             // m/s for each velocity axis and rad/s for the yaw speed that still count as standing still
+}
+
+impl Default for LevelSwitchConfig {
+    fn default() -> Self {
+        LevelSwitchConfig { announce_period: Duration::from_millis(50), operator_timeout: Duration::from_secs(120), max_speed: 0.05 }
+    }
+}
+
+impl LevelSwitchConfig {
+    pub fn with_max_speed(mut self, max_speed: f32) -> Self {
+        self.max_speed = max_speed;
+        self
+    }
+}
+
+// One socket talking to both the high level (8082) and the low level (8007) port of the dog.
+// The current level is whatever the dog streams, not what we asked for.
+pub struct LevelSession {
+    conn: UnitreeConnection,
+    high_addr: SocketAddr,
+    low_addr: SocketAddr,
+    config: LevelSwitchConfig,
+    latest: Option<Vec<u8>>,
+    last_update: Option<Instant>,
+}
+
+impl LevelSession {
+    // Binds the local address of `high`, commands go to the send addresses of both profiles
+    pub fn new(high: &ConnectionProfile, low: &ConnectionProfile, config: LevelSwitchConfig) -> Self {
+        let conn = UnitreeConnection::new(IpAddr::V4(high.local_ip), high.listen_port, high.send_addr());
+        conn.start_recv();
+        LevelSession { conn, high_addr: high.send_addr(), low_addr: low.send_addr(), config, latest: None, last_update: None }
+    }
+
+    // Tell both ports where we are, the dog answers on the level it is in. The low level port
+    // gets damping rather than the zero gain servo init frame, so a limp dog stays limp.
+    pub fn handshake(&mut self) {
+        self.conn.send_to(&init_frame(Level::High), self.high_addr);
+        self.conn.send_to(&LowCmd::damping().build_cmd(false), self.low_addr);
+    }
+
+    // Drain the socket and keep the newest state
+    pub fn poll(&mut self) -> Option<&[u8]> {
+        if let Some(packet) = self.conn.get_data().into_iter().rev().find(|p| classify_state(p).is_some()) {
+            self.latest = Some(packet);
+            self.last_update = Some(Instant::now());
+        }
+        self.latest.as_deref()
+    }
+
+    pub fn level(&self) -> Option<Level> {
+        self.latest.as_deref().and_then(classify_state)
+    }
+
+    pub fn latest_state(&self) -> Option<&[u8]> {
+        self.latest.as_deref()
+    }
+
+    pub fn last_update(&self) -> Option<Instant> {
+        self.last_update
+    }
+
+    // Route a HighCmd/LowCmd frame to the matching port. Frames for the other level are refused.
+    pub fn send(&self, cmd: &[u8]) -> Result<(), SessionError> {
+        let target = if cmd.get(2) == Some(&(Level::Low as u8)) { Level::Low } else { Level::High };
+        match self.level() {
+            None => Err(SessionError::UnknownLevel),
+            Some(level) if level != target => Err(SessionError::WrongLevel { frame: target, robot: level }),
+            Some(Level::High) => {
+                self.conn.send_to(cmd, self.high_addr);
+                Ok(())
+            }
+            Some(Level::Low) => {
+                self.conn.send_to(cmd, self.low_addr);
+                Ok(())
+            }
+        }
+    }
+
+    // Check the robot is standing still, hand TO_LOW_STEPS to `guide` (print them, show them in
+    // a UI) and wait for the low level state. The dog has to be standing still, not lying down,
+    // because the sequence starts with L2 + A which makes a standing dog lie down. Standing still
+    // means a standing mode and a measured velocity and yaw speed below config.max_speed.
+    pub fn switch_to_low<G>(&mut self, guide: G) -> Result<(), SessionError>
+    where
+        G: FnOnce(&[&'static str]),
+    {
+        self.poll();
+        let state = self.latest.clone().ok_or(SessionError::UnknownLevel)?;
+        match classify_state(&state) {
+            Some(Level::Low) => return Err(SessionError::AlreadyAt(Level::Low)),
+            Some(Level::High) => {}
+            None => return Err(SessionError::UnknownLevel),
+        }
+        let mut hstate = HighState::new();
+        hstate.parse_data(&state);
+        match hstate.mode() {
+            Ok(MotorModeHigh::Idle | MotorModeHigh::ForceStand | MotorModeHigh::StandUp) => {}
+            Ok(MotorModeHigh::StandDown | MotorModeHigh::Damping) => {
+                return Err(SessionError::UnsafeTransition(Level::Low, "robot is lying down, stand it up before switching"))
+            }
+            _ => return Err(SessionError::UnsafeTransition(Level::Low, "robot is moving, stop it before switching")),
+        }
+        let max_speed = self.config.max_speed;
+        if hstate.velocity.iter().any(|v| v.abs() >= max_speed) || hstate.yaw_speed.abs() >= max_speed {
+            return Err(SessionError::UnsafeTransition(Level::Low, "robot is still moving, wait until it stands still"));
+        }
+
+        guide(&TO_LOW_STEPS);
+        self.wait_for(Level::Low)
+    }
+
+    // Only with every leg motor in damping, the robot has to be limp before it is powered off.
+    // Hands TO_HIGH_STEPS to `guide` and waits for the high level state after the reboot.
+    pub fn switch_to_high<G>(&mut self, guide: G) -> Result<(), SessionError>
+    where
+        G: FnOnce(&[&'static str]),
+    {
+        self.poll();
+        let state = self.latest.clone().ok_or(SessionError::UnknownLevel)?;
+        match classify_state(&state) {
+            Some(Level::High) => return Err(SessionError::AlreadyAt(Level::High)),
+            Some(Level::Low) => {}
+            None => return Err(SessionError::UnknownLevel),
+        }
+        let all_damping = (0..12).all(|i| state[LOW_STATE_MOTORS + i * 32] == MotorModeLow::Damping as u8);
+        if !all_damping {
+            return Err(SessionError::UnsafeTransition(Level::High, "all leg motors must be in damping"));
+        }
+
+        guide(&TO_HIGH_STEPS);
+        self.wait_for(Level::High)
+    }
+
+    // Keep announcing ourselves on both ports until the new level's state arrives. The dog answers
+    // on the level it is in, and after a reboot it needs the init frame again.
+    fn wait_for(&mut self, level: Level) -> Result<(), SessionError> {
+        let started = Instant::now();
+        while started.elapsed() < self.config.operator_timeout {
+            self.handshake();
+            thread::sleep(self.config.announce_period);
+            if self.poll().and_then(classify_state) == Some(level) {
+                return Ok(());
+            }
+        }
+        Err(SessionError::NoState(level))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ucl::mock::{MockConfig, UdpMock};
+
+    // go1-mock on localhost with a profile for each of its ports
+    struct Dog {
+        mock: UdpMock,
+        high: ConnectionProfile,
+        low: ConnectionProfile,
+    }
+
+    impl Dog {
+        fn start() -> Self {
+            let config = MockConfig { boot_time: Duration::from_millis(50), ..MockConfig::default() };
+            let mock = UdpMock::bind(IpAddr::from([127, 0, 0, 1]), 0, 0, config).unwrap();
+            let profile = |name, addr: SocketAddr| ConnectionProfile::new(name, "127.0.0.1", addr.port(), "127.0.0.1", 0);
+            let (high, low) = (profile("high", mock.high_addr()), profile("low", mock.low_addr()));
+            Dog { mock, high, low }
+        }
+
+        fn session(&self) -> LevelSession {
+            let config = LevelSwitchConfig {
+                announce_period: Duration::from_millis(10),
+                operator_timeout: Duration::from_secs(2),
+                ..LevelSwitchConfig::default()
+            };
+            let mut session = LevelSession::new(&self.high, &self.low, config);
+            let started = Instant::now();
+            while session.level().is_none() && started.elapsed() < Duration::from_secs(2) {
+                session.handshake();
+                thread::sleep(Duration::from_millis(10));
+                session.poll();
+            }
+            session
+        }
+    }
+
+    #[test]
+    fn operator_switches_to_low_and_back() {
+        let dog = Dog::start();
+        let mut session = dog.session();
+        assert_eq!(session.level(), Some(Level::High));
+        assert!(matches!(
+            session.send(&LowCmd::damping().build_cmd(false)),
+            Err(SessionError::WrongLevel { frame: Level::Low, robot: Level::High })
+        ));
+
+        let mut shown = Vec::new();
+        session
+            .switch_to_low(|steps| {
+                shown = steps.to_vec();
+                dog.mock.robot.lock().unwrap().operator_switch(Level::Low);
+            })
+            .unwrap();
+        assert_eq!(shown, TO_LOW_STEPS);
+        assert_eq!(session.level(), Some(Level::Low));
+        assert!(session.send(&LowCmd::damping().build_cmd(false)).is_ok());
+        assert!(matches!(session.switch_to_low(|_| {}), Err(SessionError::AlreadyAt(Level::Low))));
+
+        session.switch_to_high(|_| dog.mock.robot.lock().unwrap().power_cycle()).unwrap();
+        assert_eq!(session.level(), Some(Level::High));
+    }
+
+    #[test]
+    fn refuses_unsafe_switches_without_bothering_the_operator() {
+        let dog = Dog::start();
+        let mut session = dog.session();
+        dog.mock.robot.lock().unwrap().mode = MotorModeHigh::VelWalk;
+        thread::sleep(Duration::from_millis(20));
+        let mut asked = false;
+        let refused = session.switch_to_low(|_| asked = true);
+        assert!(matches!(refused, Err(SessionError::UnsafeTransition(Level::Low, _))), "{:?}", refused);
+
+        // In low level with the legs holding a pose
+        dog.mock.robot.lock().unwrap().operator_switch(Level::Low);
+        let started = Instant::now();
+        while session.poll().and_then(classify_state) != Some(Level::Low) && started.elapsed() < Duration::from_secs(2) {
+            session.handshake();
+            thread::sleep(Duration::from_millis(10));
+        }
+        session.send(&LowCmd::new().build_cmd(false)).unwrap(); // servo mode on every motor
+        thread::sleep(Duration::from_millis(20));
+        let refused = session.switch_to_high(|_| asked = true);
+        assert!(matches!(refused, Err(SessionError::UnsafeTransition(Level::High, _))), "{:?}", refused);
+        assert!(!asked);
+    }
+
+    // Every reason switch_to_low says no, none of them reach the operator
+    #[test]
+    fn switch_to_low_wants_the_robot_standing_still() {
+        let dog = Dog::start();
+        let mut session = dog.session();
+        let mut refuse = |setup: &dyn Fn(&mut crate::ucl::mock::MockRobot), why: &str| {
+            setup(&mut dog.mock.robot.lock().unwrap());
+            thread::sleep(Duration::from_millis(20));
+            let mut asked = false;
+            match session.switch_to_low(|_| asked = true) {
+                Err(SessionError::UnsafeTransition(Level::Low, reason)) => assert!(reason.contains(why), "{}", reason),
+                other => panic!("{:?}", other),
+            }
+            assert!(!asked);
+        };
+
+        refuse(&|robot| robot.mode = MotorModeHigh::StandDown, "lying down");
+        refuse(&|robot| robot.mode = MotorModeHigh::Damping, "lying down");
+        refuse(&|robot| robot.mode = MotorModeHigh::VelWalk, "moving");
+        // Idle but still coasting, it brakes over ~100 ms
+        refuse(
+            &|robot| {
+                robot.mode = MotorModeHigh::Idle;
+                robot.velocity = [3.0, 0.0];
+            },
+            "still moving",
+        );
+        refuse(
+            &|robot| {
+                robot.velocity = [0.0, 0.0];
+                robot.yaw_speed = -3.0;
+            },
+            "still moving",
+        );
+    }
+}
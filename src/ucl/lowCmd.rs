@@ -1,97 +1,208 @@
-use crc::{crc32, Hasher32};
-use std::collections::HashMap;
-use byteorder::{ByteOrder, LittleEndian};
+use super::common::{encrypt_crc, frame_crc};
+use super::complex::{BmsCmd, MotorCmdArray, MotorCmdError, MotorSpecs, Validation};
 
-// Assuming `MotorModeLow`, `GaitType`, `SpeedLevel` are enums defined in `enums.rs`
-// and `float_to_hex`, `encrypt_crc`, `gen_crc` are functions defined in `common.rs`
-// and `BmsCmd`, `MotorCmd`, `MotorCmdArray` are structs or enums defined in `complex.rs`
-// You will need to define these in Rust accordingly.
+const LOW_CMD_LEN: usize = 614;
 
+#[derive(Debug, Clone)]
 pub struct LowCmd {
-    head: Vec<u8>,
+    head: [u8; 2],
     level_flag: u8,
     frame_reserve: u8,
-    sn: Vec<u8>,
-    version: Vec<u8>,
-    band_width: Vec<u8>,
-    motor_cmd: MotorCmdArray,
-    bms: BmsCmd,
-    wireless_remote: Vec<u8>,
-    reserve: Vec<u8>,
-    crc: Option<u32>,
-    encrypt: bool,
+    sn: [u8; 8],
+    version: [u8; 8],
+    band_width: [u8; 2],
+    pub motor_cmd: MotorCmdArray,
+    pub motor_specs: MotorSpecs, // what motor_cmd is checked against when the frame is built
+    pub validation: Validation,
+    pub bms: BmsCmd,
+    pub wireless_remote: [u8; 40],
+    reserve: [u8; 4],
+    crc: Option<[u8; 4]>,
+    pub encrypt: bool,
+}
+
+impl Default for LowCmd {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl LowCmd {
     pub fn new() -> LowCmd {
         LowCmd {
-            head: vec![0xFE, 0xEF], // Hex FEEF
+            head: [0xFE, 0xEF], // Hex FEEF
             level_flag: 0xff,
             frame_reserve: 0,
-            sn: vec![0; 8],
-            version: vec![0; 8],
-            band_width: vec![0x3a, 0xc0], // Hex 3AC0
-            motor_cmd: MotorCmdArray::new(), // Placeholder for the actual implementation
-            bms: BmsCmd::new(0, vec![0, 0, 0]), // Placeholder for the actual implementation
-            wireless_remote: vec![0; 40],
-            reserve: vec![0; 4],
+            sn: [0; 8],
+            version: [0; 8],
+            band_width: [0x3a, 0xc0], // Hex 3AC0
+            motor_cmd: MotorCmdArray::new(),
+            motor_specs: MotorSpecs::go1(),
+            validation: Validation::Lenient,
+            bms: BmsCmd::new(0, [0, 0, 0]),
+            wireless_remote: [0; 40],
+            reserve: [0; 4],
             crc: None,
             encrypt: true,
         }
     }
 
+    // All motors in damping, the fallback for every stop path
+    pub fn damping() -> LowCmd {
+        let mut lcmd = LowCmd::new();
+        lcmd.motor_cmd = MotorCmdArray::damping();
+        lcmd
+    }
+
+    // With Validation::Strict a motor command outside its spec is not sent: the frame goes out
+    // with every motor in damping instead. Use try_build_cmd to get the error.
     pub fn build_cmd(&mut self, debug: bool) -> Vec<u8> {
-        let mut cmd = vec![0; 614];
+        match self.try_build_cmd(debug) {
+            Ok(cmd) => cmd,
+            Err((motor, e)) => {
+                eprintln!("[lowcmd] motor {}: {}, sending damping instead", motor, e);
+                let mut damping = LowCmd { motor_cmd: MotorCmdArray::damping(), ..self.clone() };
+                let cmd = damping.try_build_cmd(debug).expect("damping is within every spec");
+                self.crc = damping.crc;
+                cmd
+            }
+        }
+    }
+
+    // Fails in Strict mode with the index of the first motor command outside its spec
+    pub fn try_build_cmd(&mut self, debug: bool) -> Result<Vec<u8>, (usize, MotorCmdError)> {
+        let motor_bytes = self.motor_cmd.get_bytes(&self.motor_specs, self.validation)?;
+        let mut cmd = vec![0; LOW_CMD_LEN];
         cmd[0..2].copy_from_slice(&self.head);
         cmd[2] = self.level_flag;
         cmd[3] = self.frame_reserve;
         cmd[4..12].copy_from_slice(&self.sn);
         cmd[12..20].copy_from_slice(&self.version);
         cmd[20..22].copy_from_slice(&self.band_width);
-        cmd[22..562].copy_from_slice(&self.motor_cmd.get_bytes());
-        cmd[562..594].copy_from_slice(&self.bms.get_bytes());
-        cmd[594..606].copy_from_slice(&self.wireless_remote);
-
-        // ... Fill in the rest of the cmd with data from motor_cmd, bms, etc.
-    //     if self.encrypt:
-    //     cmd[-4:] = encryptCrc(genCrc(cmd[:-6]))
-    // else:
-    //     cmd[-4:] = genCrc(cmd[:-6])
-        // Placeholder for the actual CRC and encryption logic
-        // You will need to implement the `encrypt_crc` and `gen_crc` functions in Rust.
+        cmd[22..562].copy_from_slice(&motor_bytes);
+        cmd[562..566].copy_from_slice(&self.bms.get_bytes());
+        cmd[566..606].copy_from_slice(&self.wireless_remote);
+        cmd[606..610].copy_from_slice(&self.reserve);
+
+        let crc = if self.encrypt {
+            encrypt_crc(frame_crc(&cmd))
+        } else {
+            frame_crc(&cmd).to_le_bytes()
+        };
+        cmd[610..614].copy_from_slice(&crc);
+        self.crc = Some(crc);
 
         if debug {
             println!("Length: {}", cmd.len());
             println!("Data: {}", cmd.iter().map(|byte| format!("{:02x}", byte)).collect::<String>());
         }
 
-        cmd
+        Ok(cmd)
     }
 
-    // Assuming this is meant to be a static method to parse a `LowCmd` from bytes
-    pub fn low_cmd_from_bytes(data: &[u8]) -> LowCmd {
+    // Decode a 614 byte frame. Whether the CRC was encrypted is detected, build_cmd on the
+    // result uses the same variant.
+    pub fn from_bytes(data: &[u8]) -> Result<LowCmd, &'static str> {
+        if data.len() != LOW_CMD_LEN {
+            return Err("Incorrect byte length for LowCmd");
+        }
+        if data[0..2] != [0xFE, 0xEF] || data[2] != 0xff {
+            return Err("Not a low level command frame");
+        }
+        let crc: [u8; 4] = data[610..614].try_into().unwrap();
+        let encrypt = if crc == frame_crc(data).to_le_bytes() {
+            false
+        } else if crc == encrypt_crc(frame_crc(data)) {
+            true
+        } else {
+            return Err("CRC mismatch");
+        };
+
         let mut lcmd = LowCmd::new();
-        lcmd.head = data[0..2].to_vec();
+        lcmd.head = [data[0], data[1]];
         lcmd.level_flag = data[2];
         lcmd.frame_reserve = data[3];
-        lcmd.sn = data[4..12].to_vec();
-        lcmd.version = data[12..20].to_vec();
-        lcmd.band_width = data[20..22].to_vec();
-        lcmd.motor_cmd = MotorCmdArray::from_bytes(&data[22..562]);
+        lcmd.sn.copy_from_slice(&data[4..12]);
+        lcmd.version.copy_from_slice(&data[12..20]);
+        lcmd.band_width.copy_from_slice(&data[20..22]);
+        lcmd.motor_cmd.from_bytes(&data[22..562])?;
         lcmd.bms = BmsCmd::from_bytes(&data[562..566]);
-        lcmd.wireless_remote = data[566..606].to_vec();
-        lcmd.encrypt = None;
+        lcmd.wireless_remote.copy_from_slice(&data[566..606]);
+        lcmd.reserve.copy_from_slice(&data[606..610]);
+        lcmd.crc = Some(crc);
+        lcmd.encrypt = encrypt;
+        Ok(lcmd)
+    }
+}
 
-        // if cmd[-4:] == encryptCrc(genCrc(cmd[:-6])):
-        //     lcmd.encrypt = True
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ucl::complex::MotorCmd;
+    use crate::ucl::enums::MotorModeLow;
 
-        // if cmd[-4:] == genCrc(cmd[:-6]):
-        //     lcmd.encrypt = False
-        // ... Parse the rest of the data into the lcmd fields
+    #[test]
+    fn build_cmd_layout_and_crc() {
+        let mut lcmd = LowCmd::new();
+        lcmd.wireless_remote[2] = 0x01;
+        lcmd.bms = BmsCmd::new(1, [2, 3, 4]);
+        let frame = lcmd.build_cmd(false);
 
-        // Placeholder for the actual CRC check and setting the encrypt field
-        // You will need to implement the `encrypt_crc` and `gen_crc` functions in Rust.
+        assert_eq!(frame.len(), LOW_CMD_LEN);
+        assert_eq!(frame[0..3], [0xFE, 0xEF, 0xff]);
+        assert_eq!(frame[562..566], [1, 2, 3, 4]);
+        assert_eq!(frame[568], 0x01);
+        assert_eq!(frame[610..614], encrypt_crc(frame_crc(&frame)));
 
-        lcmd
+        lcmd.encrypt = false;
+        let plain = lcmd.build_cmd(false);
+        assert_eq!(plain[610..614], frame_crc(&plain).to_le_bytes());
+    }
+
+    #[test]
+    fn from_bytes_round_trips_both_crc_variants() {
+        for encrypt in [true, false] {
+            let mut lcmd = LowCmd::new();
+            lcmd.encrypt = encrypt;
+            lcmd.motor_cmd.set_motor_cmd(4, MotorCmd::new(MotorModeLow::Servo as u8, 1.25, -0.5, -1.5, 20.5, 1.2, [0, 0, 0]));
+            lcmd.wireless_remote[3] = 0x80;
+            let frame = lcmd.build_cmd(false);
+
+            let mut decoded = LowCmd::from_bytes(&frame).unwrap();
+            assert_eq!(decoded.encrypt, encrypt);
+            let motor = decoded.motor_cmd.get_motor_cmd(4).unwrap();
+            assert_eq!((motor.q(), motor.dq(), motor.tau(), motor.kp(), motor.kd()), (1.25, -0.5, -1.5, 20.5, 1.2));
+            assert_eq!(decoded.build_cmd(false), frame);
+        }
+    }
+
+    #[test]
+    fn validation_mode_reaches_the_wire() {
+        // 30 Nm is within the calf rating, above hip and thigh
+        let mut lcmd = LowCmd::new();
+        lcmd.motor_cmd.set_motor_cmd(2, MotorCmd::new(MotorModeLow::Servo as u8, 0.0, 0.0, 30.0, 0.0, 0.0, [0, 0, 0]));
+        lcmd.motor_cmd.set_motor_cmd(1, MotorCmd::new(MotorModeLow::Servo as u8, 0.0, 0.0, 30.0, 0.0, 0.0, [0, 0, 0]));
+        let lenient = LowCmd::from_bytes(&lcmd.build_cmd(false)).unwrap();
+        let tau = |motor| lenient.motor_cmd.get_motor_cmd(motor).unwrap().tau();
+        assert!((tau(1) - 23.7).abs() < 0.01 && (tau(2) - 30.0).abs() < 0.01, "{} {}", tau(1), tau(2));
+
+        lcmd.validation = Validation::Strict;
+        let (motor, err) = lcmd.try_build_cmd(false).unwrap_err();
+        assert_eq!(motor, 1);
+        assert!(matches!(err, MotorCmdError::OutOfRange { field: "tau", .. }));
+        let sent = LowCmd::from_bytes(&lcmd.build_cmd(false)).unwrap();
+        for motor in 0..20 {
+            assert_eq!(sent.motor_cmd.get_motor_cmd(motor).unwrap().mode(), MotorModeLow::Damping as u8);
+        }
+    }
+
+    #[test]
+    fn from_bytes_rejects_bad_frames() {
+        let mut frame = LowCmd::damping().build_cmd(false);
+        assert!(LowCmd::from_bytes(&frame[..600]).is_err());
+        frame[100] ^= 0x01;
+        assert_eq!(LowCmd::from_bytes(&frame).err(), Some("CRC mismatch"));
+        frame[2] = 0x00;
+        assert!(LowCmd::from_bytes(&frame).is_err());
     }
 }
@@ -0,0 +1,130 @@
+use super::common::hex_to_float;
+use super::complex::{BmsState, Imu, MotorState};
+
+#[derive(Debug, Clone)]
+pub struct LowState {
+    pub head: [u8; 2],
+    pub level_flag: u8,
+    pub frame_reserve: u8,
+    pub sn: [u8; 8],
+    pub version: [u8; 8],
+    pub band_width: [u8; 2],
+    pub imu: Imu,
+    pub motor_state: Vec<MotorState>,
+    pub bms: BmsState,
+    pub foot_force: [u16; 4],
+    pub foot_force_est: [u16; 4],
+    pub tick: u32,
+    pub wireless_remote: [u8; 40],
+    pub reserve: [u8; 4],
+    pub crc: [u8; 4],
+}
+
+impl Default for LowState {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl LowState {
+    pub fn new() -> Self {
+        LowState {
+            head: [0; 2],
+            level_flag: 0,
+            frame_reserve: 0,
+            sn: [0; 8],
+            version: [0; 8],
+            band_width: [0; 2],
+            imu: Imu::default(),
+            motor_state: vec![MotorState::default(); 20],
+            bms: BmsState::default(),
+            foot_force: [0; 4],
+            foot_force_est: [0; 4],
+            tick: 0,
+            wireless_remote: [0; 40],
+            reserve: [0; 4],
+            crc: [0; 4],
+        }
+    }
+
+    // Convert data slice to BmsState, cell voltages come in units of 32 mV here
+    pub fn data_to_bms_state(&self, data: &[u8]) -> BmsState {
+        let version_h = data[0];
+        let version_l = data[1];
+        let bms_status = data[2];
+        let soc = data[3];
+        let current = i32::from_le_bytes([data[4], data[5], data[6], data[7]]);
+        let cycle = u16::from_le_bytes([data[8], data[9]]);
+        let bq_ntc = [data[10], data[11]];
+        let mcu_ntc = [data[12], data[13]];
+        let cell_vol = data[14..24].iter().map(|v| *v as u16 * 32).collect();
+        BmsState::new(version_h, version_l, bms_status, soc, current, cycle, bq_ntc, mcu_ntc, cell_vol)
+    }
+
+    // Convert data slice to Imu
+    pub fn data_to_imu(&self, data: &[u8]) -> Imu {
+        let quaternion = [
+            hex_to_float(&data[0..4]),
+            hex_to_float(&data[4..8]),
+            hex_to_float(&data[8..12]),
+            hex_to_float(&data[12..16]),
+        ];
+        let gyroscope = [
+            hex_to_float(&data[16..20]),
+            hex_to_float(&data[20..24]),
+            hex_to_float(&data[24..28]),
+        ];
+        let accelerometer = [
+            hex_to_float(&data[28..32]),
+            hex_to_float(&data[32..36]),
+            hex_to_float(&data[36..40]),
+        ];
+        let rpy = [
+            hex_to_float(&data[40..44]),
+            hex_to_float(&data[44..48]),
+            hex_to_float(&data[48..52]),
+        ];
+        let temperature = data[52] as f32;
+        Imu::new(quaternion, gyroscope, accelerometer, rpy, temperature)
+    }
+
+    // Low level motor feedback packs ddq and tau_est as fixed point i16
+    pub fn data_to_motor_state(&self, data: &[u8]) -> MotorState {
+        let mode = data[0];
+        let q = hex_to_float(&data[1..5]);
+        let dq = hex_to_float(&data[5..9]);
+        let ddq = i16::from_le_bytes([data[9], data[10]]) as f32;
+        let tau_est = i16::from_le_bytes([data[11], data[12]]) as f32 * 0.00390625; // 1/256 Nm
+        let q_raw = hex_to_float(&data[13..17]);
+        let dq_raw = hex_to_float(&data[17..21]);
+        let ddq_raw = i16::from_le_bytes([data[21], data[22]]) as f32;
+        let temperature = data[23] as f32;
+        MotorState::new(mode, q, dq, ddq, tau_est, q_raw, dq_raw, ddq_raw, temperature, &data[24..32])
+    }
+
+    // Parse a 807 byte LowState datagram
+    pub fn parse_data(&mut self, data: &[u8]) {
+        self.head = [data[0], data[1]];
+        self.level_flag = data[2];
+        self.frame_reserve = data[3];
+        self.sn.copy_from_slice(&data[4..12]);
+        self.version.copy_from_slice(&data[12..20]);
+        self.band_width.copy_from_slice(&data[20..22]);
+        self.imu = self.data_to_imu(&data[22..75]);
+        self.motor_state.clear();
+        for i in 0..20 {
+            self.motor_state.push(self.data_to_motor_state(&data[75 + i * 32..107 + i * 32]));
+        }
+        self.bms = self.data_to_bms_state(&data[715..739]);
+
+        for i in 0..4 {
+            self.foot_force[i] = u16::from_le_bytes([data[739 + i * 2], data[740 + i * 2]]);
+            self.foot_force_est[i] = u16::from_le_bytes([data[747 + i * 2], data[748 + i * 2]]);
+        }
+        self.tick = u32::from_le_bytes([data[755], data[756], data[757], data[758]]);
+
+        self.wireless_remote.copy_from_slice(&data[759..799]);
+        self.reserve.copy_from_slice(&data[799..803]);
+        self.crc.copy_from_slice(&data[803..807]);
+    }
+}
@@ -0,0 +1,503 @@
+use std::io;
+use std::net::{IpAddr, SocketAddr, UdpSocket};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+use super::common::{float_to_hex, frame_crc, hex_to_float};
+use super::discovery::LOW_STATE_LEN;
+use super::enums::{GaitType, Level, MotorModeHigh, MotorModeLow};
+use super::highState::HighState;
+use super::transport::Simulator;
+
+pub const STATE_PERIOD: Duration = Duration::from_millis(2); // the dog streams state at 500 Hz
+
+const HIGH_CMD_LEN: usize = 129;
+const LOW_CMD_LEN: usize = 614;
+const MOTOR_CMD_LEN: usize = 27;
+
+// Standing pose per leg (hip, thigh, calf) and how fast the joints settle on a target
+const STAND_POSE: [f32; 3] = [0.0, 0.67, -1.3];
+const LIE_POSE: [f32; 3] = [0.0, 1.2, -2.7];
+const JOINT_TIME_CONSTANT: f32 = 0.05;
+const STAND_HEIGHT: f32 = 0.28;
+
+#[derive(Debug, Clone)]
+pub struct MockConfig {
+    pub sn: [u8; 8],
+    pub version: [u8; 8],
+    pub battery: u8, // SOC in percent
+    pub level: Level, // level at power on, Air/Pro dogs start in high level
+    // Silent this long after power_cycle(). The real dog takes tens of seconds, the default is
+    // short so demos and tests do not wait.
+    pub boot_time: Duration,
+}
+
+impl Default for MockConfig {
+    fn default() -> Self {
+        MockConfig {
+            sn: [0x4d, 0x4f, 0x43, 0x4b, 0x00, 0x00, 0x00, 0x01], // "MOCK" 1
+            version: [1, 0, 0, 0, 0, 0, 0, 0],
+            battery: 90,
+            level: Level::High,
+            boot_time: Duration::from_secs(1),
+        }
+    }
+}
+
+// A pretend Go1. Commands change its mode and targets, step() advances it,
+// high_state()/low_state() encode what the real dog would send back.
+#[derive(Debug, Clone)]
+pub struct MockRobot {
+    pub config: MockConfig,
+    // Commands for the other level are ignored. On the real dog the operator switches with the RC,
+    // operator_switch() plays that part, power_cycle() goes back to config.level.
+    pub level: Level,
+    pub mode: MotorModeHigh,
+    pub gait_type: GaitType,
+    pub body_height: f32,
+    pub position: [f32; 2],
+    pub yaw: f32,
+    pub velocity: [f32; 2], // body frame
+    pub yaw_speed: f32,
+    pub q: [f32; 12],
+    pub dq: [f32; 12],
+    q_target: [f32; 12],
+    motor_damping: [bool; 12],
+    cmd_velocity: [f32; 2],
+    cmd_yaw_speed: f32,
+    cmd_body_height: f32,
+    tick: u32,
+    commands: u64,
+    booting: Duration, // left until it answers again after a power cycle
+}
+
+impl MockRobot {
+    pub fn new(config: MockConfig) -> Self {
+        let stand = Self::pose(STAND_POSE);
+        let level = config.level;
+        MockRobot {
+            config,
+            level,
+            mode: MotorModeHigh::Idle,
+            gait_type: GaitType::Idle,
+            body_height: STAND_HEIGHT,
+            position: [0.0, 0.0],
+            yaw: 0.0,
+            velocity: [0.0, 0.0],
+            yaw_speed: 0.0,
+            q: stand,
+            dq: [0.0; 12],
+            q_target: stand,
+            motor_damping: [false; 12],
+            cmd_velocity: [0.0, 0.0],
+            cmd_yaw_speed: 0.0,
+            cmd_body_height: 0.0,
+            tick: 0,
+            commands: 0,
+            booting: Duration::ZERO,
+        }
+    }
+
+    fn pose(leg: [f32; 3]) -> [f32; 12] {
+        let mut q = [0.0; 12];
+        for (i, value) in q.iter_mut().enumerate() {
+            *value = leg[i % 3];
+        }
+        q
+    }
+
+    pub fn commands_received(&self) -> u64 {
+        self.commands
+    }
+
+    pub fn is_booting(&self) -> bool {
+        !self.booting.is_zero()
+    }
+
+    // Streaming state: booted and told where to send it
+    pub fn is_streaming(&self) -> bool {
+        !self.is_booting() && self.commands > 0
+    }
+
+    // Take a HighCmd or LowCmd frame. Returns false for anything that is neither, and for
+    // everything while it boots.
+    pub fn handle_command(&mut self, cmd: &[u8]) -> bool {
+        if self.is_booting() || cmd.len() < 3 || cmd[0..2] != [0xFE, 0xEF] {
+            return false;
+        }
+        let level = match (cmd.len(), cmd[2]) {
+            (HIGH_CMD_LEN, 0x00) => Level::High,
+            (LOW_CMD_LEN, 0xff) => Level::Low,
+            _ => return false,
+        };
+        self.commands += 1;
+        if level != self.level {
+            return true; // the dog ignores the other level's port, but the sender still gets our state
+        }
+        match level {
+            Level::High => self.handle_high(cmd),
+            Level::Low => self.handle_low(cmd),
+        }
+        true
+    }
+
+    // What the RC level switch leaves behind: the new level, lying on the floor with the motors limp
+    pub fn operator_switch(&mut self, level: Level) {
+        self.level = level;
+        self.mode = MotorModeHigh::Damping;
+        self.motor_damping = [true; 12];
+        self.q_target = Self::pose(LIE_POSE);
+        self.cmd_velocity = [0.0, 0.0];
+        self.cmd_yaw_speed = 0.0;
+    }
+
+    // Off and on again: silent for config.boot_time, then in config.level, streaming again
+    // once a command tells it where to
+    pub fn power_cycle(&mut self) {
+        *self = MockRobot::new(self.config.clone());
+        self.booting = self.config.boot_time;
+    }
+
+    fn handle_high(&mut self, cmd: &[u8]) {
+        self.mode = MotorModeHigh::try_from(cmd[22]).unwrap_or(MotorModeHigh::Idle);
+        self.gait_type = GaitType::try_from(cmd[23]).unwrap_or(GaitType::Idle);
+        self.cmd_body_height = hex_to_float(&cmd[29..33]);
+        self.cmd_velocity = [hex_to_float(&cmd[53..57]), hex_to_float(&cmd[57..61])];
+        self.cmd_yaw_speed = hex_to_float(&cmd[61..65]);
+
+        self.motor_damping = [self.mode == MotorModeHigh::Damping; 12];
+        self.q_target = match self.mode {
+            MotorModeHigh::StandDown | MotorModeHigh::Damping => Self::pose(LIE_POSE),
+            _ => Self::pose(STAND_POSE),
+        };
+    }
+
+    fn handle_low(&mut self, cmd: &[u8]) {
+        for i in 0..12 {
+            let motor = &cmd[22 + i * MOTOR_CMD_LEN..22 + (i + 1) * MOTOR_CMD_LEN];
+            self.motor_damping[i] = motor[0] != MotorModeLow::Servo as u8;
+            if !self.motor_damping[i] {
+                self.q_target[i] = hex_to_float(&motor[1..5]);
+            }
+        }
+    }
+
+    // Advance the robot by `dt`
+    pub fn step(&mut self, dt: Duration) {
+        if self.is_booting() {
+            self.booting = self.booting.saturating_sub(dt);
+            return;
+        }
+        let dt = dt.as_secs_f32();
+        self.tick = self.tick.wrapping_add((dt * 1000.0) as u32);
+
+        // Only the walking modes move the body, everything else brakes to a stop
+        let walking = self.level == Level::High && matches!(self.mode, MotorModeHigh::VelWalk | MotorModeHigh::PosWalk);
+        let (target_v, target_yaw) = if walking { (self.cmd_velocity, self.cmd_yaw_speed) } else { ([0.0, 0.0], 0.0) };
+        let blend = (dt / 0.1).min(1.0); // ~100 ms to reach a commanded speed
+        for (v, target) in self.velocity.iter_mut().zip(target_v) {
+            *v += (target - *v) * blend;
+        }
+        self.yaw_speed += (target_yaw - self.yaw_speed) * blend;
+
+        // Body frame velocity into the world frame
+        let (sin, cos) = self.yaw.sin_cos();
+        self.position[0] += (self.velocity[0] * cos - self.velocity[1] * sin) * dt;
+        self.position[1] += (self.velocity[0] * sin + self.velocity[1] * cos) * dt;
+        self.yaw += self.yaw_speed * dt;
+
+        let target_height = match self.mode {
+            MotorModeHigh::StandDown | MotorModeHigh::Damping => 0.1,
+            _ => STAND_HEIGHT + self.cmd_body_height,
+        };
+        self.body_height += (target_height - self.body_height) * blend;
+
+        // Joints settle on their targets, damped motors just slow down where they are
+        let alpha = (dt / JOINT_TIME_CONSTANT).min(1.0);
+        for i in 0..12 {
+            let previous = self.q[i];
+            if !self.motor_damping[i] {
+                self.q[i] += (self.q_target[i] - self.q[i]) * alpha;
+            }
+            self.dq[i] = if dt > 0.0 { (self.q[i] - previous) / dt } else { 0.0 };
+        }
+    }
+
+    fn header(&self, frame: &mut [u8], level: Level) {
+        frame[0..2].copy_from_slice(&[0xFE, 0xEF]);
+        frame[2] = level as u8;
+        frame[4..12].copy_from_slice(&self.config.sn);
+        frame[12..20].copy_from_slice(&self.config.version);
+    }
+
+    fn imu(&self, data: &mut [u8]) {
+        let (half_sin, half_cos) = (self.yaw / 2.0).sin_cos();
+        let floats = [
+            half_cos, 0.0, 0.0, half_sin, // quaternion w x y z
+            0.0, 0.0, self.yaw_speed,     // gyroscope
+            0.0, 0.0, 9.81,               // accelerometer
+            0.0, 0.0, self.yaw,           // rpy
+        ];
+        for (i, value) in floats.iter().enumerate() {
+            data[i * 4..i * 4 + 4].copy_from_slice(&float_to_hex(*value));
+        }
+        data[52] = 30; // temperature
+    }
+
+    fn bms(&self, data: &mut [u8]) {
+        data[2] = 8; // status: ok
+        data[3] = self.config.battery;
+        data[4..8].copy_from_slice(&(-2000i32).to_le_bytes()); // discharging at 2 A
+        data[8..10].copy_from_slice(&42u16.to_le_bytes());
+        data[10..12].copy_from_slice(&[28, 28]);
+        data[12..14].copy_from_slice(&[32, 32]);
+    }
+
+    fn foot_force(&self) -> u16 {
+        if self.body_height > 0.2 { 120 } else { 20 }
+    }
+
+    fn seal(frame: &mut [u8]) {
+        let crc = frame_crc(frame);
+        let len = frame.len();
+        frame[len - 4..].copy_from_slice(&crc.to_le_bytes());
+    }
+
+    // 1087 byte HighState with a valid CRC
+    pub fn high_state(&self) -> Vec<u8> {
+        let mut state = HighState::new();
+        state.level_flag = Level::High as u8;
+        state.sn = self.config.sn;
+        state.version = self.config.version;
+        let mut imu = vec![0u8; 53];
+        self.imu(&mut imu);
+        state.imu = state.data_to_imu(&imu);
+        for (i, motor) in state.motor_state.iter_mut().take(12).enumerate() {
+            motor.mode = if self.motor_damping[i] { MotorModeLow::Damping as u8 } else { MotorModeLow::Servo as u8 };
+            motor.q = self.q[i];
+            motor.dq = self.dq[i];
+            motor.q_raw = self.q[i];
+            motor.dq_raw = self.dq[i];
+            motor.temperature = 35.0;
+        }
+        let mut bms = vec![0u8; 34];
+        self.bms(&mut bms);
+        state.bms = state.data_to_bms_state(&bms);
+        for (i, cell) in state.bms.cell_vol.iter_mut().enumerate() {
+            *cell = if i < 8 { 3700 + self.config.battery as u16 * 5 } else { 0 };
+        }
+        state.foot_force = [self.foot_force(); 4];
+        state.foot_force_est = [self.foot_force(); 4];
+        state.mode = self.mode as u8;
+        state.progress = 1.0;
+        state.gait_type = self.gait_type as u8;
+        state.position = [self.position[0], self.position[1], 0.0];
+        state.body_height = self.body_height;
+        state.velocity = [self.velocity[0], self.velocity[1], 0.0];
+        state.yaw_speed = self.yaw_speed;
+        state.build_state()
+    }
+
+    // 807 byte LowState with a valid CRC
+    pub fn low_state(&self) -> Vec<u8> {
+        let mut frame = vec![0u8; LOW_STATE_LEN];
+        self.header(&mut frame, Level::Low);
+        self.imu(&mut frame[22..75]);
+        for i in 0..12 {
+            let motor = &mut frame[75 + i * 32..107 + i * 32];
+            motor[0] = if self.motor_damping[i] { MotorModeLow::Damping as u8 } else { MotorModeLow::Servo as u8 };
+            motor[1..5].copy_from_slice(&float_to_hex(self.q[i]));
+            motor[5..9].copy_from_slice(&float_to_hex(self.dq[i]));
+            motor[13..17].copy_from_slice(&float_to_hex(self.q[i]));
+            motor[17..21].copy_from_slice(&float_to_hex(self.dq[i]));
+            motor[23] = 35;
+        }
+        self.bms(&mut frame[715..739]);
+        for cell in 14..22 {
+            frame[715 + cell] = ((3700 + self.config.battery as u16 * 5) / 32) as u8;
+        }
+        for leg in 0..4 {
+            frame[739 + leg * 2..741 + leg * 2].copy_from_slice(&self.foot_force().to_le_bytes());
+            frame[747 + leg * 2..749 + leg * 2].copy_from_slice(&self.foot_force().to_le_bytes());
+        }
+        frame[755..759].copy_from_slice(&self.tick.to_le_bytes());
+        Self::seal(&mut frame);
+        frame
+    }
+
+    // State of the level the robot is in
+    pub fn state(&self) -> Vec<u8> {
+        match self.level {
+            Level::High => self.high_state(),
+            Level::Low => self.low_state(),
+        }
+    }
+}
+
+// In-process use through SimTransport: SimTransport::new(MockRobot::new(..)).with_tick(STATE_PERIOD).
+// Like the dog it stays silent until the first command arrives.
+impl Simulator for MockRobot {
+    fn on_command(&mut self, cmd: &[u8]) -> Vec<Vec<u8>> {
+        self.handle_command(cmd);
+        Vec::new()
+    }
+
+    fn on_tick(&mut self) -> Vec<Vec<u8>> {
+        if self.is_booting() {
+            self.step(STATE_PERIOD);
+        }
+        if !self.is_streaming() {
+            return Vec::new();
+        }
+        self.step(STATE_PERIOD);
+        vec![self.state()]
+    }
+}
+
+// A MockRobot behind the dog's two UDP ports, what go1-mock runs. State goes to whoever sent
+// the last command, on the port of the level the robot is in. Tests bind it to 127.0.0.1 with
+// port 0 and point a profile at high_addr()/low_addr(). Stops when dropped.
+pub struct UdpMock {
+    pub robot: Arc<Mutex<MockRobot>>,
+    high_addr: SocketAddr,
+    low_addr: SocketAddr,
+    running: Arc<AtomicBool>,
+}
+
+impl UdpMock {
+    pub fn bind(ip: IpAddr, high_port: u16, low_port: u16, config: MockConfig) -> io::Result<Self> {
+        let high = UdpSocket::bind((ip, high_port))?;
+        let low = UdpSocket::bind((ip, low_port))?;
+        let (high_addr, low_addr) = (high.local_addr()?, low.local_addr()?);
+        let robot = Arc::new(Mutex::new(MockRobot::new(config)));
+        let running = Arc::new(AtomicBool::new(true));
+        let client: Arc<Mutex<Option<SocketAddr>>> = Arc::new(Mutex::new(None));
+
+        for socket in [&high, &low] {
+            let socket = socket.try_clone()?;
+            socket.set_read_timeout(Some(Duration::from_millis(50)))?;
+            let (robot, client, running) = (Arc::clone(&robot), Arc::clone(&client), Arc::clone(&running));
+            thread::spawn(move || {
+                let mut buffer = [0; 2048];
+                while running.load(Ordering::SeqCst) {
+                    let (size, from) = match socket.recv_from(&mut buffer) {
+                        Ok(received) => received,
+                        Err(_) => continue, // timeouts, ICMP errors from clients that went away
+                    };
+                    if robot.lock().unwrap().handle_command(&buffer[..size]) {
+                        *client.lock().unwrap() = Some(from);
+                    }
+                }
+            });
+        }
+
+        let (dog, stream) = (Arc::clone(&robot), Arc::clone(&running));
+        thread::spawn(move || {
+            let mut deadline = Instant::now();
+            while stream.load(Ordering::SeqCst) {
+                deadline += STATE_PERIOD;
+                let state = {
+                    let mut robot = dog.lock().unwrap();
+                    robot.step(STATE_PERIOD);
+                    if robot.is_streaming() { Some((robot.level, robot.state())) } else { None }
+                };
+                if let (Some((level, state)), Some(to)) = (state, *client.lock().unwrap()) {
+                    let socket = match level {
+                        Level::High => &high,
+                        Level::Low => &low,
+                    };
+                    let _ = socket.send_to(&state, to);
+                }
+                let now = Instant::now();
+                if deadline > now {
+                    thread::sleep(deadline - now);
+                } else {
+                    deadline = now;
+                }
+            }
+        });
+        Ok(UdpMock { robot, high_addr, low_addr, running })
+    }
+
+    pub fn high_addr(&self) -> SocketAddr {
+        self.high_addr
+    }
+
+    pub fn low_addr(&self) -> SocketAddr {
+        self.low_addr
+    }
+}
+
+impl Drop for UdpMock {
+    fn drop(&mut self) {
+        self.running.store(false, Ordering::SeqCst);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ucl::discovery::{classify_state, init_frame, HIGH_STATE_LEN};
+
+    fn booted(level: Level) -> MockRobot {
+        let mut robot = MockRobot::new(MockConfig { level, boot_time: Duration::from_millis(20), ..MockConfig::default() });
+        assert!(robot.handle_command(&init_frame(level)));
+        robot
+    }
+
+    #[test]
+    fn operator_switch_changes_the_streamed_level() {
+        let mut robot = booted(Level::High);
+        let state = robot.on_tick().pop().unwrap();
+        assert_eq!((state.len(), state[2], classify_state(&state)), (HIGH_STATE_LEN, 0x00, Some(Level::High)));
+
+        robot.operator_switch(Level::Low);
+        let state = robot.on_tick().pop().unwrap();
+        assert_eq!((state.len(), state[2], classify_state(&state)), (LOW_STATE_LEN, 0xff, Some(Level::Low)));
+        assert_eq!(robot.mode, MotorModeHigh::Damping);
+
+        // High level commands are ignored now, low level ones are taken
+        let commands = robot.commands_received();
+        robot.handle_command(&crate::ucl::highCmd::HighCmd::new().build_cmd(false));
+        assert_eq!(robot.mode, MotorModeHigh::Damping);
+        assert!(robot.handle_command(&init_frame(Level::Low)));
+        assert_eq!(robot.commands_received(), commands + 2);
+    }
+
+    #[test]
+    fn power_cycle_goes_quiet_and_comes_back_in_the_boot_level() {
+        let mut robot = booted(Level::High);
+        robot.operator_switch(Level::Low);
+        robot.power_cycle();
+        assert!(robot.is_booting());
+        assert!(!robot.handle_command(&init_frame(Level::High)));
+        for _ in 0..10 {
+            assert!(robot.on_tick().is_empty());
+        }
+
+        // Booted, but silent until a command says where to stream, then in the boot level
+        assert!(!robot.is_booting());
+        assert!(robot.on_tick().is_empty());
+        assert!(robot.handle_command(&init_frame(Level::Low)));
+        let state = robot.on_tick().pop().unwrap();
+        assert_eq!((state.len(), classify_state(&state)), (HIGH_STATE_LEN, Some(Level::High)));
+    }
+
+    #[test]
+    fn udp_mock_answers_on_localhost() {
+        let mock = UdpMock::bind(IpAddr::from([127, 0, 0, 1]), 0, 0, MockConfig::default()).unwrap();
+        let client = UdpSocket::bind("127.0.0.1:0").unwrap();
+        client.set_read_timeout(Some(Duration::from_millis(500))).unwrap();
+        client.send_to(&init_frame(Level::High), mock.high_addr()).unwrap();
+        let mut buffer = [0; 2048];
+        let (size, from) = client.recv_from(&mut buffer).unwrap();
+        assert_eq!((size, from), (HIGH_STATE_LEN, mock.high_addr()));
+
+        mock.robot.lock().unwrap().power_cycle();
+        client.set_read_timeout(Some(Duration::from_millis(50))).unwrap();
+        while client.recv_from(&mut buffer).is_ok() {} // what was on its way
+        client.send_to(&init_frame(Level::High), mock.high_addr()).unwrap();
+        assert!(client.recv_from(&mut buffer).is_err());
+    }
+}
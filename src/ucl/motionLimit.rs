@@ -0,0 +1,312 @@
+use std::time::Duration;
+use super::enums::{GaitType, SpeedLevel};
+use super::highCmd::HighCmd;
+
+// Symmetric maxima, e.g. vx 1.0 allows -1.0 to 1.0 m/s
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct MotionMax {
+    pub vx: f32,        // m/s, forward
+    pub vy: f32,        // m/s, sideways
+    pub yaw_speed: f32, // rad/s
+}
+
+impl MotionMax {
+    pub fn new(vx: f32, vy: f32, yaw_speed: f32) -> Self {
+        MotionMax { vx, vy, yaw_speed }
+    }
+
+    fn min(&self, other: &MotionMax) -> MotionMax {
+        MotionMax::new(self.vx.min(other.vx), self.vy.min(other.vy), self.yaw_speed.min(other.yaw_speed))
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct MotionLimitConfig {
+    pub speed_levels: [MotionMax; 3], // indexed by SpeedLevel
+    pub gaits: [MotionMax; 5],        // indexed by GaitType, the smaller of both applies
+    pub body_height: (f32, f32),      // m, offset from the default height
+    pub euler: [f32; 3],              // rad, roll pitch yaw
+    // Slew rates, how fast a target may change from one frame to the next
+    pub acceleration: f32,     // m/s^2, vx and vy
+    pub yaw_acceleration: f32, // rad/s^2
+    pub body_height_rate: f32, // m/s
+    pub euler_rate: f32,       // rad/s
+}
+
+impl Default for MotionLimitConfig {
+    // Go1 ranges from the official SDK documentation, a bit below what the dog can do
+    fn default() -> Self {
+        MotionLimitConfig {
+            speed_levels: [
+                MotionMax::new(0.5, 0.3, 1.0), // LowSpeed
+                MotionMax::new(1.0, 0.5, 2.0), // MediumSpeed
+                MotionMax::new(2.5, 1.0, 3.0), // HighSpeed
+            ],
+            gaits: [
+                MotionMax::new(1.5, 0.6, 2.5), // Idle, walks like Trot
+                MotionMax::new(1.5, 0.6, 2.5), // Trot
+                MotionMax::new(3.5, 1.0, 3.0), // TrotRunning
+                MotionMax::new(0.4, 0.2, 0.8), // ClimbStair
+                MotionMax::new(0.8, 0.4, 1.5), // TrotObstacle
+            ],
+            body_height: (-0.13, 0.03),
+            euler: [0.75, 0.75, 0.6],
+            acceleration: 2.0,
+            yaw_acceleration: 4.0,
+            body_height_rate: 0.2,
+            euler_rate: 1.5,
+        }
+    }
+}
+
+impl MotionLimitConfig {
+    pub fn max_motion(&self, speed_level: SpeedLevel, gait_type: GaitType) -> MotionMax {
+        self.speed_levels[speed_level as usize].min(&self.gaits[gait_type as usize])
+    }
+
+    // For speed levels or gaits this build does not know, e.g. from a decoded frame
+    pub fn slowest(&self) -> MotionMax {
+        self.speed_levels.iter().chain(self.gaits.iter()).fold(self.speed_levels[0], |slowest, max| slowest.min(max))
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HighField {
+    VelocityX,
+    VelocityY,
+    YawSpeed,
+    BodyHeight,
+    Roll,
+    Pitch,
+    Yaw,
+}
+
+const FIELDS: [HighField; 7] = [
+    HighField::VelocityX, HighField::VelocityY, HighField::YawSpeed, HighField::BodyHeight,
+    HighField::Roll, HighField::Pitch, HighField::Yaw,
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ClampReason {
+    Maximum,    // outside the range of the speed level, gait or body pose
+    SlewRate,   // changed faster than the configured rate, also ramps down after a lower maximum
+    NotANumber, // NaN, the previous value was kept
+}
+
+// One field of one frame that did not go out as requested
+#[derive(Debug, Clone, PartialEq)]
+pub struct ClampEvent {
+    pub frame: u64, // counts limit() calls
+    pub field: HighField,
+    pub requested: f32,
+    pub sent: f32,
+    pub reason: ClampReason,
+}
+
+// Sits between the application and build_cmd, limits and ramps the motion targets:
+//   let events = limiter.limit(&mut hcmd, period);
+#[derive(Debug, Clone)]
+pub struct MotionLimiter {
+    pub config: MotionLimitConfig,
+    last: [f32; 7], // what went out last frame, in FIELDS order
+    frame: u64,
+    clamped_frames: u64,
+}
+
+impl MotionLimiter {
+    pub fn new(config: MotionLimitConfig) -> Self {
+        MotionLimiter { config, last: [0.0; 7], frame: 0, clamped_frames: 0 }
+    }
+
+    // Ramp from standing still again, e.g. after a level switch or a stop
+    pub fn reset(&mut self) {
+        self.last = [0.0; 7];
+    }
+
+    pub fn frames(&self) -> u64 {
+        self.frame
+    }
+
+    // Frames in which at least one field was clamped
+    pub fn clamped_frames(&self) -> u64 {
+        self.clamped_frames
+    }
+
+    // Limit `cmd` in place, `dt` is the time since the previous frame
+    pub fn limit(&mut self, cmd: &mut HighCmd, dt: Duration) -> Vec<ClampEvent> {
+        self.frame += 1;
+        let dt = dt.as_secs_f32();
+        let max = match (cmd.speed_level(), cmd.gait_type()) {
+            (Ok(speed_level), Ok(gait_type)) => self.config.max_motion(speed_level, gait_type),
+            _ => self.config.slowest(),
+        };
+        let euler = self.config.euler;
+        let ranges = [
+            (-max.vx, max.vx),
+            (-max.vy, max.vy),
+            (-max.yaw_speed, max.yaw_speed),
+            self.config.body_height,
+            (-euler[0], euler[0]),
+            (-euler[1], euler[1]),
+            (-euler[2], euler[2]),
+        ];
+        let acceleration = self.config.acceleration;
+        let euler_rate = self.config.euler_rate;
+        let rates = [acceleration, acceleration, self.config.yaw_acceleration, self.config.body_height_rate, euler_rate, euler_rate, euler_rate];
+        let requested = [cmd.velocity[0], cmd.velocity[1], cmd.yaw_speed, cmd.body_height, cmd.euler[0], cmd.euler[1], cmd.euler[2]];
+
+        let mut sent = requested;
+        let mut events = Vec::new();
+        for i in 0..FIELDS.len() {
+            let (min, max) = ranges[i];
+            let step = rates[i] * dt;
+            let last = self.last[i];
+            let reason = if requested[i].is_nan() {
+                sent[i] = last;
+                Some(ClampReason::NotANumber)
+            } else {
+                let in_range = requested[i].clamp(min, max);
+                sent[i] = in_range.clamp(last - step, last + step);
+                if sent[i] != in_range {
+                    Some(ClampReason::SlewRate)
+                } else if in_range != requested[i] {
+                    Some(ClampReason::Maximum)
+                } else {
+                    None
+                }
+            };
+            if let Some(reason) = reason {
+                events.push(ClampEvent { frame: self.frame, field: FIELDS[i], requested: requested[i], sent: sent[i], reason });
+            }
+        }
+
+        cmd.velocity = [sent[0], sent[1]];
+        cmd.yaw_speed = sent[2];
+        cmd.body_height = sent[3];
+        cmd.euler = [sent[4], sent[5], sent[6]];
+        self.last = sent;
+        if !events.is_empty() {
+            self.clamped_frames += 1;
+        }
+        events
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn unknown_speed_level_or_gait_gets_the_slowest_limits() {
+        let config = MotionLimitConfig::default();
+        assert_eq!(config.slowest(), MotionMax::new(0.4, 0.2, 0.8));
+
+        let mut limiter = MotionLimiter::new(MotionLimitConfig { acceleration: 100.0, ..config });
+        let mut cmd = HighCmd::new();
+        cmd.set_speed_level(SpeedLevel::HighSpeed);
+        cmd.gait_type = 12;
+        cmd.velocity = [2.0, 0.0];
+        limiter.limit(&mut cmd, Duration::from_secs(1));
+        assert_eq!(cmd.velocity, [0.4, 0.0]);
+    }
+
+    // MediumSpeed trot, at most 1.0 m/s and 2.0 rad/s
+    fn trot(vx: f32, yaw_speed: f32) -> HighCmd {
+        let mut cmd = HighCmd::new();
+        cmd.set_speed_level(SpeedLevel::MediumSpeed);
+        cmd.set_gait_type(GaitType::Trot);
+        cmd.velocity = [vx, 0.0];
+        cmd.yaw_speed = yaw_speed;
+        cmd
+    }
+
+    fn reasons(events: &[ClampEvent]) -> Vec<(HighField, ClampReason)> {
+        events.iter().map(|event| (event.field, event.reason)).collect()
+    }
+
+    #[test]
+    fn slew_rate_ramps_up_and_down_over_several_frames() {
+        // 2 m/s^2 and 4 rad/s^2 over 125 ms: 0.25 m/s and 0.5 rad/s per frame
+        let mut limiter = MotionLimiter::new(MotionLimitConfig::default());
+        let dt = Duration::from_millis(125);
+        let mut sent = Vec::new();
+        for _ in 0..5 {
+            let mut cmd = trot(1.0, 1.0);
+            let events = limiter.limit(&mut cmd, dt);
+            sent.push((cmd.velocity[0], cmd.yaw_speed, reasons(&events)));
+        }
+        let ramping = |fields: &[HighField]| fields.iter().map(|field| (*field, ClampReason::SlewRate)).collect::<Vec<_>>();
+        assert_eq!(sent, [
+            (0.25, 0.5, ramping(&[HighField::VelocityX, HighField::YawSpeed])),
+            (0.5, 1.0, ramping(&[HighField::VelocityX])),
+            (0.75, 1.0, ramping(&[HighField::VelocityX])),
+            (1.0, 1.0, vec![]),
+            (1.0, 1.0, vec![]),
+        ]);
+        assert_eq!((limiter.frames(), limiter.clamped_frames()), (5, 3));
+
+        // Stopping ramps down just the same, twice the dt is twice the step
+        let mut cmd = trot(0.0, 0.0);
+        let events = limiter.limit(&mut cmd, dt * 2);
+        assert_eq!((cmd.velocity[0], cmd.yaw_speed), (0.5, 0.0));
+        assert_eq!(events[0], ClampEvent { frame: 6, field: HighField::VelocityX, requested: 0.0, sent: 0.5, reason: ClampReason::SlewRate });
+
+        limiter.reset();
+        let mut cmd = trot(1.0, 0.0);
+        limiter.limit(&mut cmd, dt);
+        assert_eq!(cmd.velocity[0], 0.25);
+    }
+
+    #[test]
+    fn maximum_clamps_to_the_speed_level_gait_and_pose() {
+        let config = MotionLimitConfig { acceleration: 100.0, yaw_acceleration: 100.0, body_height_rate: 100.0, euler_rate: 100.0, ..MotionLimitConfig::default() };
+        let mut limiter = MotionLimiter::new(config);
+        let mut cmd = trot(3.0, -5.0);
+        cmd.set_speed_level(SpeedLevel::LowSpeed);
+        cmd.velocity[1] = 0.2;
+        cmd.body_height = -0.5;
+        cmd.euler = [2.0, 0.1, 0.0];
+        let events = limiter.limit(&mut cmd, Duration::from_secs(1));
+
+        assert_eq!((cmd.velocity, cmd.yaw_speed, cmd.body_height, cmd.euler), ([0.5, 0.2], -1.0, -0.13, [0.75, 0.1, 0.0]));
+        assert_eq!(reasons(&events), [
+            (HighField::VelocityX, ClampReason::Maximum),
+            (HighField::YawSpeed, ClampReason::Maximum),
+            (HighField::BodyHeight, ClampReason::Maximum),
+            (HighField::Roll, ClampReason::Maximum),
+        ]);
+        assert_eq!((events[0].requested, events[0].sent), (3.0, 0.5));
+
+        // A trot tops out below HighSpeed's 2.5 m/s
+        let mut cmd = trot(3.0, 0.0);
+        cmd.set_speed_level(SpeedLevel::HighSpeed);
+        limiter.limit(&mut cmd, Duration::from_secs(1));
+        assert_eq!(cmd.velocity[0], 1.5);
+    }
+
+    #[test]
+    fn nan_keeps_the_last_value_and_a_lower_maximum_ramps_down() {
+        let mut limiter = MotionLimiter::new(MotionLimitConfig::default());
+        let dt = Duration::from_millis(125);
+        for _ in 0..4 {
+            limiter.limit(&mut trot(1.0, 0.0), dt);
+        }
+
+        let mut cmd = trot(f32::NAN, 0.0);
+        let events = limiter.limit(&mut cmd, dt);
+        assert_eq!(cmd.velocity[0], 1.0);
+        assert_eq!(reasons(&events), [(HighField::VelocityX, ClampReason::NotANumber)]);
+        assert!(events[0].requested.is_nan());
+
+        // Down to LowSpeed, 0.5 m/s: slewing, not jumping
+        let mut cmd = trot(1.0, 0.0);
+        cmd.set_speed_level(SpeedLevel::LowSpeed);
+        let events = limiter.limit(&mut cmd, dt);
+        assert_eq!(cmd.velocity[0], 0.75);
+        assert_eq!(reasons(&events), [(HighField::VelocityX, ClampReason::SlewRate)]);
+        cmd.velocity[0] = 1.0;
+        let events = limiter.limit(&mut cmd, dt);
+        assert_eq!(cmd.velocity[0], 0.5);
+        assert_eq!(reasons(&events), [(HighField::VelocityX, ClampReason::Maximum)]);
+    }
+}
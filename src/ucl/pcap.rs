@@ -0,0 +1,544 @@
+use std::fs;
+use std::io::{self, Write};
+use std::net::{Ipv4Addr, SocketAddrV4};
+use std::path::Path;
+use std::time::Duration;
+use super::common::byte_print;
+use super::discovery::{HIGH_STATE_LEN, LOW_STATE_LEN};
+use super::highCmd::HighCmd;
+use super::highState::HighState;
+use super::lowCmd::LowCmd;
+use super::lowState::LowState;
+use super::recording::Recording;
+use super::unitreeConnection::{LISTEN_PORT, SEND_PORT_HIGH, SEND_PORT_LOW};
+
+// UDP ports the dog talks on, a datagram is kept if either side uses one of them
+pub const GO1_PORTS: [u16; 3] = [SEND_PORT_LOW, SEND_PORT_HIGH, LISTEN_PORT];
+
+const HIGH_CMD_LEN: usize = 129;
+const LOW_CMD_LEN: usize = 614;
+const CMD_HEAD: [u8; 2] = [0xFE, 0xEF];
+
+// Link layer types we can strip down to IPv4
+const LINKTYPE_NULL: u32 = 0;
+const LINKTYPE_ETHERNET: u32 = 1;
+const LINKTYPE_RAW: u32 = 101;
+const LINKTYPE_RAW_OLD: u32 = 12;
+const LINKTYPE_LINUX_SLL: u32 = 113;
+const LINKTYPE_LINUX_SLL2: u32 = 276;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FrameKind {
+    HighCmd,
+    HighState,
+    LowCmd,
+    LowState,
+    Unknown,
+}
+
+// Classify a Go1 payload by its length and head
+pub fn classify_frame(data: &[u8]) -> FrameKind {
+    match data.len() {
+        HIGH_CMD_LEN if data[..2] == CMD_HEAD => FrameKind::HighCmd,
+        LOW_CMD_LEN if data[..2] == CMD_HEAD => FrameKind::LowCmd,
+        HIGH_STATE_LEN => FrameKind::HighState,
+        LOW_STATE_LEN => FrameKind::LowState,
+        _ => FrameKind::Unknown,
+    }
+}
+
+#[derive(Debug, Clone)]
+pub enum Frame {
+    HighCmd(Box<HighCmd>),
+    HighState(Box<HighState>),
+    LowCmd(Box<LowCmd>),
+    LowState(Box<LowState>),
+    Unknown(Vec<u8>),
+}
+
+pub fn decode_frame(data: &[u8]) -> Frame {
+    match classify_frame(data) {
+        FrameKind::HighCmd => match HighCmd::from_bytes(data) {
+            Ok(hcmd) => Frame::HighCmd(Box::new(hcmd)),
+            Err(_) => Frame::Unknown(data.to_vec()), // right size and head but a bad CRC
+        },
+        FrameKind::HighState => {
+            let mut hstate = HighState::new();
+            hstate.parse_data(data);
+            Frame::HighState(Box::new(hstate))
+        }
+        FrameKind::LowCmd => match LowCmd::from_bytes(data) {
+            Ok(lcmd) => Frame::LowCmd(Box::new(lcmd)),
+            Err(_) => Frame::Unknown(data.to_vec()),
+        },
+        FrameKind::LowState => {
+            let mut lstate = LowState::new();
+            lstate.parse_data(data);
+            Frame::LowState(Box::new(lstate))
+        }
+        FrameKind::Unknown => Frame::Unknown(data.to_vec()),
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct CapturedFrame {
+    pub timestamp: Duration, // capture time since the unix epoch
+    pub src: SocketAddrV4,
+    pub dst: SocketAddrV4,
+    pub kind: FrameKind,
+    pub payload: Vec<u8>,
+    pub frame: Frame,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct ImportStats {
+    pub packets: usize,     // link layer packets in the capture
+    pub not_udp: usize,     // not IPv4/UDP, or an unsupported link type
+    pub other_ports: usize, // UDP, but not on a Go1 port
+    pub fragmented: usize,  // IP fragments, not reassembled
+    pub truncated: usize,   // cut off by the capture snaplen
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Timeline {
+    pub frames: Vec<CapturedFrame>,
+    pub stats: ImportStats,
+}
+
+impl Timeline {
+    pub fn iter(&self) -> std::slice::Iter<'_, CapturedFrame> {
+        self.frames.iter()
+    }
+
+    pub fn of_kind(&self, kind: FrameKind) -> impl Iterator<Item = &CapturedFrame> {
+        self.frames.iter().filter(move |f| f.kind == kind)
+    }
+
+    // One line per frame: seconds since the first frame, addresses, kind, length, payload
+    pub fn write_csv<W: Write>(&self, out: &mut W) -> io::Result<()> {
+        writeln!(out, "time,src,dst,kind,len,payload")?;
+        let start = self.frames.first().map(|f| f.timestamp).unwrap_or_default();
+        for f in self.frames.iter() {
+            let t = f.timestamp.saturating_sub(start).as_secs_f64();
+            writeln!(out, "{:.6},{},{},{:?},{},{}", t, f.src, f.dst, f.kind, f.payload.len(), byte_print(&f.payload))?;
+        }
+        Ok(())
+    }
+
+    // Commands become sent and states received datagrams, so a capture can be fed to ReplayConnection
+    pub fn to_recording(&self) -> Recording {
+        let mut recording = Recording::default();
+        let start = self.frames.first().map(|f| f.timestamp).unwrap_or_default();
+        recording.started_unix_ms = start.as_millis() as u64;
+        for f in self.frames.iter() {
+            let t = f.timestamp.saturating_sub(start);
+            match f.kind {
+                FrameKind::HighCmd | FrameKind::LowCmd => recording.tx.push((t, f.payload.clone())),
+                FrameKind::HighState | FrameKind::LowState => {
+                    if recording.sn.is_none() {
+                        recording.sn = Some(f.payload[4..12].to_vec());
+                        recording.version = Some(f.payload[12..20].to_vec());
+                    }
+                    recording.rx.push((t, f.payload.clone()));
+                }
+                FrameKind::Unknown => {}
+            }
+        }
+        recording
+    }
+}
+
+// Read a pcap or pcapng file, the format is detected from the magic number
+pub fn read_capture(path: &Path) -> io::Result<Timeline> {
+    parse_capture(&fs::read(path)?)
+}
+
+pub fn parse_capture(data: &[u8]) -> io::Result<Timeline> {
+    let mut timeline = Timeline::default();
+    let mut on_packet = |linktype: u32, timestamp: Duration, packet: &[u8], truncated: bool| {
+        timeline.stats.packets += 1;
+        if truncated {
+            timeline.stats.truncated += 1;
+            return;
+        }
+        match udp_payload(linktype, packet) {
+            Udp::Datagram(src, dst, payload) => {
+                if !GO1_PORTS.contains(&src.port()) && !GO1_PORTS.contains(&dst.port()) {
+                    timeline.stats.other_ports += 1;
+                    return;
+                }
+                let kind = classify_frame(payload);
+                timeline.frames.push(CapturedFrame { timestamp, src, dst, kind, payload: payload.to_vec(), frame: decode_frame(payload) });
+            }
+            Udp::Fragment => timeline.stats.fragmented += 1,
+            Udp::Other => timeline.stats.not_udp += 1,
+        }
+    };
+
+    match data.get(0..4) {
+        Some([0x0a, 0x0d, 0x0d, 0x0a]) => parse_pcapng(data, &mut on_packet)?,
+        Some(_) => parse_pcap(data, &mut on_packet)?,
+        None => return Err(invalid("file too short")),
+    }
+    timeline.frames.sort_by_key(|f| f.timestamp);
+    Ok(timeline)
+}
+
+fn invalid(what: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, what.to_string())
+}
+
+// Byte order aware readers, pcap and pcapng are written in the capturing host's order
+#[derive(Clone, Copy)]
+struct Endian {
+    big: bool,
+}
+
+impl Endian {
+    fn u16(&self, data: &[u8], at: usize) -> io::Result<u16> {
+        let bytes: [u8; 2] = data.get(at..at + 2).ok_or_else(|| invalid("unexpected end of file"))?.try_into().unwrap();
+        Ok(if self.big { u16::from_be_bytes(bytes) } else { u16::from_le_bytes(bytes) })
+    }
+
+    fn u32(&self, data: &[u8], at: usize) -> io::Result<u32> {
+        let bytes: [u8; 4] = data.get(at..at + 4).ok_or_else(|| invalid("unexpected end of file"))?.try_into().unwrap();
+        Ok(if self.big { u32::from_be_bytes(bytes) } else { u32::from_le_bytes(bytes) })
+    }
+}
+
+fn parse_pcap<F>(data: &[u8], on_packet: &mut F) -> io::Result<()>
+where
+    F: FnMut(u32, Duration, &[u8], bool),
+{
+    let magic = data.get(0..4).ok_or_else(|| invalid("file too short"))?;
+    let (endian, nanos) = match magic {
+        [0xd4, 0xc3, 0xb2, 0xa1] => (Endian { big: false }, false),
+        [0xa1, 0xb2, 0xc3, 0xd4] => (Endian { big: true }, false),
+        [0x4d, 0x3c, 0xb2, 0xa1] => (Endian { big: false }, true),
+        [0xa1, 0xb2, 0x3c, 0x4d] => (Endian { big: true }, true),
+        _ => return Err(invalid("not a pcap or pcapng file")),
+    };
+    let linktype = endian.u32(data, 20)? & 0x0fff_ffff; // upper bits carry FCS info
+    let mut at = 24;
+    while at + 16 <= data.len() {
+        let secs = endian.u32(data, at)? as u64;
+        let frac = endian.u32(data, at + 4)? as u64;
+        let incl_len = endian.u32(data, at + 8)? as usize;
+        let orig_len = endian.u32(data, at + 12)? as usize;
+        let packet = data.get(at + 16..at + 16 + incl_len).ok_or_else(|| invalid("truncated packet record"))?;
+        let timestamp = Duration::from_secs(secs) + if nanos { Duration::from_nanos(frac) } else { Duration::from_micros(frac) };
+        on_packet(linktype, timestamp, packet, incl_len < orig_len);
+        at += 16 + incl_len;
+    }
+    Ok(())
+}
+
+struct Interface {
+    linktype: u32,
+    tsresol: Tsresol,
+}
+
+#[derive(Clone, Copy)]
+enum Tsresol {
+    Decimal(u32), // 10^-n seconds per unit
+    Binary(u32),  // 2^-n seconds per unit
+}
+
+impl Tsresol {
+    fn to_duration(self, units: u64) -> Duration {
+        match self {
+            Tsresol::Decimal(n) => {
+                let per_sec = 10u64.pow(n.min(19));
+                let nanos = (units % per_sec) as u128 * 1_000_000_000 / per_sec as u128;
+                Duration::new(units / per_sec, nanos as u32)
+            }
+            Tsresol::Binary(n) => {
+                let n = n.min(63);
+                let nanos = ((units & ((1u64 << n) - 1)) as u128 * 1_000_000_000) >> n;
+                Duration::new(units >> n, nanos as u32)
+            }
+        }
+    }
+}
+
+fn parse_pcapng<F>(data: &[u8], on_packet: &mut F) -> io::Result<()>
+where
+    F: FnMut(u32, Duration, &[u8], bool),
+{
+    let mut endian = Endian { big: false };
+    let mut interfaces: Vec<Interface> = Vec::new();
+    let mut at = 0;
+
+    while at + 12 <= data.len() {
+        // The section header decides the byte order of everything up to the next one
+        if data[at..at + 4] == [0x0a, 0x0d, 0x0d, 0x0a] {
+            endian = match data.get(at + 8..at + 12) {
+                Some([0x4d, 0x3c, 0x2b, 0x1a]) => Endian { big: false },
+                Some([0x1a, 0x2b, 0x3c, 0x4d]) => Endian { big: true },
+                _ => return Err(invalid("bad pcapng byte order magic")),
+            };
+            interfaces.clear();
+        }
+        let block_type = endian.u32(data, at)?;
+        let block_len = endian.u32(data, at + 4)? as usize;
+        if block_len < 12 || at + block_len > data.len() {
+            return Err(invalid("bad pcapng block length"));
+        }
+        let body = &data[at + 8..at + block_len - 4];
+
+        match block_type {
+            // Interface Description Block
+            0x0000_0001 => {
+                let linktype = endian.u16(body, 0)? as u32;
+                let tsresol = interface_tsresol(endian, body.get(8..).unwrap_or(&[]))?;
+                interfaces.push(Interface { linktype, tsresol });
+            }
+            // Enhanced Packet Block
+            0x0000_0006 => {
+                let iface = interfaces.get(endian.u32(body, 0)? as usize).ok_or_else(|| invalid("packet for unknown interface"))?;
+                let units = ((endian.u32(body, 4)? as u64) << 32) | endian.u32(body, 8)? as u64;
+                let captured = endian.u32(body, 12)? as usize;
+                let original = endian.u32(body, 16)? as usize;
+                let packet = body.get(20..20 + captured).ok_or_else(|| invalid("truncated enhanced packet block"))?;
+                on_packet(iface.linktype, iface.tsresol.to_duration(units), packet, captured < original);
+            }
+            // Simple Packet Block, no timestamp and always interface 0
+            0x0000_0003 => {
+                let iface = interfaces.first().ok_or_else(|| invalid("packet for unknown interface"))?;
+                let original = endian.u32(body, 0)? as usize;
+                let captured = original.min(body.len() - 4);
+                on_packet(iface.linktype, Duration::ZERO, &body[4..4 + captured], captured < original);
+            }
+            // Obsolete Packet Block
+            0x0000_0002 => {
+                let iface = interfaces.get(endian.u16(body, 0)? as usize).ok_or_else(|| invalid("packet for unknown interface"))?;
+                let units = ((endian.u32(body, 4)? as u64) << 32) | endian.u32(body, 8)? as u64;
+                let captured = endian.u32(body, 12)? as usize;
+                let original = endian.u32(body, 16)? as usize;
+                let packet = body.get(20..20 + captured).ok_or_else(|| invalid("truncated packet block"))?;
+                on_packet(iface.linktype, iface.tsresol.to_duration(units), packet, captured < original);
+            }
+            _ => {}
+        }
+        at += block_len;
+    }
+    Ok(())
+}
+
+// Look for the if_tsresol option, the default is microseconds
+fn interface_tsresol(endian: Endian, options: &[u8]) -> io::Result<Tsresol> {
+    let mut at = 0;
+    while at + 4 <= options.len() {
+        let code = endian.u16(options, at)?;
+        let len = endian.u16(options, at + 2)? as usize;
+        if code == 0 {
+            break;
+        }
+        if code == 9 && len >= 1 {
+            let value = *options.get(at + 4).ok_or_else(|| invalid("truncated if_tsresol option"))?;
+            return Ok(if value & 0x80 != 0 { Tsresol::Binary((value & 0x7f) as u32) } else { Tsresol::Decimal(value as u32) });
+        }
+        at += 4 + ((len + 3) & !3);
+    }
+    Ok(Tsresol::Decimal(6))
+}
+
+enum Udp<'a> {
+    Datagram(SocketAddrV4, SocketAddrV4, &'a [u8]),
+    Fragment,
+    Other, // not IPv4/UDP
+}
+
+fn udp_payload(linktype: u32, packet: &[u8]) -> Udp<'_> {
+    let ip = match linktype {
+        LINKTYPE_ETHERNET => {
+            let mut offset = 12;
+            let mut ethertype = packet.get(12..14).map(|b| u16::from_be_bytes([b[0], b[1]]));
+            // 802.1Q / 802.1ad tags
+            while ethertype == Some(0x8100) || ethertype == Some(0x88a8) {
+                offset += 4;
+                ethertype = packet.get(offset..offset + 2).map(|b| u16::from_be_bytes([b[0], b[1]]));
+            }
+            match ethertype {
+                Some(0x0800) => &packet[offset + 2..],
+                _ => return Udp::Other,
+            }
+        }
+        LINKTYPE_RAW | LINKTYPE_RAW_OLD => packet,
+        LINKTYPE_LINUX_SLL => match packet.get(14..16) {
+            Some([0x08, 0x00]) => &packet[16..],
+            _ => return Udp::Other,
+        },
+        LINKTYPE_LINUX_SLL2 => match packet.get(0..2) {
+            Some([0x08, 0x00]) if packet.len() >= 20 => &packet[20..],
+            _ => return Udp::Other,
+        },
+        LINKTYPE_NULL => match packet.get(0..4) {
+            // AF_INET in whatever byte order the capturing host used
+            Some([2, 0, 0, 0]) | Some([0, 0, 0, 2]) => &packet[4..],
+            _ => return Udp::Other,
+        },
+        _ => return Udp::Other,
+    };
+
+    if ip.len() < 20 || ip[0] >> 4 != 4 {
+        return Udp::Other;
+    }
+    let header_len = (ip[0] & 0x0f) as usize * 4;
+    let total_len = u16::from_be_bytes([ip[2], ip[3]]) as usize;
+    let flags_offset = u16::from_be_bytes([ip[6], ip[7]]);
+    if ip[9] != 17 || header_len < 20 || total_len > ip.len() || total_len < header_len + 8 {
+        return Udp::Other;
+    }
+    if flags_offset & 0x3fff != 0 {
+        return Udp::Fragment; // more fragments flag or non-zero offset
+    }
+    let src_ip = Ipv4Addr::new(ip[12], ip[13], ip[14], ip[15]);
+    let dst_ip = Ipv4Addr::new(ip[16], ip[17], ip[18], ip[19]);
+
+    let udp = &ip[header_len..total_len];
+    let src_port = u16::from_be_bytes([udp[0], udp[1]]);
+    let dst_port = u16::from_be_bytes([udp[2], udp[3]]);
+    let udp_len = (u16::from_be_bytes([udp[4], udp[5]]) as usize).clamp(8, udp.len());
+    Udp::Datagram(SocketAddrV4::new(src_ip, src_port), SocketAddrV4::new(dst_ip, dst_port), &udp[8..udp_len])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DOG: [u8; 4] = [192, 168, 123, 10];
+    const PC: [u8; 4] = [192, 168, 123, 14];
+
+    // Ethernet + IPv4 + UDP around `payload`, checksums left at zero like offloading NICs do
+    fn udp_packet(src: ([u8; 4], u16), dst: ([u8; 4], u16), payload: &[u8]) -> Vec<u8> {
+        let mut packet = vec![0; 12];
+        packet.extend_from_slice(&[0x08, 0x00]);
+        let total_len = (20 + 8 + payload.len()) as u16;
+        packet.extend_from_slice(&[0x45, 0, (total_len >> 8) as u8, total_len as u8, 0, 0, 0x40, 0, 64, 17, 0, 0]);
+        packet.extend_from_slice(&src.0);
+        packet.extend_from_slice(&dst.0);
+        packet.extend_from_slice(&src.1.to_be_bytes());
+        packet.extend_from_slice(&dst.1.to_be_bytes());
+        packet.extend_from_slice(&((8 + payload.len()) as u16).to_be_bytes());
+        packet.extend_from_slice(&[0, 0]);
+        packet.extend_from_slice(payload);
+        packet
+    }
+
+    fn pcap(records: &[(u32, u32, Vec<u8>, usize)]) -> Vec<u8> {
+        let mut data = vec![0xd4, 0xc3, 0xb2, 0xa1, 2, 0, 4, 0];
+        data.extend_from_slice(&[0; 8]);
+        data.extend_from_slice(&65535u32.to_le_bytes());
+        data.extend_from_slice(&LINKTYPE_ETHERNET.to_le_bytes());
+        for (secs, micros, packet, orig_len) in records {
+            data.extend_from_slice(&secs.to_le_bytes());
+            data.extend_from_slice(&micros.to_le_bytes());
+            data.extend_from_slice(&(packet.len() as u32).to_le_bytes());
+            data.extend_from_slice(&(*orig_len as u32).to_le_bytes());
+            data.extend_from_slice(packet);
+        }
+        data
+    }
+
+    fn block(block_type: u32, body: &[u8]) -> Vec<u8> {
+        let mut body = body.to_vec();
+        body.resize((body.len() + 3) & !3, 0);
+        let len = (body.len() + 12) as u32;
+        let mut data = block_type.to_le_bytes().to_vec();
+        data.extend_from_slice(&len.to_le_bytes());
+        data.extend_from_slice(&body);
+        data.extend_from_slice(&len.to_le_bytes());
+        data
+    }
+
+    // Section header, one Ethernet interface with nanosecond timestamps, then enhanced packets
+    fn pcapng(packets: &[(u64, Vec<u8>)]) -> Vec<u8> {
+        let mut shb = vec![0x4d, 0x3c, 0x2b, 0x1a, 1, 0, 0, 0];
+        shb.extend_from_slice(&[0xff; 8]);
+        let mut data = block(0x0a0d_0d0a, &shb);
+
+        let mut idb = (LINKTYPE_ETHERNET as u16).to_le_bytes().to_vec();
+        idb.extend_from_slice(&[0, 0, 0, 0, 0, 0]);
+        idb.extend_from_slice(&[9, 0, 1, 0, 9, 0, 0, 0, 0, 0, 0, 0]); // if_tsresol = 9, end of options
+        data.extend(block(1, &idb));
+
+        for (nanos, packet) in packets {
+            let mut epb = 0u32.to_le_bytes().to_vec();
+            epb.extend_from_slice(&((nanos >> 32) as u32).to_le_bytes());
+            epb.extend_from_slice(&(*nanos as u32).to_le_bytes());
+            epb.extend_from_slice(&(packet.len() as u32).to_le_bytes());
+            epb.extend_from_slice(&(packet.len() as u32).to_le_bytes());
+            epb.extend_from_slice(packet);
+            data.extend(block(6, &epb));
+        }
+        data
+    }
+
+    #[test]
+    fn pcap_classifies_and_decodes_go1_frames() {
+        let low = LowCmd::damping().build_cmd(false);
+        let high = HighCmd::new().build_cmd(false);
+        let state = vec![0; LOW_STATE_LEN];
+        let data = pcap(&[
+            (10, 500_000, udp_packet((PC, LISTEN_PORT), (DOG, SEND_PORT_LOW), &low), 0),
+            (10, 0, udp_packet((PC, LISTEN_PORT), (DOG, SEND_PORT_HIGH), &high), 0),
+            (10, 750_000, udp_packet((DOG, SEND_PORT_LOW), (PC, LISTEN_PORT), &state), 0),
+            (11, 0, udp_packet((PC, 5000), (DOG, 6000), &[1, 2, 3]), 0),
+            (12, 0, udp_packet((PC, LISTEN_PORT), (DOG, SEND_PORT_LOW), &low)[..60].to_vec(), 656),
+        ]);
+
+        let timeline = parse_capture(&data).unwrap();
+        assert_eq!(timeline.stats.packets, 5);
+        assert_eq!(timeline.stats.other_ports, 1);
+        assert_eq!(timeline.stats.truncated, 1);
+
+        let kinds: Vec<FrameKind> = timeline.iter().map(|f| f.kind).collect();
+        assert_eq!(kinds, [FrameKind::HighCmd, FrameKind::LowCmd, FrameKind::LowState]);
+        assert_eq!(timeline.frames[1].timestamp, Duration::from_micros(10_500_000));
+        assert_eq!(timeline.frames[1].dst, SocketAddrV4::new(Ipv4Addr::from(DOG), SEND_PORT_LOW));
+        match &timeline.frames[1].frame {
+            Frame::LowCmd(lcmd) => assert!(lcmd.encrypt),
+            other => panic!("expected a LowCmd, got {:?}", other),
+        }
+        assert!(matches!(timeline.frames[0].frame, Frame::HighCmd(_)));
+    }
+
+    #[test]
+    fn pcapng_uses_the_interface_resolution() {
+        let mut high = HighCmd::new();
+        high.encrypt = true;
+        let frame = high.build_cmd(false);
+        let mut corrupt = frame.clone();
+        corrupt[30] ^= 0xff;
+        let data = pcapng(&[
+            (1_500_000_001, udp_packet((PC, LISTEN_PORT), (DOG, SEND_PORT_HIGH), &frame)),
+            (1_500_000_002, udp_packet((PC, LISTEN_PORT), (DOG, SEND_PORT_HIGH), &corrupt)),
+        ]);
+
+        let timeline = parse_capture(&data).unwrap();
+        assert_eq!(timeline.frames.len(), 2);
+        assert_eq!(timeline.frames[0].timestamp, Duration::new(1, 500_000_001));
+        match &timeline.frames[0].frame {
+            Frame::HighCmd(hcmd) => assert!(hcmd.encrypt),
+            other => panic!("expected a HighCmd, got {:?}", other),
+        }
+        // Right size and head, but the CRC does not match
+        assert_eq!(timeline.frames[1].kind, FrameKind::HighCmd);
+        assert!(matches!(timeline.frames[1].frame, Frame::Unknown(_)));
+    }
+
+    #[test]
+    fn rejects_garbage() {
+        assert!(parse_capture(&[1, 2]).is_err());
+        assert!(parse_capture(&[0; 32]).is_err());
+
+        // if_tsresol claims a value but the interface block ends right after the option header
+        let mut data = pcapng(&[]);
+        data.truncate(data.len() - 32); // keep the section header, drop its interface block
+        let mut idb = (LINKTYPE_ETHERNET as u16).to_le_bytes().to_vec();
+        idb.extend_from_slice(&[0, 0, 0, 0, 0, 0, 9, 0, 1, 0]);
+        data.extend(block(1, &idb));
+        assert_eq!(parse_capture(&data).unwrap_err().kind(), io::ErrorKind::InvalidData);
+    }
+}
@@ -0,0 +1,194 @@
+use std::fmt;
+use std::io;
+
+// Real-time settings for a single thread. Everything is opt-in, the default changes nothing.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct RealtimeConfig {
+    pub fifo_priority: Option<i32>, // SCHED_FIFO priority, 1 (lowest) to 99 (highest)
+    pub cpu: Option<usize>,         // pin the thread to this CPU, ideally one given to isolcpus=
+    pub lock_memory: bool,          // mlockall(MCL_CURRENT | MCL_FUTURE) for the whole process
+}
+
+impl RealtimeConfig {
+    pub fn fifo(priority: i32) -> Self {
+        RealtimeConfig { fifo_priority: Some(priority), ..Default::default() }
+    }
+
+    pub fn on_cpu(mut self, cpu: usize) -> Self {
+        self.cpu = Some(cpu);
+        self
+    }
+
+    pub fn with_locked_memory(mut self) -> Self {
+        self.lock_memory = true;
+        self
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.fifo_priority.is_none() && self.cpu.is_none() && !self.lock_memory
+    }
+}
+
+#[derive(Debug)]
+pub enum RealtimeError {
+    PermissionDenied(&'static str), // missing CAP_SYS_NICE / CAP_IPC_LOCK or rlimit too low
+    InvalidArgument(&'static str, String),
+    Os(&'static str, io::Error),
+    Unsupported(&'static str),
+}
+
+impl fmt::Display for RealtimeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RealtimeError::PermissionDenied(what) => write!(
+                f,
+                "{}: permission denied (needs CAP_SYS_NICE/CAP_IPC_LOCK or matching rtprio/memlock limits), continuing without it",
+                what
+            ),
+            RealtimeError::InvalidArgument(what, reason) => write!(f, "{}: {}", what, reason),
+            RealtimeError::Os(what, err) => write!(f, "{}: {}", what, err),
+            RealtimeError::Unsupported(what) => write!(f, "{}: not supported on this platform", what),
+        }
+    }
+}
+
+impl std::error::Error for RealtimeError {}
+
+// Apply `config` to the calling thread. Every setting is attempted independently so a
+// missing capability for one does not prevent the others; the failures are returned.
+pub fn apply_to_current_thread(config: &RealtimeConfig) -> Vec<RealtimeError> {
+    let mut errors = Vec::new();
+    if config.lock_memory {
+        if let Err(err) = lock_memory() {
+            errors.push(err);
+        }
+    }
+    if let Some(cpu) = config.cpu {
+        if let Err(err) = pin_to_cpu(cpu) {
+            errors.push(err);
+        }
+    }
+    if let Some(priority) = config.fifo_priority {
+        if let Err(err) = set_fifo_priority(priority) {
+            errors.push(err);
+        }
+    }
+    errors
+}
+
+// Same as apply_to_current_thread, but only reports the failures on stderr
+pub fn apply_or_warn(config: &RealtimeConfig, thread_name: &str) {
+    for err in apply_to_current_thread(config) {
+        eprintln!("[realtime] {} thread: {}", thread_name, err);
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn os_error(what: &'static str) -> RealtimeError {
+    let err = io::Error::last_os_error();
+    match err.raw_os_error() {
+        Some(libc::EPERM) | Some(libc::EACCES) => RealtimeError::PermissionDenied(what),
+        _ => RealtimeError::Os(what, err),
+    }
+}
+
+#[cfg(target_os = "linux")]
+pub fn set_fifo_priority(priority: i32) -> Result<(), RealtimeError> {
+    let (min, max) = unsafe { (libc::sched_get_priority_min(libc::SCHED_FIFO), libc::sched_get_priority_max(libc::SCHED_FIFO)) };
+    if priority < min || priority > max {
+        return Err(RealtimeError::InvalidArgument("SCHED_FIFO", format!("priority {} outside {}-{}", priority, min, max)));
+    }
+    let param = libc::sched_param { sched_priority: priority };
+    // pthread_setschedparam returns the error code instead of setting errno
+    let ret = unsafe { libc::pthread_setschedparam(libc::pthread_self(), libc::SCHED_FIFO, &param) };
+    match ret {
+        0 => Ok(()),
+        libc::EPERM => Err(RealtimeError::PermissionDenied("SCHED_FIFO")),
+        code => Err(RealtimeError::Os("SCHED_FIFO", io::Error::from_raw_os_error(code))),
+    }
+}
+
+#[cfg(target_os = "linux")]
+pub fn pin_to_cpu(cpu: usize) -> Result<(), RealtimeError> {
+    if cpu >= libc::CPU_SETSIZE as usize {
+        return Err(RealtimeError::InvalidArgument("CPU affinity", format!("cpu {} out of range", cpu)));
+    }
+    let ret = unsafe {
+        let mut set: libc::cpu_set_t = std::mem::zeroed();
+        libc::CPU_SET(cpu, &mut set);
+        libc::sched_setaffinity(0, std::mem::size_of::<libc::cpu_set_t>(), &set)
+    };
+    if ret != 0 {
+        return match io::Error::last_os_error().raw_os_error() {
+            Some(libc::EINVAL) => Err(RealtimeError::InvalidArgument("CPU affinity", format!("cpu {} is not available", cpu))),
+            _ => Err(os_error("CPU affinity")),
+        };
+    }
+    Ok(())
+}
+
+#[cfg(target_os = "linux")]
+pub fn lock_memory() -> Result<(), RealtimeError> {
+    if unsafe { libc::mlockall(libc::MCL_CURRENT | libc::MCL_FUTURE) } != 0 {
+        return Err(os_error("mlockall"));
+    }
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn set_fifo_priority(_priority: i32) -> Result<(), RealtimeError> {
+    Err(RealtimeError::Unsupported("SCHED_FIFO"))
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn pin_to_cpu(_cpu: usize) -> Result<(), RealtimeError> {
+    Err(RealtimeError::Unsupported("CPU affinity"))
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn lock_memory() -> Result<(), RealtimeError> {
+    Err(RealtimeError::Unsupported("mlockall"))
+}
+
+#[cfg(all(test, target_os = "linux"))]
+mod tests {
+    use super::*;
+
+    fn is_invalid(result: Result<(), RealtimeError>) -> bool {
+        matches!(result, Err(RealtimeError::InvalidArgument(..)))
+    }
+
+    #[test]
+    fn priority_outside_1_to_99_is_rejected_before_the_syscall() {
+        for priority in [-1, 0, 100, 1000] {
+            assert!(is_invalid(set_fifo_priority(priority)), "priority {}", priority);
+        }
+        // In range it gets as far as the permission check
+        assert!(!is_invalid(set_fifo_priority(1)));
+    }
+
+    #[test]
+    fn cpu_beyond_the_machine_is_rejected() {
+        let configured = unsafe { libc::sysconf(libc::_SC_NPROCESSORS_CONF) } as usize;
+        for cpu in [configured, configured + 7, libc::CPU_SETSIZE as usize, usize::MAX] {
+            match pin_to_cpu(cpu) {
+                Err(RealtimeError::InvalidArgument("CPU affinity", reason)) => assert!(reason.contains(&cpu.to_string()), "{}", reason),
+                other => panic!("cpu {}: {:?}", cpu, other),
+            }
+        }
+
+        // One this thread may already run on is fine, and the errors above changed nothing
+        let cpu = unsafe { libc::sched_getcpu() };
+        assert!(cpu >= 0);
+        assert!(pin_to_cpu(cpu as usize).is_ok());
+    }
+
+    #[test]
+    fn failures_are_collected_per_setting() {
+        let config = RealtimeConfig::fifo(0).on_cpu(libc::CPU_SETSIZE as usize);
+        let errors = apply_to_current_thread(&config);
+        assert_eq!(errors.len(), 2);
+        assert!(errors.iter().all(|err| matches!(err, RealtimeError::InvalidArgument(..))));
+        assert!(apply_to_current_thread(&RealtimeConfig::default()).is_empty());
+    }
+}
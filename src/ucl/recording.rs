@@ -0,0 +1,368 @@
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, BufWriter, Write};
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use serde::{Deserialize, Serialize};
+use super::common::{byte_print, bytes_from_hex};
+use super::discovery::classify_state;
+use super::transport::Transport;
+use super::unitreeConnection::UnitreeConnection;
+
+// One JSON object per line. `t_us` is microseconds since the recording started.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum Entry {
+    Header { started_unix_ms: u64, lib_version: String },
+    Robot { t_us: u64, sn: String, version: String }, // from the first state packet
+    Tx { t_us: u64, data: String },
+    Rx { t_us: u64, data: String },
+}
+
+pub struct Recorder {
+    writer: BufWriter<File>,
+    started: Instant,
+    robot_seen: bool,
+}
+
+impl Recorder {
+    pub fn create(path: &Path) -> io::Result<Self> {
+        let mut recorder = Recorder {
+            writer: BufWriter::new(File::create(path)?),
+            started: Instant::now(),
+            robot_seen: false,
+        };
+        let started_unix_ms = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_millis() as u64).unwrap_or(0);
+        recorder.write(&Entry::Header { started_unix_ms, lib_version: super::common::lib_version().to_string() })?;
+        Ok(recorder)
+    }
+
+    fn t_us(&self, at: Instant) -> u64 {
+        at.saturating_duration_since(self.started).as_micros() as u64
+    }
+
+    fn write(&mut self, entry: &Entry) -> io::Result<()> {
+        serde_json::to_writer(&mut self.writer, entry)?;
+        self.writer.write_all(b"\n")
+    }
+
+    pub fn record_tx(&mut self, at: Instant, data: &[u8]) -> io::Result<()> {
+        let t_us = self.t_us(at);
+        self.write(&Entry::Tx { t_us, data: byte_print(data) })
+    }
+
+    pub fn record_rx(&mut self, at: Instant, data: &[u8]) -> io::Result<()> {
+        let t_us = self.t_us(at);
+        if !self.robot_seen && classify_state(data).is_some() {
+            self.robot_seen = true;
+            self.write(&Entry::Robot { t_us, sn: byte_print(&data[4..12]), version: byte_print(&data[12..20]) })?;
+        }
+        self.write(&Entry::Rx { t_us, data: byte_print(data) })
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+}
+
+// Transport that logs every sent command and received datagram
+pub struct RecordingConnection<T: Transport = UnitreeConnection> {
+    conn: T,
+    recorder: Mutex<Recorder>,
+}
+
+impl<T: Transport> RecordingConnection<T> {
+    pub fn new(conn: T, path: &Path) -> io::Result<Self> {
+        Ok(RecordingConnection { conn, recorder: Mutex::new(Recorder::create(path)?) })
+    }
+
+    pub fn start_recv(&self) {
+        self.conn.start_recv();
+    }
+
+    pub fn send(&self, cmd: &[u8]) {
+        self.conn.send(cmd);
+        self.record_tx(cmd);
+    }
+
+    pub fn try_send(&self, cmd: &[u8]) -> io::Result<()> {
+        self.conn.try_send(cmd)?;
+        self.record_tx(cmd);
+        Ok(())
+    }
+
+    pub fn halt(&self) -> bool {
+        self.conn.halt()
+    }
+
+    pub fn send_halted(&self, cmd: &[u8]) -> io::Result<()> {
+        self.conn.send_halted(cmd)?;
+        self.record_tx(cmd);
+        Ok(())
+    }
+
+    fn record_tx(&self, cmd: &[u8]) {
+        if let Err(e) = self.recorder.lock().unwrap().record_tx(Instant::now(), cmd) {
+            eprintln!("[recording] could not write command: {}", e);
+        }
+    }
+
+    pub fn get_data(&self) -> Vec<Vec<u8>> {
+        self.get_data_timestamped().into_iter().map(|(_, packet)| packet).collect()
+    }
+
+    pub fn get_data_timestamped(&self) -> Vec<(Instant, Vec<u8>)> {
+        let data = self.conn.get_data_timestamped();
+        let mut recorder = self.recorder.lock().unwrap();
+        for (at, packet) in data.iter() {
+            if let Err(e) = recorder.record_rx(*at, packet) {
+                eprintln!("[recording] could not write datagram: {}", e);
+            }
+        }
+        data
+    }
+
+    pub fn last_received(&self) -> Option<Instant> {
+        self.conn.last_received()
+    }
+
+    pub fn flush(&self) -> io::Result<()> {
+        self.recorder.lock().unwrap().flush()
+    }
+}
+
+impl<T: Transport> Drop for RecordingConnection<T> {
+    fn drop(&mut self) {
+        let _ = self.flush();
+    }
+}
+
+// A recording loaded back into memory
+#[derive(Debug, Clone, Default)]
+pub struct Recording {
+    pub started_unix_ms: u64,
+    pub sn: Option<Vec<u8>>,
+    pub version: Option<Vec<u8>>,
+    pub tx: Vec<(Duration, Vec<u8>)>,
+    pub rx: Vec<(Duration, Vec<u8>)>,
+}
+
+impl Recording {
+    pub fn load(path: &Path) -> io::Result<Self> {
+        let reader = BufReader::new(File::open(path)?);
+        let mut recording = Recording::default();
+        for (line_no, line) in reader.lines().enumerate() {
+            let line = line?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            let bad_line = |what: &str| io::Error::new(io::ErrorKind::InvalidData, format!("line {}: {}", line_no + 1, what));
+            let entry: Entry = serde_json::from_str(&line).map_err(|e| bad_line(&e.to_string()))?;
+            match entry {
+                Entry::Header { started_unix_ms, .. } => recording.started_unix_ms = started_unix_ms,
+                Entry::Robot { sn, version, .. } => {
+                    recording.sn = Some(bytes_from_hex(&sn).ok_or_else(|| bad_line("invalid hex"))?);
+                    recording.version = Some(bytes_from_hex(&version).ok_or_else(|| bad_line("invalid hex"))?);
+                }
+                Entry::Tx { t_us, data } => {
+                    let bytes = bytes_from_hex(&data).ok_or_else(|| bad_line("invalid hex"))?;
+                    recording.tx.push((Duration::from_micros(t_us), bytes));
+                }
+                Entry::Rx { t_us, data } => {
+                    let bytes = bytes_from_hex(&data).ok_or_else(|| bad_line("invalid hex"))?;
+                    recording.rx.push((Duration::from_micros(t_us), bytes));
+                }
+            }
+        }
+        Ok(recording)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReplaySpeed {
+    RealTime,         // datagrams become available at their recorded offsets
+    AsFastAsPossible, // every get_data call returns the next datagram
+}
+
+// Plays a recording back through the Transport API. Sent commands are kept
+// so they can be compared with the recorded ones.
+pub struct ReplayConnection {
+    recording: Recording,
+    speed: ReplaySpeed,
+    started: Mutex<Option<Instant>>,
+    position: Mutex<usize>,
+    last_recv: Mutex<Option<Instant>>,
+    sent: Mutex<Vec<Vec<u8>>>,
+}
+
+impl ReplayConnection {
+    pub fn open(path: &Path, speed: ReplaySpeed) -> io::Result<Self> {
+        Ok(Self::new(Recording::load(path)?, speed))
+    }
+
+    pub fn new(recording: Recording, speed: ReplaySpeed) -> Self {
+        ReplayConnection {
+            recording,
+            speed,
+            started: Mutex::new(None),
+            position: Mutex::new(0),
+            last_recv: Mutex::new(None),
+            sent: Mutex::new(Vec::new()),
+        }
+    }
+
+    pub fn recording(&self) -> &Recording {
+        &self.recording
+    }
+
+    // Starts the replay clock
+    pub fn start_recv(&self) {
+        *self.started.lock().unwrap() = Some(Instant::now());
+    }
+
+    pub fn send(&self, cmd: &[u8]) {
+        self.sent.lock().unwrap().push(cmd.to_vec());
+    }
+
+    pub fn get_data(&self) -> Vec<Vec<u8>> {
+        let started = match *self.started.lock().unwrap() {
+            Some(started) => started,
+            None => return Vec::new(),
+        };
+        let mut position = self.position.lock().unwrap();
+        let end = match self.speed {
+            ReplaySpeed::RealTime => {
+                let elapsed = started.elapsed();
+                let ahead = self.recording.rx[*position..].iter().take_while(|(t, _)| *t <= elapsed).count();
+                *position + ahead
+            }
+            ReplaySpeed::AsFastAsPossible => (*position + 1).min(self.recording.rx.len()),
+        };
+        let data: Vec<Vec<u8>> = self.recording.rx[*position..end].iter().map(|(_, packet)| packet.clone()).collect();
+        *position = end;
+        if !data.is_empty() {
+            *self.last_recv.lock().unwrap() = Some(Instant::now());
+        }
+        data
+    }
+
+    pub fn last_received(&self) -> Option<Instant> {
+        *self.last_recv.lock().unwrap()
+    }
+
+    pub fn is_finished(&self) -> bool {
+        *self.position.lock().unwrap() >= self.recording.rx.len()
+    }
+
+    pub fn sent_commands(&self) -> Vec<Vec<u8>> {
+        self.sent.lock().unwrap().clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+    use std::thread;
+    use crate::ucl::highCmd::HighCmd;
+    use crate::ucl::highState::HighState;
+    use crate::ucl::transport::channel_pair;
+
+    // Unique per test, removed on drop
+    struct TempFile(PathBuf);
+
+    impl TempFile {
+        fn new(test: &str) -> Self {
+            TempFile(std::env::temp_dir().join(format!("go1-recording-{}-{}.jsonl", std::process::id(), test)))
+        }
+    }
+
+    impl Drop for TempFile {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_file(&self.0);
+        }
+    }
+
+    fn state(body_height: f32) -> Vec<u8> {
+        let mut hstate = HighState::new();
+        hstate.sn = [1, 2, 3, 4, 5, 6, 7, 8];
+        hstate.body_height = body_height;
+        hstate.build_state()
+    }
+
+    // Two states 30 ms apart and one command, recorded through a channel and loaded back
+    fn record(file: &TempFile) -> (Vec<u8>, Vec<u8>, Vec<u8>) {
+        let (ours, dog) = channel_pair();
+        let conn = RecordingConnection::new(ours, &file.0).unwrap();
+        let (first, second, cmd) = (state(0.1), state(0.2), HighCmd::new().build_cmd(false));
+        thread::sleep(Duration::from_millis(30));
+        dog.send(&first);
+        thread::sleep(Duration::from_millis(30));
+        dog.send(&second);
+        assert_eq!(conn.get_data(), [first.clone(), second.clone()]);
+        conn.send(&cmd);
+        drop(conn);
+        assert_eq!(dog.get_data(), std::slice::from_ref(&cmd));
+        (first, second, cmd)
+    }
+
+    #[test]
+    fn recording_loads_back() {
+        let file = TempFile::new("load");
+        let (first, second, cmd) = record(&file);
+        let recording = Recording::load(&file.0).unwrap();
+        assert!(recording.started_unix_ms > 0);
+        assert_eq!(recording.sn.as_deref(), Some(&first[4..12]));
+        assert_eq!(recording.version.as_deref(), Some(&first[12..20]));
+        assert_eq!(recording.rx.iter().map(|(_, packet)| packet.clone()).collect::<Vec<_>>(), [first, second]);
+        assert_eq!(recording.tx.iter().map(|(_, packet)| packet.clone()).collect::<Vec<_>>(), [cmd]);
+        let (t_first, t_second) = (recording.rx[0].0, recording.rx[1].0);
+        assert!(t_first >= Duration::from_millis(30) && t_second >= t_first + Duration::from_millis(30), "{:?} {:?}", t_first, t_second);
+    }
+
+    #[test]
+    fn replays_as_fast_as_possible() {
+        let file = TempFile::new("fast");
+        let (first, second, cmd) = record(&file);
+        let replay = ReplayConnection::open(&file.0, ReplaySpeed::AsFastAsPossible).unwrap();
+        assert!(Transport::get_data(&replay).is_empty(), "nothing before start_recv");
+        Transport::start_recv(&replay);
+        assert_eq!(Transport::get_data(&replay), [first]);
+        assert!(!replay.is_finished());
+        assert_eq!(Transport::get_data(&replay), [second]);
+        assert!(replay.is_finished());
+        assert!(Transport::get_data(&replay).is_empty());
+        assert!(replay.last_received().is_some());
+
+        Transport::send(&replay, &cmd);
+        assert_eq!(replay.sent_commands(), replay.recording().tx.iter().map(|(_, packet)| packet.clone()).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn replays_in_real_time() {
+        let file = TempFile::new("realtime");
+        let (first, second, _) = record(&file);
+        let replay = ReplayConnection::open(&file.0, ReplaySpeed::RealTime).unwrap();
+        let (t_first, t_second) = (replay.recording().rx[0].0, replay.recording().rx[1].0);
+        Transport::start_recv(&replay);
+        let started = Instant::now();
+        assert!(Transport::get_data(&replay).is_empty());
+        thread::sleep(((t_first + t_second) / 2).saturating_sub(started.elapsed()));
+        assert_eq!(Transport::get_data(&replay), [first]);
+        thread::sleep((t_second + Duration::from_millis(10)).saturating_sub(started.elapsed()));
+        assert_eq!(Transport::get_data(&replay), [second]);
+        assert!(replay.is_finished());
+    }
+
+    #[test]
+    fn bad_hex_is_an_error() {
+        let file = TempFile::new("hex");
+        let header = r#"{"type":"header","started_unix_ms":1,"lib_version":"x"}"#;
+        for line in [r#"{"type":"robot","t_us":0,"sn":"zz","version":"01"}"#, r#"{"type":"rx","t_us":0,"data":"0g"}"#] {
+            std::fs::write(&file.0, format!("{}\n{}\n", header, line)).unwrap();
+            let err = Recording::load(&file.0).unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+            assert_eq!(err.to_string(), "line 2: invalid hex");
+        }
+    }
+}
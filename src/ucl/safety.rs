@@ -0,0 +1,494 @@
+use std::f32::consts::FRAC_PI_3;
+use std::fmt;
+use super::complex::{MotorCmd, MotorCmdArray};
+use super::enums::{MotorModeLow, RobotModel};
+use super::lowState::LowState;
+
+// What the official SDK documents about PowerProtect (unitree_legged_sdk, include/unitree_legged_sdk/safety.h,
+// v3.2 to v3.8): the factor 1 to 10 means 10 % to 100 % of the power limit, with a counter
+// next to the limit (WattLimit, Wcount). The implementation only ships inside the compiled
+// library, so the numbers below are ours and not the vendor's. The limit is per joint on the
+// power tau * dq, so one joint far over it is caught and only that joint is stopped; the
+// robot wide sum is an opt-in extra, see Safety::with_total_limit. Counts are frames at 500 Hz.
+const JOINT_WATT_LIMIT_FULL: f32 = 200.0; // per joint at factor 10, 20 W at factor 1
+const WCOUNT_DAMP: u32 = 10;  // frames in a row over the limit before the joint is damped
+const WCOUNT_ZERO: u32 = 100; // still over it after this many, the controller keeps pushing, cut
+const LEG_MOTORS: usize = 12;
+
+// Gain used when a joint is damped instead of zeroed, brakes without fighting the leg
+const PROTECT_KD: f32 = 2.0;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SafetyError {
+    InvalidFactor(u8), // PowerProtect only knows factors 1 to 10
+    MissingMotorState, // LowState without the 12 leg motors, e.g. never parsed
+    PositionOutOfRange { motor: usize, q: f32, range: JointRange },
+}
+
+impl fmt::Display for SafetyError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SafetyError::InvalidFactor(factor) => write!(f, "power protect factor {} is outside 1 to 10", factor),
+            SafetyError::MissingMotorState => write!(f, "low state has no motor state for the leg joints"),
+            SafetyError::PositionOutOfRange { motor, q, range } => {
+                write!(f, "motor {} target {} rad is outside {} to {}", motor, q, range.min, range.max)
+            }
+        }
+    }
+}
+
+impl std::error::Error for SafetyError {}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PowerAction {
+    Damped, // no torque or stiffness left on the joint, only damping
+    Zeroed, // motor put into damping mode with everything zero
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct PowerViolation {
+    pub joint: Option<usize>, // the leg motor over its limit, None for the robot wide total
+    pub power: f32,           // W, what the command would put out at the measured speed
+    pub limit: f32,           // W
+    pub count: u32,           // frames in a row over the limit
+    pub action: PowerAction,  // applied to that joint, or to every leg joint for the total
+}
+
+// PowerProtect, run on every LowCmd before it is sent:
+//   safety.power_protect(&mut motor_cmd, &lstate, 1)?;
+#[derive(Debug, Clone, Default)]
+pub struct Safety {
+    wcount: [u32; LEG_MOTORS],
+    total_limit: Option<f32>,
+    total_count: u32,
+    violations: u64,
+}
+
+impl Safety {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // Also watch the sum over all leg joints, in W and not scaled by the factor. Over it for
+    // WCOUNT_DAMP frames damps every leg joint, like a robot wide WattLimit would.
+    pub fn with_total_limit(mut self, watts: f32) -> Self {
+        self.total_limit = Some(watts);
+        self
+    }
+
+    // The per joint limit for `factor`
+    pub fn joint_watt_limit(factor: u8) -> f32 {
+        JOINT_WATT_LIMIT_FULL * factor as f32 / 10.0
+    }
+
+    // Frames protect stepped in so far
+    pub fn violations(&self) -> u64 {
+        self.violations
+    }
+
+    // Power of each leg joint: its output torque estimated from the command (feed forward plus
+    // the PD terms at the measured position and speed) times the measured speed. A joint that
+    // stays over the limit for WCOUNT_DAMP frames is damped, after WCOUNT_ZERO it is cut; one
+    // frame under the limit starts its count over. The other joints keep their command.
+    // Start with factor 1 and only raise it once the controller is known to behave.
+    pub fn power_protect(&mut self, cmd: &mut MotorCmdArray, state: &LowState, factor: u8) -> Result<Vec<PowerViolation>, SafetyError> {
+        if !(1..=10).contains(&factor) {
+            return Err(SafetyError::InvalidFactor(factor));
+        }
+        if state.motor_state.len() < LEG_MOTORS {
+            return Err(SafetyError::MissingMotorState);
+        }
+
+        let mut power = [0.0; LEG_MOTORS];
+        for (motor, joint_power) in power.iter_mut().enumerate() {
+            let Some(motor_cmd) = cmd.get_motor_cmd(motor) else { continue };
+            if motor_cmd.mode() != MotorModeLow::Servo as u8 {
+                continue; // damping and overheat produce no torque of their own
+            }
+            let measured = &state.motor_state[motor];
+            let tau = motor_cmd.tau()
+                + motor_cmd.kp() * (motor_cmd.q() - measured.q)
+                + motor_cmd.kd() * (motor_cmd.dq() - measured.dq);
+            *joint_power = (tau * measured.dq).abs();
+        }
+
+        let mut violations = Vec::new();
+        let limit = Self::joint_watt_limit(factor);
+        for (motor, (joint_power, count)) in power.iter().zip(self.wcount.iter_mut()).enumerate() {
+            if let Some(action) = count_over(count, *joint_power, limit) {
+                protect(cmd, state, motor, action);
+                violations.push(PowerViolation { joint: Some(motor), power: *joint_power, limit, count: *count, action });
+            }
+        }
+        if let Some(total_limit) = self.total_limit {
+            let total = power.iter().sum();
+            if let Some(action) = count_over(&mut self.total_count, total, total_limit) {
+                for motor in 0..LEG_MOTORS {
+                    protect(cmd, state, motor, action);
+                }
+                violations.push(PowerViolation { joint: None, power: total, limit: total_limit, count: self.total_count, action });
+            }
+        }
+        if !violations.is_empty() {
+            self.violations += 1;
+        }
+        Ok(violations)
+    }
+}
+
+// Step a Wcount, the action once it ran long enough. A NaN power fails the limit check as
+// well and is cut right away.
+fn count_over(count: &mut u32, power: f32, limit: f32) -> Option<PowerAction> {
+    if power <= limit {
+        *count = 0;
+        return None;
+    }
+    *count += 1;
+    if power.is_nan() || *count >= WCOUNT_ZERO {
+        Some(PowerAction::Zeroed)
+    } else if *count >= WCOUNT_DAMP {
+        Some(PowerAction::Damped)
+    } else {
+        None
+    }
+}
+
+fn protect(cmd: &mut MotorCmdArray, state: &LowState, motor: usize, action: PowerAction) {
+    let Some(motor_cmd) = cmd.get_motor_cmd(motor) else { return };
+    let protected = match action {
+        PowerAction::Damped => MotorCmd::new(MotorModeLow::Servo as u8, state.motor_state[motor].q, 0.0, 0.0, 0.0, PROTECT_KD, motor_cmd.reserve()),
+        PowerAction::Zeroed => MotorCmd::new(MotorModeLow::Damping as u8, 0.0, 0.0, 0.0, 0.0, 0.0, motor_cmd.reserve()),
+    };
+    cmd.set_motor_cmd(motor, protected);
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct JointRange {
+    pub min: f32, // rad
+    pub max: f32, // rad
+}
+
+impl JointRange {
+    pub fn new(min: f32, max: f32) -> Self {
+        JointRange { min, max }
+    }
+
+    pub fn contains(&self, q: f32) -> bool {
+        q >= self.min && q <= self.max
+    }
+
+    pub fn clamp(&self, q: f32) -> f32 {
+        q.clamp(self.min, self.max)
+    }
+}
+
+// Same range on all four legs, motor index i is joint i % 3 (hip, thigh, calf)
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct JointLimits {
+    pub hip: JointRange,
+    pub thigh: JointRange,
+    pub calf: JointRange,
+}
+
+impl JointLimits {
+    // Mechanical ranges from the published URDFs (unitree_ros, *_description/xacro/const.xacro)
+    pub fn for_model(model: RobotModel) -> Self {
+        match model {
+            RobotModel::Aliengo => JointLimits {
+                hip: JointRange::new(-1.2217305, 1.2217305),
+                thigh: JointRange::new(-2.0943951, 4.1887902),
+                calf: JointRange::new(-2.7750735, -0.6457718),
+            },
+            RobotModel::A1 => JointLimits {
+                hip: JointRange::new(-0.8028515, 0.8028515),
+                thigh: JointRange::new(-FRAC_PI_3, 4.1887902),
+                calf: JointRange::new(-2.6965337, -0.9162979),
+            },
+            RobotModel::Go1 => JointLimits {
+                hip: JointRange::new(-0.863, 0.863),
+                thigh: JointRange::new(-0.686, 4.501),
+                calf: JointRange::new(-2.818, -0.888),
+            },
+        }
+    }
+
+    pub fn range(&self, motor: usize) -> JointRange {
+        match motor % 3 {
+            0 => self.hip,
+            1 => self.thigh,
+            _ => self.calf,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LimitMode {
+    Clamp,  // move out of range targets onto the nearest limit
+    Reject, // refuse the whole MotorCmdArray
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct LimitViolation {
+    pub motor: usize,
+    pub q: f32, // the target or measured position that was out of range
+    pub range: JointRange,
+}
+
+#[derive(Debug, Clone)]
+pub struct PositionLimit {
+    pub limits: JointLimits,
+    pub mode: LimitMode,
+}
+
+impl PositionLimit {
+    pub fn new(model: RobotModel, mode: LimitMode) -> Self {
+        PositionLimit { limits: JointLimits::for_model(model), mode }
+    }
+
+    // Check the position targets of the leg joints. Only joints that hold a position (Servo
+    // mode with kp > 0) count, velocity and torque control park q at the PosStopF sentinel.
+    // Clamp returns what it moved, Reject fails on the first one. A NaN target has no nearest
+    // limit and fails in both modes. On any error `cmd` is left untouched, the clamping is done
+    // on a copy that only replaces `cmd` once all joints passed.
+    pub fn check_cmd(&self, cmd: &mut MotorCmdArray) -> Result<Vec<LimitViolation>, SafetyError> {
+        let mut checked = cmd.clone();
+        let mut violations = Vec::new();
+        for motor in 0..LEG_MOTORS {
+            let Some(motor_cmd) = checked.get_motor_cmd(motor) else { continue };
+            if motor_cmd.mode() != MotorModeLow::Servo as u8 || motor_cmd.kp() <= 0.0 {
+                continue;
+            }
+            let range = self.limits.range(motor);
+            let q = motor_cmd.q();
+            if range.contains(q) {
+                continue;
+            }
+            if self.mode == LimitMode::Reject || q.is_nan() {
+                return Err(SafetyError::PositionOutOfRange { motor, q, range });
+            }
+            let clamped = MotorCmd::new(motor_cmd.mode(), range.clamp(q), motor_cmd.dq(), motor_cmd.tau(), motor_cmd.kp(), motor_cmd.kd(), motor_cmd.reserve());
+            violations.push(LimitViolation { motor, q, range });
+            checked.set_motor_cmd(motor, clamped);
+        }
+        *cmd = checked;
+        Ok(violations)
+    }
+
+    // Leg joints whose measured position is outside the range, a sign of a bad calibration,
+    // a fall or a joint forced past its stop
+    pub fn check_state(&self, state: &LowState) -> Vec<LimitViolation> {
+        state.motor_state.iter().take(LEG_MOTORS).enumerate()
+            .filter(|(motor, measured)| !self.limits.range(*motor).contains(measured.q))
+            .map(|(motor, measured)| LimitViolation { motor, q: measured.q, range: self.limits.range(motor) })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Every leg joint held at 0.5 rad by a stiff PD while the legs are shaken at 4 rad/s:
+    // 20 * 0.5 - 1 * 4 = 6 Nm, 24 W per joint, 288 W in total
+    fn shaking() -> (MotorCmdArray, LowState) {
+        let mut cmd = MotorCmdArray::new();
+        let mut state = LowState::new();
+        for motor in 0..LEG_MOTORS {
+            cmd.set_motor_cmd(motor, MotorCmd::new(MotorModeLow::Servo as u8, 0.5, 0.0, 0.0, 20.0, 1.0, [0, 0, 0]));
+            state.motor_state[motor].dq = 4.0;
+        }
+        (cmd, state)
+    }
+
+    fn protect(safety: &mut Safety, factor: u8) -> (Vec<PowerViolation>, MotorCmdArray) {
+        let (mut cmd, state) = shaking();
+        let violations = safety.power_protect(&mut cmd, &state, factor).unwrap();
+        (violations, cmd)
+    }
+
+    #[test]
+    fn under_the_limit_passes() {
+        let mut safety = Safety::new();
+        assert_eq!(Safety::joint_watt_limit(1), 20.0);
+        for _ in 0..WCOUNT_ZERO + 1 {
+            let (violations, cmd) = protect(&mut safety, 3);
+            assert!(violations.is_empty());
+            assert_eq!(cmd.get_motor_cmd(0).unwrap().kp(), 20.0);
+        }
+        assert_eq!(safety.violations(), 0);
+    }
+
+    #[test]
+    fn damps_after_wcount_frames() {
+        let mut safety = Safety::new();
+        for _ in 1..WCOUNT_DAMP {
+            let (violations, cmd) = protect(&mut safety, 1);
+            assert!(violations.is_empty());
+            assert_eq!(cmd.get_motor_cmd(3).unwrap().kp(), 20.0);
+        }
+        let (violations, cmd) = protect(&mut safety, 1);
+        assert_eq!(violations.len(), LEG_MOTORS);
+        let violation = &violations[3];
+        assert_eq!((violation.joint, violation.action, violation.count), (Some(3), PowerAction::Damped, WCOUNT_DAMP));
+        assert!((violation.power - 24.0).abs() < 0.1 && violation.limit == 20.0, "{:?}", violation);
+        for motor in 0..LEG_MOTORS {
+            let damped = cmd.get_motor_cmd(motor).unwrap();
+            assert_eq!(damped.mode(), MotorModeLow::Servo as u8);
+            assert_eq!((damped.kp(), damped.tau(), damped.kd()), (0.0, 0.0, PROTECT_KD));
+        }
+    }
+
+    #[test]
+    fn one_hot_joint_is_caught_and_only_it_is_stopped() {
+        // Only motor 4 moves, fast: 20 * 0.5 - 1 * 40 = -30 Nm at 40 rad/s, 1200 W
+        let (sent, mut state) = shaking();
+        for motor in 0..LEG_MOTORS {
+            state.motor_state[motor].dq = if motor == 4 { 40.0 } else { 0.0 };
+        }
+        let mut safety = Safety::new();
+        let (mut cmd, mut violations) = (sent.clone(), Vec::new());
+        for _ in 0..WCOUNT_DAMP {
+            cmd = sent.clone();
+            violations = safety.power_protect(&mut cmd, &state, 10).unwrap();
+        }
+        assert_eq!(violations.len(), 1);
+        assert_eq!((violations[0].joint, violations[0].action), (Some(4), PowerAction::Damped));
+        assert!((violations[0].power - 1200.0).abs() < 1.0);
+        assert_eq!(cmd.get_motor_cmd(4).unwrap().kp(), 0.0);
+        for motor in (0..LEG_MOTORS).filter(|motor| *motor != 4) {
+            assert_eq!(cmd.get_motor_cmd(motor).unwrap().kp(), 20.0, "motor {}", motor);
+        }
+    }
+
+    #[test]
+    fn zeroes_when_it_keeps_pushing() {
+        let mut safety = Safety::new();
+        for _ in 1..WCOUNT_ZERO {
+            protect(&mut safety, 1);
+        }
+        let (violations, cmd) = protect(&mut safety, 1);
+        assert!(violations.iter().all(|violation| violation.action == PowerAction::Zeroed));
+        assert_eq!(cmd.get_motor_cmd(11).unwrap().mode(), MotorModeLow::Damping as u8);
+        assert_eq!(safety.violations(), (WCOUNT_ZERO - WCOUNT_DAMP + 1) as u64);
+    }
+
+    #[test]
+    fn one_frame_under_the_limit_resets_the_count() {
+        let mut safety = Safety::new();
+        for _ in 1..WCOUNT_DAMP {
+            protect(&mut safety, 1);
+        }
+        assert!(protect(&mut safety, 5).0.is_empty());
+        assert!(protect(&mut safety, 1).0.is_empty());
+    }
+
+    #[test]
+    fn total_limit_is_opt_in_and_damps_every_leg() {
+        // 24 W per joint is under the joint limit at factor 10, the 288 W sum is over 200 W
+        let mut safety = Safety::new().with_total_limit(200.0);
+        for _ in 1..WCOUNT_DAMP {
+            assert!(protect(&mut safety, 10).0.is_empty());
+        }
+        let (violations, cmd) = protect(&mut safety, 10);
+        assert_eq!(violations.len(), 1);
+        assert_eq!((violations[0].joint, violations[0].action), (None, PowerAction::Damped));
+        assert!((violations[0].power - 288.0).abs() < 0.5 && violations[0].limit == 200.0);
+        assert!((0..LEG_MOTORS).all(|motor| cmd.get_motor_cmd(motor).unwrap().kp() == 0.0));
+
+        let mut safety = Safety::new();
+        for _ in 0..WCOUNT_ZERO {
+            assert!(protect(&mut safety, 10).0.is_empty());
+        }
+    }
+
+    #[test]
+    fn nan_is_cut_right_away() {
+        let (mut cmd, mut state) = shaking();
+        state.motor_state[2].dq = f32::NAN;
+        let violations = Safety::new().power_protect(&mut cmd, &state, 10).unwrap();
+        assert_eq!(violations.len(), 1);
+        assert_eq!((violations[0].joint, violations[0].action), (Some(2), PowerAction::Zeroed));
+        assert_eq!(cmd.get_motor_cmd(2).unwrap().mode(), MotorModeLow::Damping as u8);
+        assert_eq!(cmd.get_motor_cmd(1).unwrap().mode(), MotorModeLow::Servo as u8);
+    }
+
+    #[test]
+    fn rejects_bad_input() {
+        let (mut cmd, mut state) = shaking();
+        let mut safety = Safety::new();
+        assert_eq!(safety.power_protect(&mut cmd, &state, 0), Err(SafetyError::InvalidFactor(0)));
+        assert_eq!(safety.power_protect(&mut cmd, &state, 11), Err(SafetyError::InvalidFactor(11)));
+        state.motor_state.clear();
+        assert_eq!(safety.power_protect(&mut cmd, &state, 1), Err(SafetyError::MissingMotorState));
+    }
+
+    fn holding(targets: &[(usize, f32)]) -> MotorCmdArray {
+        let mut cmd = MotorCmdArray::new();
+        for motor in 0..LEG_MOTORS {
+            cmd.set_motor_cmd(motor, MotorCmd::new(MotorModeLow::Servo as u8, [0.0, 0.8, -1.5][motor % 3], 0.0, 0.0, 20.0, 1.0, [0, 0, 0]));
+        }
+        for &(motor, q) in targets {
+            cmd.set_motor_cmd(motor, MotorCmd::new(MotorModeLow::Servo as u8, q, 0.0, 0.0, 20.0, 1.0, [0, 0, 0]));
+        }
+        cmd
+    }
+
+    fn targets(cmd: &MotorCmdArray) -> Vec<f32> {
+        (0..LEG_MOTORS).map(|motor| cmd.get_motor_cmd(motor).unwrap().q()).collect()
+    }
+
+    #[test]
+    fn clamp_moves_only_the_out_of_range_joints() {
+        let limit = PositionLimit::new(RobotModel::Go1, LimitMode::Clamp);
+        let mut cmd = holding(&[(3, 1.2), (8, -3.0)]);
+        let violations = limit.check_cmd(&mut cmd).unwrap();
+        assert_eq!(violations.iter().map(|v| (v.motor, v.q)).collect::<Vec<_>>(), [(3, 1.2), (8, -3.0)]);
+        let q = targets(&cmd);
+        assert_eq!((q[3], q[8]), (0.863, -2.818));
+        assert_eq!(q[0], 0.0);
+
+        // In range and velocity/torque controlled joints pass as they are
+        let mut cmd = holding(&[]);
+        cmd.set_motor_cmd(5, MotorCmd::new(MotorModeLow::Servo as u8, 9.0, 2.0, 0.0, 0.0, 3.0, [0, 0, 0]));
+        assert!(limit.check_cmd(&mut cmd).unwrap().is_empty());
+        assert_eq!(cmd.get_motor_cmd(5).unwrap().q(), 9.0);
+    }
+
+    #[test]
+    fn reject_leaves_the_command_untouched() {
+        let limit = PositionLimit::new(RobotModel::Go1, LimitMode::Reject);
+        let mut cmd = holding(&[(1, 5.0), (4, 5.0)]);
+        let before = targets(&cmd);
+        assert!(matches!(limit.check_cmd(&mut cmd), Err(SafetyError::PositionOutOfRange { motor: 1, .. })));
+        assert_eq!(targets(&cmd), before);
+        assert!(limit.check_cmd(&mut holding(&[])).unwrap().is_empty());
+    }
+
+    #[test]
+    fn nan_fails_in_both_modes_without_clamping_the_joints_before_it() {
+        // Reject already stops at the out of range hip before the NaN
+        for (mode, failing) in [(LimitMode::Clamp, 7), (LimitMode::Reject, 0)] {
+            let limit = PositionLimit::new(RobotModel::Go1, mode);
+            let mut cmd = holding(&[(0, 2.0), (7, f32::NAN)]);
+            let before = targets(&cmd);
+            assert!(matches!(limit.check_cmd(&mut cmd), Err(SafetyError::PositionOutOfRange { motor, .. }) if motor == failing));
+            let after = targets(&cmd);
+            assert_eq!(after[0], before[0]);
+            assert!(after[7].is_nan());
+        }
+    }
+
+    #[test]
+    fn check_state_reports_joints_past_their_stop() {
+        let limit = PositionLimit::new(RobotModel::Go1, LimitMode::Clamp);
+        let mut state = LowState::new();
+        for motor in 0..LEG_MOTORS {
+            state.motor_state[motor].q = [0.0, 0.8, -1.5][motor % 3];
+        }
+        assert!(limit.check_state(&state).is_empty());
+        state.motor_state[2].q = -0.5;
+        state.motor_state[13].q = 9.0; // not a leg motor
+        let violations = limit.check_state(&state);
+        assert_eq!(violations, [LimitViolation { motor: 2, q: -0.5, range: JointRange::new(-2.818, -0.888) }]);
+    }
+}
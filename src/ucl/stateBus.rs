@@ -0,0 +1,563 @@
+use std::ffi::CString;
+use std::io;
+use std::ptr;
+use std::sync::atomic::{fence, AtomicU32, AtomicU64, Ordering};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use super::discovery::classify_state;
+use super::enums::Level;
+use super::highState::HighState;
+use super::lowState::LowState;
+use super::transport::Transport;
+use super::unitreeConnection::UnitreeConnection;
+
+// Layout version, bump whenever StateRegion or CommandRegion changes
+const BUS_MAGIC: u64 = 0x474f_3142_5553_0003; // "GO1BUS" + 3
+const STATE_CAPACITY: usize = 2048;
+const COMMAND_CAPACITY: usize = 1024;
+// Give up on a read after this many torn attempts (e.g. the writer died mid-write)
+const READ_RETRIES: usize = 1000;
+// How long submit waits for a live lock holder before giving up
+const LOCK_TIMEOUT: Duration = Duration::from_millis(10);
+
+// Readable by every user, only the publisher can write it
+const STATE_MODE: libc::mode_t = 0o644;
+// The command mailbox lives in its own segment that only the publisher's user can open, so
+// subscribers running as another user can neither read the token nor write commands
+const COMMAND_MODE: libc::mode_t = 0o600;
+const COMMAND_SUFFIX: &str = "-cmd";
+
+pub const DEFAULT_BUS_NAME: &str = "/go1-state";
+
+// Latest datagram of one kind, guarded by a seqlock (odd sequence = write in progress).
+// A reader may copy the payload while the publisher overwrites it, and only the sequence check
+// afterwards tells it to throw the copy away. A plain memcpy racing a write is undefined
+// behaviour even if the result is discarded, so the payload is stored as AtomicU64 words that
+// both sides access with relaxed loads and stores (see store_words/load_words). The fences
+// around them order the words against the sequence number.
+#[repr(C)]
+struct StateSlot {
+    seq: AtomicU64,
+    len: AtomicU32,
+    received_unix_us: AtomicU64,
+    data: [AtomicU64; STATE_CAPACITY / 8],
+}
+
+// Single command slot. Writers serialize on `lock`, the publisher reads it like a seqlock.
+#[repr(C)]
+struct Mailbox {
+    lock: AtomicU32, // pid of the writer holding it, 0 when free
+    seq: AtomicU64,
+    token: AtomicU64,
+    len: AtomicU32,
+    data: [AtomicU64; COMMAND_CAPACITY / 8], // same word layout as StateSlot
+}
+
+#[repr(C)]
+struct StateRegion {
+    magic: AtomicU64,
+    publisher_pid: AtomicU32,
+    high: StateSlot,
+    low: StateSlot,
+}
+
+#[repr(C)]
+struct CommandRegion {
+    magic: AtomicU64,
+    token_check: AtomicU64, // token_check(token), a client with the wrong token fails at open
+    mailbox: Mailbox,
+}
+
+// Not a secret, the segment permissions keep other users out. This only stops a misconfigured
+// client from replacing a pending valid command with one the publisher is going to reject.
+fn token_check(token: u64) -> u64 {
+    let mut z = token ^ 0x9e37_79b9_7f4a_7c15;
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    z ^ (z >> 31)
+}
+
+fn is_alive(pid: u32) -> bool {
+    pid != 0 && unsafe { libc::kill(pid as libc::pid_t, 0) } == 0
+}
+
+// A mapped shared-memory segment holding one R, unmapped on drop
+struct Mapping<R> {
+    region: *mut R,
+    name: CString,
+    owner: bool, // the publisher unlinks the name when it goes away
+}
+
+unsafe impl<R> Send for Mapping<R> {}
+unsafe impl<R> Sync for Mapping<R> {}
+
+impl Mapping<StateRegion> {
+    // Fails if a live publisher already owns `name`; a segment left behind by a crashed one is replaced
+    fn create_state(name: &str) -> io::Result<Self> {
+        match Self::map(name, Some(STATE_MODE), true) {
+            Err(err) if err.raw_os_error() == Some(libc::EEXIST) => {
+                let stale = Self::map(name, None, false)?;
+                let pid = stale.region().publisher_pid.load(Ordering::Relaxed);
+                if is_alive(pid) {
+                    return Err(io::Error::new(io::ErrorKind::AddrInUse, format!("{} is published by process {}", name, pid)));
+                }
+                unsafe { libc::shm_unlink(stale.name.as_ptr()) };
+                drop(stale);
+                Self::map(name, Some(STATE_MODE), true)
+            }
+            other => other,
+        }
+    }
+}
+
+impl Mapping<CommandRegion> {
+    // Only called once the state segment is ours, so an existing one is left over from a crash
+    fn create_command(name: &str) -> io::Result<Self> {
+        match Self::map(name, Some(COMMAND_MODE), true) {
+            Err(err) if err.raw_os_error() == Some(libc::EEXIST) => {
+                let c_name = CString::new(name).map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "bus name contains a NUL byte"))?;
+                unsafe { libc::shm_unlink(c_name.as_ptr()) };
+                Self::map(name, Some(COMMAND_MODE), true)
+            }
+            other => other,
+        }
+    }
+}
+
+impl<R> Mapping<R> {
+    fn open(name: &str, writable: bool, magic: impl Fn(&R) -> u64) -> io::Result<Self> {
+        let mapping = Self::map(name, None, writable)?;
+        if magic(mapping.region()) != BUS_MAGIC {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, format!("{} is not a go1 state bus (or a different version)", name)));
+        }
+        Ok(mapping)
+    }
+
+    // `create` holds the permissions of a new segment, None opens an existing one
+    fn map(name: &str, create: Option<libc::mode_t>, writable: bool) -> io::Result<Self> {
+        let c_name = CString::new(name).map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "bus name contains a NUL byte"))?;
+        let size = std::mem::size_of::<R>();
+        unsafe {
+            let access = if writable { libc::O_RDWR } else { libc::O_RDONLY };
+            let fd = match create {
+                Some(mode) => libc::shm_open(c_name.as_ptr(), libc::O_CREAT | libc::O_EXCL | access, mode as libc::c_uint),
+                None => libc::shm_open(c_name.as_ptr(), access, 0),
+            };
+            if fd < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            // The umask must neither open up the command segment nor lock subscribers out of the state
+            if let Some(mode) = create {
+                if libc::fchmod(fd, mode) != 0 || libc::ftruncate(fd, size as libc::off_t) != 0 {
+                    let err = io::Error::last_os_error();
+                    libc::close(fd);
+                    libc::shm_unlink(c_name.as_ptr());
+                    return Err(err);
+                }
+            }
+            let prot = if writable { libc::PROT_READ | libc::PROT_WRITE } else { libc::PROT_READ };
+            let addr = libc::mmap(ptr::null_mut(), size, prot, libc::MAP_SHARED, fd, 0);
+            libc::close(fd);
+            if addr == libc::MAP_FAILED {
+                return Err(io::Error::last_os_error());
+            }
+            Ok(Mapping { region: addr as *mut R, name: c_name, owner: create.is_some() })
+        }
+    }
+
+    fn region(&self) -> &R {
+        unsafe { &*self.region }
+    }
+}
+
+impl<R> Drop for Mapping<R> {
+    fn drop(&mut self) {
+        unsafe {
+            libc::munmap(self.region as *mut libc::c_void, std::mem::size_of::<R>());
+            if self.owner {
+                libc::shm_unlink(self.name.as_ptr());
+            }
+        }
+    }
+}
+
+fn unix_us() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_micros() as u64).unwrap_or(0)
+}
+
+// Little endian, the last word padded with zeros
+fn store_words(words: &[AtomicU64], bytes: &[u8]) {
+    for (word, chunk) in words.iter().zip(bytes.chunks(8)) {
+        let mut padded = [0u8; 8];
+        padded[..chunk.len()].copy_from_slice(chunk);
+        word.store(u64::from_le_bytes(padded), Ordering::Relaxed);
+    }
+}
+
+fn load_words(words: &[AtomicU64], len: usize) -> Vec<u8> {
+    let mut bytes = Vec::with_capacity(len.div_ceil(8) * 8);
+    for word in &words[..len.div_ceil(8)] {
+        bytes.extend_from_slice(&word.load(Ordering::Relaxed).to_le_bytes());
+    }
+    bytes.truncate(len);
+    bytes
+}
+
+impl StateSlot {
+    // Only the publisher writes, so no writer lock is needed
+    fn write(&self, packet: &[u8]) {
+        let len = packet.len().min(STATE_CAPACITY);
+        let seq = self.seq.load(Ordering::Relaxed);
+        self.seq.store(seq.wrapping_add(1), Ordering::Relaxed);
+        fence(Ordering::Release);
+        store_words(&self.data, &packet[..len]);
+        self.len.store(len as u32, Ordering::Relaxed);
+        self.received_unix_us.store(unix_us(), Ordering::Relaxed);
+        self.seq.store(seq.wrapping_add(2), Ordering::Release);
+    }
+
+    // Lock-free read, retried while the publisher is mid-write. None if nothing was published yet.
+    fn read(&self) -> Option<(u64, Vec<u8>)> {
+        for _ in 0..READ_RETRIES {
+            let before = self.seq.load(Ordering::Acquire);
+            if before == 0 {
+                return None;
+            }
+            if before % 2 == 1 {
+                std::hint::spin_loop();
+                continue;
+            }
+            let len = (self.len.load(Ordering::Relaxed) as usize).min(STATE_CAPACITY);
+            let stamp = self.received_unix_us.load(Ordering::Relaxed);
+            let data = load_words(&self.data, len);
+            fence(Ordering::Acquire);
+            if self.seq.load(Ordering::Relaxed) == before {
+                return Some((stamp, data));
+            }
+        }
+        None
+    }
+}
+
+// Owns the connection and republishes its state into shared memory
+pub struct StatePublisher<T: Transport = UnitreeConnection> {
+    conn: T,
+    state: Mapping<StateRegion>,
+    command: Mapping<CommandRegion>,
+    token: u64,
+    last_command_seq: u64,
+    rejected: u64,
+}
+
+impl<T: Transport> StatePublisher<T> {
+    // `token` is the secret the command client has to present, hand it over out of band.
+    // Run subscribers as another user than the publisher and the command client, the
+    // command segment (`name` + "-cmd") is only open to the publisher's user.
+    pub fn create(name: &str, conn: T, token: u64) -> io::Result<Self> {
+        let state = Mapping::create_state(name)?;
+        let command = Mapping::create_command(&format!("{}{}", name, COMMAND_SUFFIX))?;
+        command.region().token_check.store(token_check(token), Ordering::Relaxed);
+        command.region().magic.store(BUS_MAGIC, Ordering::Release);
+        let region = state.region();
+        region.publisher_pid.store(std::process::id(), Ordering::Relaxed);
+        region.magic.store(BUS_MAGIC, Ordering::Release);
+        Ok(StatePublisher { conn, state, command, token, last_command_seq: 0, rejected: 0 })
+    }
+
+    pub fn connection(&self) -> &T {
+        &self.conn
+    }
+
+    // Commands submitted with a wrong token
+    pub fn rejected_commands(&self) -> u64 {
+        self.rejected
+    }
+
+    // Publish whatever arrived since the last call and forward a pending command.
+    // Returns true if a command was sent to the dog.
+    pub fn poll(&mut self) -> bool {
+        let region = self.state.region();
+        let mut latest_high = None;
+        let mut latest_low = None;
+        for packet in self.conn.get_data() {
+            match classify_state(&packet) {
+                Some(Level::High) => latest_high = Some(packet),
+                Some(Level::Low) => latest_low = Some(packet),
+                None => {}
+            }
+        }
+        if let Some(packet) = latest_high {
+            region.high.write(&packet);
+        }
+        if let Some(packet) = latest_low {
+            region.low.write(&packet);
+        }
+
+        match self.take_command() {
+            Some(cmd) => {
+                self.conn.send(&cmd);
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn run(&mut self, period: Duration) -> ! {
+        loop {
+            self.poll();
+            std::thread::sleep(period);
+        }
+    }
+
+    fn take_command(&mut self) -> Option<Vec<u8>> {
+        let mailbox = &self.command.region().mailbox;
+        for _ in 0..READ_RETRIES {
+            let before = mailbox.seq.load(Ordering::Acquire);
+            if before == self.last_command_seq {
+                return None;
+            }
+            if before % 2 == 1 {
+                std::hint::spin_loop();
+                continue;
+            }
+            let len = (mailbox.len.load(Ordering::Relaxed) as usize).min(COMMAND_CAPACITY);
+            let token = mailbox.token.load(Ordering::Relaxed);
+            let data = load_words(&mailbox.data, len);
+            fence(Ordering::Acquire);
+            if mailbox.seq.load(Ordering::Relaxed) != before {
+                continue;
+            }
+            self.last_command_seq = before;
+            if token != self.token {
+                self.rejected += 1;
+                return None;
+            }
+            return Some(data);
+        }
+        None // client is mid-write, pick it up on the next poll
+    }
+}
+
+// Read-only view for perception, logging, ...; maps the state segment read only
+pub struct StateSubscriber {
+    mapping: Mapping<StateRegion>,
+}
+
+impl StateSubscriber {
+    pub fn open(name: &str) -> io::Result<Self> {
+        Ok(StateSubscriber { mapping: Mapping::open(name, false, |region: &StateRegion| region.magic.load(Ordering::Acquire))? })
+    }
+
+    pub fn publisher_pid(&self) -> u32 {
+        self.mapping.region().publisher_pid.load(Ordering::Relaxed)
+    }
+
+    // Raw datagram plus its receive time in unix microseconds
+    pub fn latest_raw(&self, level: Level) -> Option<(u64, Vec<u8>)> {
+        let region = self.mapping.region();
+        match level {
+            Level::High => region.high.read(),
+            Level::Low => region.low.read(),
+        }
+    }
+
+    pub fn high_state(&self) -> Option<HighState> {
+        let (_, packet) = self.latest_raw(Level::High)?;
+        let mut hstate = HighState::new();
+        hstate.parse_data(&packet);
+        Some(hstate)
+    }
+
+    pub fn low_state(&self) -> Option<LowState> {
+        let (_, packet) = self.latest_raw(Level::Low)?;
+        let mut lstate = LowState::new();
+        lstate.parse_data(&packet);
+        Some(lstate)
+    }
+}
+
+// The one process allowed to drive the dog through the bus
+pub struct CommandClient {
+    mapping: Mapping<CommandRegion>,
+    token: u64,
+}
+
+impl CommandClient {
+    // Fails with PermissionDenied for a token the publisher would reject
+    pub fn open(name: &str, token: u64) -> io::Result<Self> {
+        let command_name = format!("{}{}", name, COMMAND_SUFFIX);
+        let mapping = Mapping::open(&command_name, true, |region: &CommandRegion| region.magic.load(Ordering::Acquire))?;
+        if mapping.region().token_check.load(Ordering::Relaxed) != token_check(token) {
+            return Err(io::Error::new(io::ErrorKind::PermissionDenied, format!("wrong token for {}", name)));
+        }
+        Ok(CommandClient { mapping, token })
+    }
+
+    // Replace the pending command; the publisher forwards the newest one on its next poll
+    pub fn submit(&self, cmd: &[u8]) -> io::Result<()> {
+        if cmd.len() > COMMAND_CAPACITY {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("command of {} bytes does not fit the mailbox", cmd.len())));
+        }
+        let mailbox = &self.mapping.region().mailbox;
+        lock(mailbox)?;
+        // Odd if the last writer died mid-write, then the frame stays marked torn until we are done
+        let seq = mailbox.seq.load(Ordering::Relaxed) | 1;
+        mailbox.seq.store(seq, Ordering::Relaxed);
+        fence(Ordering::Release);
+        store_words(&mailbox.data, cmd);
+        mailbox.len.store(cmd.len() as u32, Ordering::Relaxed);
+        mailbox.token.store(self.token, Ordering::Relaxed);
+        mailbox.seq.store(seq.wrapping_add(1), Ordering::Release);
+        mailbox.lock.store(0, Ordering::Release);
+        Ok(())
+    }
+}
+
+// Take the mailbox lock, or the lock of a holder that died without releasing it
+fn lock(mailbox: &Mailbox) -> io::Result<()> {
+    let pid = std::process::id();
+    let started = Instant::now();
+    loop {
+        match mailbox.lock.compare_exchange_weak(0, pid, Ordering::Acquire, Ordering::Relaxed) {
+            Ok(_) => return Ok(()),
+            Err(0) => {}
+            Err(holder) if !is_alive(holder) => {
+                if mailbox.lock.compare_exchange(holder, pid, Ordering::Acquire, Ordering::Relaxed).is_ok() {
+                    return Ok(());
+                }
+            }
+            Err(holder) if started.elapsed() > LOCK_TIMEOUT => {
+                return Err(io::Error::new(io::ErrorKind::WouldBlock, format!("mailbox is locked by process {}", holder)));
+            }
+            Err(_) => {}
+        }
+        std::hint::spin_loop();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::process::Command;
+    use crate::ucl::transport::{channel_pair, ChannelTransport};
+
+    // Unique per test, the segments are visible system wide
+    fn bus(test: &str) -> (String, StatePublisher<ChannelTransport>, ChannelTransport) {
+        let name = format!("/go1-test-{}-{}", std::process::id(), test);
+        let (ours, dog) = channel_pair();
+        (name.clone(), StatePublisher::create(&name, ours, 7).unwrap(), dog)
+    }
+
+    fn dead_pid() -> u32 {
+        let mut child = Command::new("true").spawn().unwrap();
+        let pid = child.id();
+        child.wait().unwrap();
+        pid
+    }
+
+    #[test]
+    fn publishes_state_and_forwards_commands() {
+        let (name, mut publisher, dog) = bus("forward");
+        let subscriber = StateSubscriber::open(&name).unwrap();
+        assert!(subscriber.high_state().is_none());
+
+        let mut hstate = HighState::new();
+        hstate.body_height = 0.3;
+        dog.send(&hstate.build_state());
+        assert!(!publisher.poll());
+        assert_eq!(subscriber.high_state().unwrap().body_height, 0.3);
+        assert_eq!(subscriber.publisher_pid(), std::process::id());
+
+        let client = CommandClient::open(&name, 7).unwrap();
+        client.submit(b"first").unwrap();
+        client.submit(b"second").unwrap();
+        assert!(publisher.poll());
+        assert!(!publisher.poll());
+        assert_eq!(dog.get_data(), [b"second".to_vec()]);
+    }
+
+    // Every frame is one byte value repeated, a torn read would mix two of them
+    #[test]
+    fn reads_racing_the_publisher_are_never_torn() {
+        let (name, publisher, _dog) = bus("race");
+        let subscriber = StateSubscriber::open(&name).unwrap();
+        let slot = &publisher.state.region().high;
+        std::thread::scope(|scope| {
+            scope.spawn(|| {
+                for i in 0..20_000u32 {
+                    slot.write(&vec![i as u8; 1000 + (i % 87) as usize]);
+                }
+            });
+            let mut reads = 0;
+            while reads < 20_000 {
+                if let Some((_, frame)) = subscriber.latest_raw(Level::High) {
+                    assert!(frame.iter().all(|b| *b == frame[0]), "torn read");
+                    assert!((1000..1087).contains(&frame.len()));
+                }
+                reads += 1;
+            }
+        });
+    }
+
+    #[test]
+    fn wrong_token_cannot_replace_a_pending_command() {
+        let (name, mut publisher, dog) = bus("token");
+        let client = CommandClient::open(&name, 7).unwrap();
+        client.submit(b"valid").unwrap();
+        let err = CommandClient::open(&name, 8).err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::PermissionDenied);
+        assert!(publisher.poll());
+        assert_eq!(dog.get_data(), [b"valid".to_vec()]);
+        assert_eq!(publisher.rejected_commands(), 0);
+    }
+
+    #[test]
+    fn subscribers_only_get_the_state_segment() {
+        let (name, _publisher, _dog) = bus("perms");
+        let mode = |name: &str| {
+            let c_name = CString::new(name).unwrap();
+            let mut stat: libc::stat = unsafe { std::mem::zeroed() };
+            unsafe {
+                let fd = libc::shm_open(c_name.as_ptr(), libc::O_RDONLY, 0);
+                assert!(fd >= 0);
+                libc::fstat(fd, &mut stat);
+                libc::close(fd);
+            }
+            stat.st_mode & 0o777
+        };
+        assert_eq!(mode(&name), STATE_MODE);
+        assert_eq!(mode(&format!("{}{}", name, COMMAND_SUFFIX)), COMMAND_MODE);
+    }
+
+    #[test]
+    fn lock_of_a_dead_writer_is_taken_over() {
+        let (name, mut publisher, dog) = bus("dead");
+        let client = CommandClient::open(&name, 7).unwrap();
+        // A writer that died holding the lock, halfway through its frame
+        let mailbox = &client.mapping.region().mailbox;
+        mailbox.lock.store(dead_pid(), Ordering::Release);
+        mailbox.seq.store(1, Ordering::Release);
+        assert!(!publisher.poll());
+
+        client.submit(b"after").unwrap();
+        assert_eq!(mailbox.lock.load(Ordering::Acquire), 0);
+        assert!(publisher.poll());
+        assert_eq!(dog.get_data(), [b"after".to_vec()]);
+    }
+
+    #[test]
+    fn live_lock_holder_times_out() {
+        let (name, _publisher, _dog) = bus("live");
+        let client = CommandClient::open(&name, 7).unwrap();
+        let mailbox = &client.mapping.region().mailbox;
+        mailbox.lock.store(unsafe { libc::getppid() } as u32, Ordering::Release);
+        assert_eq!(client.submit(b"blocked").unwrap_err().kind(), io::ErrorKind::WouldBlock);
+    }
+
+    #[test]
+    fn segments_go_away_with_the_publisher() {
+        let (name, publisher, _dog) = bus("drop");
+        drop(publisher);
+        assert!(StateSubscriber::open(&name).is_err());
+        assert_eq!(CommandClient::open(&name, 7).err().unwrap().kind(), io::ErrorKind::NotFound);
+    }
+}
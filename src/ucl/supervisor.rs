@@ -0,0 +1,176 @@
+use std::time::{Duration, Instant};
+use super::transport::Transport;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LinkState {
+    Connected, // state packets arrive normally
+    Degraded,  // short gap in the state stream
+    Lost,      // no state for `lost_after`, re-handshaking
+}
+
+#[derive(Debug, Clone)]
+pub struct SupervisorConfig {
+    pub degraded_after: Duration,
+    pub lost_after: Duration,
+    pub rehandshake_interval: Duration, // how often the init command is re-sent while lost
+}
+
+impl Default for SupervisorConfig {
+    fn default() -> Self {
+        // The dog streams state at ~500 Hz, so 100 ms is already dozens of missed packets
+        SupervisorConfig {
+            degraded_after: Duration::from_millis(100),
+            lost_after: Duration::from_secs(1),
+            rehandshake_interval: Duration::from_millis(500),
+        }
+    }
+}
+
+pub type TransitionCallback = Box<dyn FnMut(LinkState, LinkState) + Send>;
+
+// Watches the state stream of a connection and re-sends the initialization command
+// (the empty HighCmd/LowCmd that tells the dog our receive port) whenever it is lost.
+pub struct LinkSupervisor {
+    config: SupervisorConfig,
+    init_cmd: Vec<u8>,
+    state: LinkState,
+    last_handshake: Option<Instant>,
+    handshakes: u64,
+    callbacks: Vec<TransitionCallback>,
+}
+
+impl LinkSupervisor {
+    pub fn new(init_cmd: Vec<u8>, config: SupervisorConfig) -> Self {
+        LinkSupervisor {
+            config,
+            init_cmd,
+            state: LinkState::Lost,
+            last_handshake: None,
+            handshakes: 0,
+            callbacks: Vec::new(),
+        }
+    }
+
+    // Called with (old, new) on every state change
+    pub fn on_transition<F>(&mut self, callback: F)
+    where
+        F: FnMut(LinkState, LinkState) + Send + 'static,
+    {
+        self.callbacks.push(Box::new(callback));
+    }
+
+    pub fn state(&self) -> LinkState {
+        self.state
+    }
+
+    pub fn handshakes(&self) -> u64 {
+        self.handshakes
+    }
+
+    // Send the initialization command right away, e.g. at startup
+    pub fn handshake<T: Transport + ?Sized>(&mut self, conn: &T) {
+        conn.send(&self.init_cmd);
+        self.last_handshake = Some(Instant::now());
+        self.handshakes += 1;
+    }
+
+    // Call periodically (every control cycle is fine). Returns the current link state.
+    pub fn poll<T: Transport + ?Sized>(&mut self, conn: &T) -> LinkState {
+        let now = Instant::now();
+        let silence = match conn.last_received() {
+            Some(last) => now.saturating_duration_since(last),
+            None => self.config.lost_after,
+        };
+
+        let new_state = if silence >= self.config.lost_after {
+            LinkState::Lost
+        } else if silence >= self.config.degraded_after {
+            LinkState::Degraded
+        } else {
+            LinkState::Connected
+        };
+
+        if new_state == LinkState::Lost {
+            let due = match self.last_handshake {
+                Some(last) => now.saturating_duration_since(last) >= self.config.rehandshake_interval,
+                None => true,
+            };
+            if due {
+                self.handshake(conn);
+            }
+        }
+
+        if new_state != self.state {
+            let old_state = self.state;
+            self.state = new_state;
+            for callback in self.callbacks.iter_mut() {
+                callback(old_state, new_state);
+            }
+        }
+        self.state
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{Arc, Mutex};
+    use std::thread;
+    use crate::ucl::highCmd::HighCmd;
+    use crate::ucl::mock::{MockConfig, MockRobot, STATE_PERIOD};
+    use crate::ucl::transport::{channel_pair, SimTransport};
+
+    fn config() -> SupervisorConfig {
+        SupervisorConfig {
+            degraded_after: Duration::from_millis(20),
+            lost_after: Duration::from_millis(60),
+            rehandshake_interval: Duration::from_millis(30),
+        }
+    }
+
+    #[test]
+    fn connected_degraded_lost_and_rehandshake() {
+        let (ours, dog) = channel_pair();
+        let mut supervisor = LinkSupervisor::new(vec![1, 2, 3], config());
+        let transitions = Arc::new(Mutex::new(Vec::new()));
+        let seen = Arc::clone(&transitions);
+        supervisor.on_transition(move |from, to| seen.lock().unwrap().push((from, to)));
+
+        // Nothing received yet, handshake once per interval
+        assert_eq!(supervisor.poll(&ours), LinkState::Lost);
+        assert_eq!(supervisor.poll(&ours), LinkState::Lost);
+        assert_eq!(dog.get_data(), [vec![1, 2, 3]]);
+        assert_eq!(supervisor.handshakes(), 1);
+
+        dog.send(b"state");
+        assert_eq!(supervisor.poll(&ours), LinkState::Connected);
+        thread::sleep(Duration::from_millis(30));
+        assert_eq!(supervisor.poll(&ours), LinkState::Degraded);
+        thread::sleep(Duration::from_millis(40));
+        assert_eq!(supervisor.poll(&ours), LinkState::Lost);
+        assert_eq!(supervisor.handshakes(), 2);
+
+        dog.send(b"state");
+        assert_eq!(supervisor.poll(&ours), LinkState::Connected);
+        assert_eq!(
+            *transitions.lock().unwrap(),
+            [
+                (LinkState::Lost, LinkState::Connected),
+                (LinkState::Connected, LinkState::Degraded),
+                (LinkState::Degraded, LinkState::Lost),
+                (LinkState::Lost, LinkState::Connected),
+            ]
+        );
+    }
+
+    #[test]
+    fn handshake_starts_the_mock_streaming() {
+        let sim = SimTransport::new(MockRobot::new(MockConfig::default())).with_tick(STATE_PERIOD);
+        sim.start_recv();
+        let mut supervisor = LinkSupervisor::new(HighCmd::new().build_cmd(false), config());
+        assert_eq!(supervisor.poll(&sim), LinkState::Lost);
+        thread::sleep(Duration::from_millis(10));
+        assert_eq!(supervisor.poll(&sim), LinkState::Connected);
+        assert!(!sim.get_data().is_empty());
+    }
+}
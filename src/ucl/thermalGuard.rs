@@ -0,0 +1,241 @@
+use std::time::{Duration, Instant};
+use super::complex::{MotorCmd, MotorCmdArray, MotorState};
+use super::enums::MotorModeLow;
+
+const LEG_MOTORS: usize = 12;
+// Trends below this count as not heating, the smoothed trend only decays towards zero
+const MIN_TREND: f32 = 0.001; // degrees per second
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ThermalScope {
+    Joint, // damp only the overheated joint
+    Robot, // damp every motor as soon as one overheats
+}
+
+#[derive(Debug, Clone)]
+pub struct ThermalConfig {
+    pub derate_from: f32,     // degrees centigrade, full torque and gains below
+    pub limit: f32,           // damping from here on
+    pub resume_below: f32,    // an overheated joint comes back once it cooled below this
+    pub min_derate: f32,      // factor on torque and gains just below the limit
+    pub trend_time: Duration, // smoothing of the temperature trend, the readings are whole degrees
+    pub scope: ThermalScope,
+}
+
+impl Default for ThermalConfig {
+    fn default() -> Self {
+        // The motors report Overheat themselves somewhere above 90
+        ThermalConfig {
+            derate_from: 70.0,
+            limit: 85.0,
+            resume_below: 65.0,
+            min_derate: 0.3,
+            trend_time: Duration::from_secs(5),
+            scope: ThermalScope::Joint,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct JointThermal {
+    pub temperature: f32,
+    pub trend: f32,  // degrees per second
+    pub derate: f32, // factor on tau, kp and kd, 1.0 is no derating
+    pub overheated: bool,
+}
+
+// Tracks the leg motor temperatures and derates or damps the low level commands of hot joints.
+// Feed every LowState's motor_state (HighState's works for the predictions too), then pass
+// every outgoing MotorCmdArray through filter_low.
+#[derive(Debug, Clone)]
+pub struct ThermalGuard {
+    config: ThermalConfig,
+    joints: [JointThermal; LEG_MOTORS],
+    last_update: Option<Instant>,
+}
+
+impl ThermalGuard {
+    pub fn new(config: ThermalConfig) -> Self {
+        let joint = JointThermal { derate: 1.0, ..JointThermal::default() };
+        ThermalGuard { config, joints: [joint; LEG_MOTORS], last_update: None }
+    }
+
+    pub fn update(&mut self, motors: &[MotorState]) {
+        let now = Instant::now();
+        let dt = self.last_update.map(|last| now.duration_since(last).as_secs_f32());
+        self.last_update = Some(now);
+
+        let config = &self.config;
+        for (joint, motor) in self.joints.iter_mut().zip(motors) {
+            if let Some(dt) = dt.filter(|dt| *dt > 0.0) {
+                let slope = (motor.temperature - joint.temperature) / dt;
+                let alpha = (dt / config.trend_time.as_secs_f32()).min(1.0);
+                joint.trend += (slope - joint.trend) * alpha;
+            }
+            joint.temperature = motor.temperature;
+
+            // The motor's own protection counts as reaching the limit
+            if motor.temperature >= config.limit || motor.mode == MotorModeLow::Overheat as u8 {
+                joint.overheated = true;
+            } else if motor.temperature < config.resume_below {
+                joint.overheated = false;
+            }
+
+            let over = (motor.temperature - config.derate_from) / (config.limit - config.derate_from);
+            joint.derate = 1.0 - over.clamp(0.0, 1.0) * (1.0 - config.min_derate);
+        }
+    }
+
+    pub fn joint(&self, motor: usize) -> &JointThermal {
+        &self.joints[motor]
+    }
+
+    pub fn overheated(&self) -> Vec<usize> {
+        (0..LEG_MOTORS).filter(|motor| self.joints[*motor].overheated).collect()
+    }
+
+    // Time until the joint reaches the limit at its current trend, None if it is not heating up
+    // or would take longer than a Duration holds
+    pub fn time_to_limit(&self, motor: usize) -> Option<Duration> {
+        let joint = &self.joints[motor];
+        if joint.overheated {
+            return Some(Duration::ZERO);
+        }
+        if joint.trend.is_nan() || joint.trend < MIN_TREND {
+            return None;
+        }
+        Duration::try_from_secs_f32((self.config.limit - joint.temperature).max(0.0) / joint.trend).ok()
+    }
+
+    // The joint that reaches its limit first and when
+    pub fn first_to_limit(&self) -> Option<(usize, Duration)> {
+        (0..LEG_MOTORS).filter_map(|motor| self.time_to_limit(motor).map(|t| (motor, t))).min_by_key(|(_, t)| *t)
+    }
+
+    // Scale down torque and gains of hot joints, damp overheated ones (or everything, see
+    // ThermalScope). Returns true if `cmd` was changed.
+    pub fn filter_low(&self, cmd: &mut MotorCmdArray) -> bool {
+        let overheated = self.overheated();
+        if !overheated.is_empty() && self.config.scope == ThermalScope::Robot {
+            *cmd = MotorCmdArray::damping();
+            return true;
+        }
+
+        let mut changed = false;
+        for (motor, joint) in self.joints.iter().enumerate() {
+            let Some(motor_cmd) = cmd.get_motor_cmd(motor) else { continue };
+            let limited = if joint.overheated {
+                MotorCmd::new(MotorModeLow::Damping as u8, 0.0, 0.0, 0.0, 0.0, 0.0, motor_cmd.reserve())
+            } else if joint.derate < 1.0 && motor_cmd.mode() == MotorModeLow::Servo as u8 {
+                let derate = joint.derate;
+                MotorCmd::new(motor_cmd.mode(), motor_cmd.q(), motor_cmd.dq(), motor_cmd.tau() * derate, motor_cmd.kp() * derate, motor_cmd.kd() * derate, motor_cmd.reserve())
+            } else {
+                continue;
+            };
+            cmd.set_motor_cmd(motor, limited);
+            changed = true;
+        }
+        changed
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+
+    fn motors(temperature: f32) -> Vec<MotorState> {
+        vec![MotorState { temperature, ..MotorState::default() }; 20]
+    }
+
+    fn with_joint(mut motors: Vec<MotorState>, motor: usize, temperature: f32) -> Vec<MotorState> {
+        motors[motor].temperature = temperature;
+        motors
+    }
+
+    fn servo() -> MotorCmd {
+        MotorCmd::new(MotorModeLow::Servo as u8, 0.5, 0.0, 10.0, 40.0, 2.0, [0, 0, 0])
+    }
+
+    #[test]
+    fn derates_between_derate_from_and_limit() {
+        let mut guard = ThermalGuard::new(ThermalConfig::default());
+        guard.update(&with_joint(motors(40.0), 1, 77.5));
+        assert_eq!(guard.joint(0).derate, 1.0);
+        assert!((guard.joint(1).derate - 0.65).abs() < 1e-6); // halfway, 1 - 0.5 * 0.7
+
+        let mut cmd = MotorCmdArray::new();
+        cmd.set_motor_cmd(0, servo());
+        cmd.set_motor_cmd(1, servo());
+        assert!(guard.filter_low(&mut cmd));
+        assert_eq!(cmd.get_motor_cmd(0).unwrap(), &servo());
+        let hot = cmd.get_motor_cmd(1).unwrap();
+        assert!((hot.tau() - 6.5).abs() < 1e-5 && (hot.kp() - 26.0).abs() < 1e-4);
+        assert_eq!(hot.q(), 0.5);
+    }
+
+    #[test]
+    fn overheat_holds_until_resume_below() {
+        let mut guard = ThermalGuard::new(ThermalConfig::default());
+        guard.update(&with_joint(motors(40.0), 5, 85.0));
+        assert_eq!(guard.overheated(), [5]);
+
+        // Cooling below the limit is not enough, it has to get under resume_below
+        guard.update(&with_joint(motors(40.0), 5, 70.0));
+        assert_eq!(guard.overheated(), [5]);
+        let mut cmd = MotorCmdArray::new();
+        cmd.set_motor_cmd(5, servo());
+        guard.filter_low(&mut cmd);
+        assert_eq!(cmd.get_motor_cmd(5).unwrap().mode(), MotorModeLow::Damping as u8);
+
+        guard.update(&with_joint(motors(40.0), 5, 64.0));
+        assert!(guard.overheated().is_empty());
+
+        // The motor's own Overheat mode counts as over the limit
+        let mut reported = motors(40.0);
+        reported[2].mode = MotorModeLow::Overheat as u8;
+        guard.update(&reported);
+        assert_eq!(guard.overheated(), [2]);
+    }
+
+    #[test]
+    fn robot_scope_damps_everything() {
+        let mut guard = ThermalGuard::new(ThermalConfig { scope: ThermalScope::Robot, ..ThermalConfig::default() });
+        guard.update(&with_joint(motors(40.0), 11, 90.0));
+        let mut cmd = MotorCmdArray::new();
+        cmd.set_motor_cmd(0, servo());
+        assert!(guard.filter_low(&mut cmd));
+        assert_eq!(cmd.get_motor_cmd(0).unwrap().mode(), MotorModeLow::Damping as u8);
+    }
+
+    #[test]
+    fn predicts_time_to_limit_from_the_trend() {
+        let config = ThermalConfig { trend_time: Duration::from_millis(1), ..ThermalConfig::default() };
+        let mut guard = ThermalGuard::new(config);
+        guard.update(&motors(60.0));
+        assert_eq!(guard.time_to_limit(0), None);
+
+        thread::sleep(Duration::from_millis(50));
+        guard.update(&with_joint(motors(60.0), 3, 61.0));
+        let trend = guard.joint(3).trend;
+        assert!(trend > 1.0, "trend {}", trend);
+        let (motor, eta) = guard.first_to_limit().unwrap();
+        assert_eq!(motor, 3);
+        assert!((eta.as_secs_f32() - 24.0 / trend).abs() < 0.01);
+
+        guard.update(&with_joint(motors(60.0), 3, 86.0));
+        assert_eq!(guard.time_to_limit(3), Some(Duration::ZERO));
+    }
+
+    #[test]
+    fn tiny_trends_do_not_panic() {
+        let mut guard = ThermalGuard::new(ThermalConfig::default());
+        guard.update(&motors(20.0));
+        for trend in [f32::MIN_POSITIVE, 1e-30, MIN_TREND, f32::NAN, f32::INFINITY] {
+            guard.joints[0].trend = trend;
+            let _ = guard.time_to_limit(0);
+        }
+        guard.joints[0].trend = 1e-30;
+        assert_eq!(guard.time_to_limit(0), None);
+    }
+}
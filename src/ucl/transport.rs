@@ -0,0 +1,353 @@
+use std::io;
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant};
+use super::recording::{RecordingConnection, ReplayConnection};
+use super::unitreeConnection::UnitreeConnection;
+
+// What the control loop, supervisor and keepalive need from a link to the dog:
+// send a datagram, drain the ones received so far.
+pub trait Transport {
+    // Start receiving, e.g. spawn the socket thread. Most in-process transports need nothing here.
+    fn start_recv(&self) {}
+
+    fn send(&self, data: &[u8]);
+
+    // Like send, but reports errors instead of panicking. For stop paths that may run in a
+    // panic hook, a second panic there aborts the process. Transports that can not fail keep this.
+    fn try_send(&self, data: &[u8]) -> io::Result<()> {
+        self.send(data);
+        Ok(())
+    }
+
+    // Everything received since the last call, with the time it arrived
+    fn get_data_timestamped(&self) -> Vec<(Instant, Vec<u8>)>;
+
+    // When the last datagram arrived, None if nothing was received yet
+    fn last_received(&self) -> Option<Instant>;
+
+    fn get_data(&self) -> Vec<Vec<u8>> {
+        self.get_data_timestamped().into_iter().map(|(_, packet)| packet).collect()
+    }
+
+    // Close the send path for good, for the e-stop: from now on send drops, try_send fails and
+    // nothing built on them (keepalive, control loop) reaches the dog. Only send_halted still
+    // goes out. Returns false if this transport has no gate to close.
+    fn halt(&self) -> bool {
+        false
+    }
+
+    // Sends past a halt, for the e-stop's own damping frames only
+    fn send_halted(&self, data: &[u8]) -> io::Result<()> {
+        self.try_send(data)
+    }
+}
+
+// The gate behind Transport::halt. Senders hold it for the whole send, so once close()
+// returns no ordinary send is still on its way.
+#[derive(Debug, Default)]
+pub struct SendGate {
+    closed: RwLock<bool>,
+}
+
+impl SendGate {
+    pub fn close(&self) {
+        *self.closed.write().unwrap_or_else(|e| e.into_inner()) = true;
+    }
+
+    pub fn is_closed(&self) -> bool {
+        *self.closed.read().unwrap_or_else(|e| e.into_inner())
+    }
+
+    // Run `send` unless the gate is closed
+    pub fn pass<F>(&self, send: F) -> io::Result<()>
+    where
+        F: FnOnce() -> io::Result<()>,
+    {
+        let closed = self.closed.read().unwrap_or_else(|e| e.into_inner());
+        if *closed {
+            return Err(io::Error::other("sending is halted by the e-stop"));
+        }
+        send()
+    }
+}
+
+impl Transport for UnitreeConnection {
+    fn start_recv(&self) {
+        UnitreeConnection::start_recv(self)
+    }
+
+    fn send(&self, data: &[u8]) {
+        UnitreeConnection::send(self, data)
+    }
+
+    fn try_send(&self, data: &[u8]) -> io::Result<()> {
+        UnitreeConnection::try_send(self, data)
+    }
+
+    fn get_data_timestamped(&self) -> Vec<(Instant, Vec<u8>)> {
+        UnitreeConnection::get_data_timestamped(self)
+    }
+
+    fn last_received(&self) -> Option<Instant> {
+        UnitreeConnection::last_received(self)
+    }
+
+    fn halt(&self) -> bool {
+        UnitreeConnection::halt(self);
+        true
+    }
+
+    fn send_halted(&self, data: &[u8]) -> io::Result<()> {
+        UnitreeConnection::send_halted(self, data)
+    }
+}
+
+impl<T: Transport> Transport for RecordingConnection<T> {
+    fn start_recv(&self) {
+        RecordingConnection::start_recv(self)
+    }
+
+    fn send(&self, data: &[u8]) {
+        RecordingConnection::send(self, data)
+    }
+
+    fn try_send(&self, data: &[u8]) -> io::Result<()> {
+        RecordingConnection::try_send(self, data)
+    }
+
+    fn get_data_timestamped(&self) -> Vec<(Instant, Vec<u8>)> {
+        RecordingConnection::get_data_timestamped(self)
+    }
+
+    fn last_received(&self) -> Option<Instant> {
+        RecordingConnection::last_received(self)
+    }
+
+    fn halt(&self) -> bool {
+        RecordingConnection::halt(self)
+    }
+
+    fn send_halted(&self, data: &[u8]) -> io::Result<()> {
+        RecordingConnection::send_halted(self, data)
+    }
+}
+
+impl Transport for ReplayConnection {
+    fn start_recv(&self) {
+        ReplayConnection::start_recv(self)
+    }
+
+    fn send(&self, data: &[u8]) {
+        ReplayConnection::send(self, data)
+    }
+
+    fn get_data_timestamped(&self) -> Vec<(Instant, Vec<u8>)> {
+        let now = Instant::now();
+        ReplayConnection::get_data(self).into_iter().map(|packet| (now, packet)).collect()
+    }
+
+    fn last_received(&self) -> Option<Instant> {
+        ReplayConnection::last_received(self)
+    }
+}
+
+impl<T: Transport + ?Sized> Transport for Arc<T> {
+    fn start_recv(&self) {
+        (**self).start_recv()
+    }
+
+    fn send(&self, data: &[u8]) {
+        (**self).send(data)
+    }
+
+    fn try_send(&self, data: &[u8]) -> io::Result<()> {
+        (**self).try_send(data)
+    }
+
+    fn get_data_timestamped(&self) -> Vec<(Instant, Vec<u8>)> {
+        (**self).get_data_timestamped()
+    }
+
+    fn last_received(&self) -> Option<Instant> {
+        (**self).last_received()
+    }
+
+    fn halt(&self) -> bool {
+        (**self).halt()
+    }
+
+    fn send_halted(&self, data: &[u8]) -> io::Result<()> {
+        (**self).send_halted(data)
+    }
+}
+
+type Inbox = Arc<Mutex<(Vec<(Instant, Vec<u8>)>, Option<Instant>)>>;
+
+// One end of an in-memory link, whatever one end sends the other receives
+pub struct ChannelTransport {
+    inbox: Inbox,
+    peer: Inbox,
+    gate: SendGate,
+}
+
+// Two connected ends, e.g. one for the code under test and one playing the dog
+pub fn channel_pair() -> (ChannelTransport, ChannelTransport) {
+    let a: Inbox = Arc::new(Mutex::new((Vec::new(), None)));
+    let b: Inbox = Arc::new(Mutex::new((Vec::new(), None)));
+    (
+        ChannelTransport { inbox: Arc::clone(&a), peer: Arc::clone(&b), gate: SendGate::default() },
+        ChannelTransport { inbox: b, peer: a, gate: SendGate::default() },
+    )
+}
+
+impl ChannelTransport {
+    fn deliver(&self, data: &[u8]) -> io::Result<()> {
+        let now = Instant::now();
+        let mut peer = self.peer.lock().unwrap();
+        peer.0.push((now, data.to_vec()));
+        peer.1 = Some(now);
+        Ok(())
+    }
+}
+
+impl Transport for ChannelTransport {
+    fn send(&self, data: &[u8]) {
+        let _ = self.try_send(data);
+    }
+
+    fn try_send(&self, data: &[u8]) -> io::Result<()> {
+        self.gate.pass(|| self.deliver(data))
+    }
+
+    fn get_data_timestamped(&self) -> Vec<(Instant, Vec<u8>)> {
+        std::mem::take(&mut self.inbox.lock().unwrap().0)
+    }
+
+    fn last_received(&self) -> Option<Instant> {
+        self.inbox.lock().unwrap().1
+    }
+
+    fn halt(&self) -> bool {
+        self.gate.close();
+        true
+    }
+
+    fn send_halted(&self, data: &[u8]) -> io::Result<()> {
+        self.deliver(data)
+    }
+}
+
+// The robot side of a SimTransport
+pub trait Simulator: Send {
+    // Replies to a command, sent straight back
+    fn on_command(&mut self, cmd: &[u8]) -> Vec<Vec<u8>>;
+
+    // Called every tick period (see SimTransport::with_tick), e.g. to stream state like the dog does
+    fn on_tick(&mut self) -> Vec<Vec<u8>> {
+        Vec::new()
+    }
+}
+
+// A plain closure answers commands and never streams on its own
+impl<F> Simulator for F
+where
+    F: FnMut(&[u8]) -> Vec<Vec<u8>> + Send,
+{
+    fn on_command(&mut self, cmd: &[u8]) -> Vec<Vec<u8>> {
+        self(cmd)
+    }
+}
+
+struct SimState {
+    sim: Box<dyn Simulator>,
+    inbox: Vec<(Instant, Vec<u8>)>,
+    last_recv: Option<Instant>,
+    next_tick: Option<Instant>,
+}
+
+impl SimState {
+    // Run the ticks that fell due by now
+    fn catch_up(&mut self, period: Option<Duration>) {
+        if let (Some(period), Some(mut next)) = (period, self.next_tick) {
+            let now = Instant::now();
+            while next <= now {
+                let packets = self.sim.on_tick();
+                self.deliver(next, packets);
+                next += period;
+            }
+            self.next_tick = Some(next);
+        }
+    }
+
+    fn deliver(&mut self, at: Instant, packets: Vec<Vec<u8>>) {
+        if !packets.is_empty() {
+            self.last_recv = Some(at);
+        }
+        self.inbox.extend(packets.into_iter().map(|packet| (at, packet)));
+    }
+}
+
+// Runs a Simulator in-process. Ticks are not driven by a thread: the ones that fell due since
+// the last call are caught up in get_data_timestamped, stamped with their nominal time.
+pub struct SimTransport {
+    state: Mutex<SimState>,
+    tick_period: Option<Duration>,
+    gate: SendGate,
+}
+
+impl SimTransport {
+    pub fn new<S: Simulator + 'static>(sim: S) -> Self {
+        SimTransport {
+            state: Mutex::new(SimState { sim: Box::new(sim), inbox: Vec::new(), last_recv: None, next_tick: None }),
+            tick_period: None,
+            gate: SendGate::default(),
+        }
+    }
+
+    // Call on_tick every `period`, starting with start_recv
+    pub fn with_tick(mut self, period: Duration) -> Self {
+        assert!(!period.is_zero(), "sim tick period must be positive");
+        self.tick_period = Some(period);
+        self
+    }
+}
+
+impl Transport for SimTransport {
+    fn start_recv(&self) {
+        if let Some(period) = self.tick_period {
+            self.state.lock().unwrap().next_tick = Some(Instant::now() + period);
+        }
+    }
+
+    fn send(&self, data: &[u8]) {
+        let _ = self.try_send(data);
+    }
+
+    fn try_send(&self, data: &[u8]) -> io::Result<()> {
+        self.gate.pass(|| self.send_halted(data))
+    }
+
+    fn get_data_timestamped(&self) -> Vec<(Instant, Vec<u8>)> {
+        let mut state = self.state.lock().unwrap();
+        state.catch_up(self.tick_period);
+        std::mem::take(&mut state.inbox)
+    }
+
+    fn last_received(&self) -> Option<Instant> {
+        let mut state = self.state.lock().unwrap();
+        state.catch_up(self.tick_period);
+        state.last_recv
+    }
+
+    fn halt(&self) -> bool {
+        self.gate.close();
+        true
+    }
+
+    fn send_halted(&self, data: &[u8]) -> io::Result<()> {
+        let mut state = self.state.lock().unwrap();
+        let replies = state.sim.on_command(data);
+        state.deliver(Instant::now(), replies);
+        Ok(())
+    }
+}
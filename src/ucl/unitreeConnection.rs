@@ -1,58 +1,197 @@
-use std::net::{UdpSocket, SocketAddr, IpAddr, Ipv4Addr};
+use std::io::{self, ErrorKind};
+use std::net::{UdpSocket, SocketAddr, IpAddr};
 use std::sync::{Arc, Mutex};
 use std::thread;
-use std::time::Duration;
+use std::time::{Duration, Instant};
+use super::config::ConnectionProfile;
+use super::keepalive::{self, CommandSlot, KeepaliveConfig};
+use super::realtime::{self, RealtimeConfig};
+use super::transport::SendGate;
 
-const LISTEN_PORT: u16 = 8090;
-const SEND_PORT_LOW: u16 = 8007;
-const SEND_PORT_HIGH: u16 = 8082;
+pub const LISTEN_PORT: u16 = 8090;
+pub const SEND_PORT_LOW: u16 = 8007;
+pub const SEND_PORT_HIGH: u16 = 8082;
 
-const LOCAL_IP_WIFI: &str = "192.168.12.14";
-const LOCAL_IP_ETH: &str = "192.168.123.14";
-const ADDR_WIFI: &str = "192.168.12.1";
-const ADDR_LOW: &str = "192.168.123.10";
-const ADDR_HIGH: &str = "192.168.123.161";
+pub const LOCAL_IP_WIFI: &str = "192.168.12.14";
+pub const LOCAL_IP_ETH: &str = "192.168.123.14";
+pub const ADDR_WIFI: &str = "192.168.12.1";
+pub const ADDR_LOW: &str = "192.168.123.10";
+pub const ADDR_HIGH: &str = "192.168.123.161";
 
-const LOW_WIRED_DEFAULTS: (&str, &str, u16, &str) = (ADDR_LOW, LOCAL_IP_ETH, SEND_PORT_LOW, LOCAL_IP_ETH);
-const LOW_WIFI_DEFAULTS: (&str, &str, u16, &str) = (ADDR_LOW, LOCAL_IP_WIFI, SEND_PORT_LOW, LOCAL_IP_WIFI);
-const HIGH_WIRED_DEFAULTS: (&str, &str, u16, &str) = (ADDR_HIGH, LOCAL_IP_ETH, SEND_PORT_HIGH, LOCAL_IP_ETH);
-const HIGH_WIFI_DEFAULTS: (&str, &str, u16, &str) = (ADDR_WIFI, LOCAL_IP_WIFI, SEND_PORT_HIGH, LOCAL_IP_WIFI);
+pub const LOW_WIRED_DEFAULTS: (&str, &str, u16, &str) = (ADDR_LOW, LOCAL_IP_ETH, SEND_PORT_LOW, LOCAL_IP_ETH);
+pub const LOW_WIFI_DEFAULTS: (&str, &str, u16, &str) = (ADDR_LOW, LOCAL_IP_WIFI, SEND_PORT_LOW, LOCAL_IP_WIFI);
+pub const HIGH_WIRED_DEFAULTS: (&str, &str, u16, &str) = (ADDR_HIGH, LOCAL_IP_ETH, SEND_PORT_HIGH, LOCAL_IP_ETH);
+pub const HIGH_WIFI_DEFAULTS: (&str, &str, u16, &str) = (ADDR_WIFI, LOCAL_IP_WIFI, SEND_PORT_HIGH, LOCAL_IP_WIFI);
 
-struct UnitreeConnection {
+// Received datagrams with their arrival time, shared with the receive thread
+type Inbox = Arc<Mutex<Vec<(Instant, Vec<u8>)>>>;
+
+pub struct UnitreeConnection {
     socket: UdpSocket,
-    data: Arc<Mutex<Vec<Vec<u8>>>>,
+    send_addr: SocketAddr,
+    data: Inbox,
+    last_recv: Arc<Mutex<Option<Instant>>>,
+    recv_realtime: RealtimeConfig,
+    command: Arc<CommandSlot>,
+    gate: Arc<SendGate>, // closed by halt(), shared with the keepalive thread
 }
 
 impl UnitreeConnection {
-    fn new(local_ip: IpAddr, listen_port: u16, send_addr: SocketAddr) -> Self {
-        let socket = UdpSocket::bind((local_ip, listen_port)).expect("Couldn't bind to address");
-        socket.set_read_timeout(Some(Duration::from_secs(1))).expect("set_read_timeout call failed");
-        UnitreeConnection {
+    pub fn new(local_ip: IpAddr, listen_port: u16, send_addr: SocketAddr) -> Self {
+        Self::try_new(local_ip, listen_port, send_addr).expect("Couldn't bind to address")
+    }
+
+    // Like new, but a local address that can not be bound is an error instead of a panic
+    pub fn try_new(local_ip: IpAddr, listen_port: u16, send_addr: SocketAddr) -> io::Result<Self> {
+        let socket = UdpSocket::bind((local_ip, listen_port))?;
+        socket.set_read_timeout(Some(Duration::from_secs(1)))?;
+        Ok(UnitreeConnection {
             socket,
+            send_addr,
             data: Arc::new(Mutex::new(Vec::new())),
-        }
+            last_recv: Arc::new(Mutex::new(None)),
+            recv_realtime: RealtimeConfig::default(),
+            command: Arc::new(CommandSlot::default()),
+            gate: Arc::new(SendGate::default()),
+        })
+    }
+
+    pub fn from_profile(profile: &ConnectionProfile) -> Self {
+        Self::new(IpAddr::V4(profile.local_ip), profile.listen_port, profile.send_addr())
     }
 
-    fn start_recv(&self) {
+    // Scheduling for the receive thread, takes effect on the next start_recv
+    pub fn set_recv_realtime(&mut self, config: RealtimeConfig) {
+        self.recv_realtime = config;
+    }
+
+    pub fn start_recv(&self) {
         let data = Arc::clone(&self.data);
+        let last_recv = Arc::clone(&self.last_recv);
         let socket = self.socket.try_clone().expect("Couldn't clone the socket");
+        let rt_config = self.recv_realtime.clone();
         thread::spawn(move || {
+            if !rt_config.is_empty() {
+                realtime::apply_or_warn(&rt_config, "receive");
+            }
             let mut buffer = [0; 2048];
-            while let Ok((size, _)) = socket.recv_from(&mut buffer) {
-                let mut data_lock = data.lock().unwrap();
-                data_lock.push(buffer[..size].to_vec());
+            loop {
+                match socket.recv_from(&mut buffer) {
+                    Ok((size, _)) => {
+                        let now = Instant::now();
+                        *last_recv.lock().unwrap() = Some(now);
+                        let mut data_lock = data.lock().unwrap();
+                        data_lock.push((now, buffer[..size].to_vec()));
+                    }
+                    // Timeouts and ICMP errors are transient, keep listening so a rebooted dog is picked up again
+                    Err(e) if matches!(
+                        e.kind(),
+                        ErrorKind::WouldBlock | ErrorKind::TimedOut | ErrorKind::ConnectionRefused | ErrorKind::ConnectionReset
+                    ) => {}
+                    Err(_) => break,
+                }
             }
         });
     }
 
-    fn send(&self, send_addr: SocketAddr, cmd: &[u8]) {
-        self.socket.send_to(cmd, send_addr).expect("Couldn't send data");
+    // Dropped without a panic once halted
+    pub fn send(&self, cmd: &[u8]) {
+        if let Err(e) = self.try_send(cmd) {
+            if !self.gate.is_closed() {
+                panic!("Couldn't send data: {:?}", e);
+            }
+        }
+    }
+
+    pub fn try_send(&self, cmd: &[u8]) -> io::Result<()> {
+        self.gate.pass(|| self.socket.send_to(cmd, self.send_addr).map(|_| ()))
+    }
+
+    // Send to another port of the dog from the same socket, so replies still come back here
+    pub fn send_to(&self, cmd: &[u8], addr: SocketAddr) {
+        if let Err(e) = self.gate.pass(|| self.socket.send_to(cmd, addr).map(|_| ())) {
+            if !self.gate.is_closed() {
+                panic!("Couldn't send data: {:?}", e);
+            }
+        }
+    }
+
+    // For the e-stop: stop the keepalive and refuse every send and set_command from now on.
+    // Blocks until sends already in progress are out.
+    pub fn halt(&self) {
+        self.gate.close();
+        self.command.stop();
+        self.command.clear();
+    }
+
+    pub fn is_halted(&self) -> bool {
+        self.gate.is_closed()
+    }
+
+    // Past the halt, only for the e-stop's damping frames
+    pub fn send_halted(&self, cmd: &[u8]) -> io::Result<()> {
+        self.socket.send_to(cmd, self.send_addr).map(|_| ())
     }
 
-    fn get_data(&self) -> Vec<Vec<u8>> {
+    // When the last datagram arrived, None if nothing was received yet
+    pub fn last_received(&self) -> Option<Instant> {
+        *self.last_recv.lock().unwrap()
+    }
+
+    pub fn get_data(&self) -> Vec<Vec<u8>> {
+        self.get_data_timestamped().into_iter().map(|(_, packet)| packet).collect()
+    }
+
+    // Same as get_data, with the time each datagram was received
+    pub fn get_data_timestamped(&self) -> Vec<(Instant, Vec<u8>)> {
         let mut data_lock = self.data.lock().unwrap();
-        let ret = data_lock.clone();
-        data_lock.clear();
-        ret
+        std::mem::take(&mut *data_lock)
+    }
+
+    // Keep retransmitting the current command from a background thread, the dog needs a steady stream.
+    // Does nothing if the keepalive is already running.
+    pub fn start_keepalive(&self, config: KeepaliveConfig) {
+        let socket = self.socket.try_clone().expect("Couldn't clone the socket");
+        let send_addr = self.send_addr;
+        let gate = Arc::clone(&self.gate);
+        let send = move |frame: &[u8]| {
+            if let Err(e) = gate.pass(|| socket.send_to(frame, send_addr).map(|_| ())) {
+                if !gate.is_closed() {
+                    eprintln!("[keepalive] send failed: {}", e);
+                }
+            }
+        };
+        keepalive::spawn(send, Arc::clone(&self.command), config);
+    }
+
+    pub fn stop_keepalive(&self) {
+        self.command.stop();
     }
-}
\ No newline at end of file
+
+    // Replace the command the keepalive thread sends, ignored once halted
+    pub fn set_command(&self, cmd: &[u8]) {
+        if !self.gate.is_closed() {
+            self.command.set(cmd);
+        }
+    }
+
+    // Let the keepalive fall back to its idle frame right away
+    pub fn clear_command(&self) {
+        self.command.clear();
+    }
+
+    // Every control cycle when the keepalive was started with KeepaliveConfig::with_watchdog
+    pub fn feed_watchdog(&self) {
+        self.command.feed();
+    }
+
+    pub fn command_slot(&self) -> Arc<CommandSlot> {
+        Arc::clone(&self.command)
+    }
+}
+
+impl Drop for UnitreeConnection {
+    fn drop(&mut self) {
+        self.command.stop();
+    }
+}